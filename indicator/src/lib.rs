@@ -294,6 +294,118 @@ where
     }
 }
 
+/// `n` built up from ones, for types without a conversion from integers
+fn count<E>(n: usize) -> E
+where
+    E: Identity + Add<Output = E>,
+{
+    let mut out = E::zero();
+    for _ in 0..n {
+        out = out + E::one();
+    }
+    return out;
+}
+
+/// Two-state Kalman filter tracking a value and its rate of change from
+/// `(time, value)` measurements, which may be spaced irregularly. The rate
+/// follows a random walk with `process` noise density, the measurements have
+/// `measurement` noise variance. Outputs `(a, b)` of the line `a + b * time`
+/// through the current estimate, like `LinearRegression`.
+pub struct Kalman<E> {
+    process: E,
+    measurement: E,
+    /// Time, value and rate of the last update
+    state: Option<(E, E, E)>,
+    /// Covariance of the value and rate estimates
+    p: [[E; 2]; 2],
+    /// First measurement, until a rate can be told
+    first: Option<(E, E)>,
+}
+
+impl<E> Kalman<E>
+where
+    E: Identity + PartialEq + Copy + Add<Output = E> + Sub<Output = E> + Mul<Output = E> + Div<E, Output = E>,
+{
+    pub fn with_noise(process: E, measurement: E) -> Self {
+        return Kalman {
+            process,
+            measurement,
+            state: None,
+            p: [[E::zero(); 2]; 2],
+            first: None,
+        };
+    }
+
+    /// Variances of the value and rate estimates
+    pub fn variance(&self) -> Option<(E, E)> {
+        self.state?;
+        return Some((self.p[0][0], self.p[1][1]));
+    }
+
+    fn predict(&mut self, dt: E) {
+        let two = count::<E>(2);
+        let three = count::<E>(3);
+        let q = self.process;
+        let p = self.p;
+        let p00 = p[0][0] + two * dt * p[0][1] + dt * dt * p[1][1] + q * dt * dt * dt / three;
+        let p01 = p[0][1] + dt * p[1][1] + q * dt * dt / two;
+        let p11 = p[1][1] + q * dt;
+        self.p = [[p00, p01], [p01, p11]];
+    }
+}
+
+impl<E> Indicator<(E, E)> for Kalman<E>
+where
+    E: Identity + PartialEq + Copy + Add<Output = E> + Sub<Output = E> + Mul<Output = E> + Div<E, Output = E>,
+{
+    type Output = (E, E);
+    /// Unit measurement noise and process noise tracking about as fast as
+    /// a regression over `size` measurements spaced one unit of time apart.
+    fn new(size: usize) -> Result<Self, &'static str> {
+        if size < 1 {
+            return Err("Size cannot be smaller than 1!");
+        }
+        let size = count::<E>(size);
+        let process = count::<E>(64) / (size * size * size * size);
+        return Ok(Kalman::with_noise(process, E::one()));
+    }
+    fn next(&mut self, (t, y): (E, E)) {
+        let r = self.measurement;
+        let (last_t, value, rate) = match self.state {
+            Some(state) => state,
+            None => {
+                // The rate of the first two measurements seeds the filter
+                match self.first {
+                    Some((t0, y0)) if t != t0 => {
+                        let dt = t - t0;
+                        let two = count::<E>(2);
+                        self.state = Some((t, y, (y - y0) / dt));
+                        self.p = [[r, r / dt], [r / dt, two * r / (dt * dt)]];
+                    }
+                    _ => self.first = Some((t, y)),
+                }
+                return;
+            }
+        };
+        let dt = t - last_t;
+        self.predict(dt);
+        let value = value + rate * dt;
+        let p = self.p;
+        let s = p[0][0] + r;
+        let (k0, k1) = (p[0][0] / s, p[0][1] / s);
+        let innovation = y - value;
+        self.state = Some((t, value + k0 * innovation, rate + k1 * innovation));
+        let p00 = (E::one() - k0) * p[0][0];
+        let p01 = (E::one() - k0) * p[0][1];
+        let p11 = p[1][1] - k1 * p[0][1];
+        self.p = [[p00, p01], [p01, p11]];
+    }
+    fn value(&self) -> Option<(E, E)> {
+        let (t, value, rate) = self.state?;
+        return Some((value - rate * t, rate));
+    }
+}
+
 /*MIT*/
 // Based on https://github.com/craffel/median-filter/blob/master/Mediator.h by Colin Raffel
 // Original under MIT license. For posterity following code between /*MIT*/ markers can be
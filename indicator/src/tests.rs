@@ -141,3 +141,46 @@ fn test_median() {
         }
     });
 }
+#[test]
+fn test_kalman() {
+    assert!(Kalman::<TYPE>::new(0).is_err());
+    let mut rng = rand::thread_rng();
+    let line = |t: TYPE| 3. + 0.5 * t;
+
+    // Exact from the first two measurements
+    let mut test_indicator = Kalman::new(100).unwrap();
+    test_indicator.next((1., line(1.)));
+    assert_eq!(test_indicator.value(), None);
+    test_indicator.next((1., line(1.)));
+    assert_eq!(test_indicator.value(), None);
+    test_indicator.next((3., line(3.)));
+    let (a, b) = test_indicator.value().unwrap();
+    assert!((a - 3.).abs() < EPS && (b - 0.5).abs() < EPS, "{} + {} t", a, b);
+
+    // Noisy and irregularly spaced
+    let mut test_indicator = Kalman::with_noise(1e-8, 0.01);
+    let mut t = 0.;
+    let mut first_var = None;
+    for _ in 0..SIZE {
+        t += 0.5 + rng.gen::<TYPE>();
+        let noise = 0.1 * (rng.gen::<TYPE>() - 0.5) * 12f64.sqrt();
+        test_indicator.next((t, line(t) + noise));
+        if let Some((var_a, var_b)) = test_indicator.variance() {
+            assert!(var_a > 0. && var_b > 0.);
+            first_var.get_or_insert(var_b);
+        }
+    }
+    let (a, b) = test_indicator.value().unwrap();
+    let (var_a, var_b) = test_indicator.variance().unwrap();
+    assert!(var_b < first_var.unwrap() / 100.);
+    assert!((a + b * t - line(t)).abs() < 4. * var_a.sqrt(), "{} + {} t", a, b);
+    assert!((b - 0.5).abs() < 4. * var_b.sqrt() + 1e-6, "{} + {} t", a, b);
+
+    // A change of rate is followed
+    for _ in 0..SIZE {
+        t += 1.;
+        test_indicator.next((t, 1. - 0.25 * t));
+    }
+    let (a, b) = test_indicator.value().unwrap();
+    assert!((b + 0.25).abs() < 1e-3, "{} + {} t", a, b);
+}
@@ -0,0 +1,201 @@
+//! Shared network time. Sessions are scheduled on the clock of the network,
+//! which is the system clock when it is disciplined by `ptp4l` or similar.
+//! Otherwise a `TimeSource` tells how far the local clock is off and the
+//! player moves its schedule accordingly.
+//!
+//! Sound cards may timestamp their status with a monotonic clock instead of
+//! the system clock, a `Timebase` maps those timestamps to the system clock
+//! so the schedule doesn't jump when the system clock is stepped.
+
+#[cfg(test)]
+mod tests;
+
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Fastest the offset of a `Timebase` follows the system clock by default,
+/// as fast as `ntpd` or `ptp4l` slew it
+pub const MAX_SLEW: f64 = 500e-6;
+
+pub trait TimeSource: Send + Sync {
+    /// Network time minus local time in seconds, `None` while unknown
+    fn offset(&self) -> Option<f64>;
+    /// Error of the offset in seconds the source claims, `None` when it
+    /// doesn't say
+    fn error(&self) -> Option<f64> {
+        return None;
+    }
+    /// What the status line calls the source
+    fn name(&self) -> String;
+}
+
+/// What a slave does while its clock can't be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockPolicy {
+    /// Keeps playing and warns
+    #[default]
+    Warn,
+    /// Keeps the schedule but plays silence until the lock is back
+    Mute,
+    /// Stops playing the session
+    Stop,
+}
+
+impl FromStr for LockPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LockPolicy, String> {
+        return match s {
+            "warn" => Ok(LockPolicy::Warn),
+            "mute" => Ok(LockPolicy::Mute),
+            "stop" => Ok(LockPolicy::Stop),
+            _ => Err(format!(
+                "Unknown lock policy {}, expected warn, mute or stop",
+                s
+            )),
+        };
+    }
+}
+
+/// `time` moved by `seconds`, which may be negative.
+pub fn shift(time: SystemTime, seconds: f64) -> SystemTime {
+    return if seconds >= 0. {
+        time + Duration::from_secs_f64(seconds)
+    } else {
+        time - Duration::from_secs_f64(-seconds)
+    };
+}
+
+/// `lhs - rhs` in seconds.
+pub fn diff(lhs: SystemTime, rhs: SystemTime) -> f64 {
+    return match lhs.duration_since(rhs) {
+        Ok(duration) => duration.as_secs_f64(),
+        Err(err) => -err.duration().as_secs_f64(),
+    };
+}
+
+/// Clock the sound card timestamps its status with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TstampClock {
+    /// The system clock, steps and slews included
+    #[default]
+    Realtime,
+    /// Slewed along with the system clock but never stepped
+    Monotonic,
+    /// The bare oscillator, neither slewed nor stepped
+    MonotonicRaw,
+}
+
+impl TstampClock {
+    fn id(self) -> nix::libc::clockid_t {
+        return match self {
+            TstampClock::Realtime => nix::libc::CLOCK_REALTIME,
+            TstampClock::Monotonic => nix::libc::CLOCK_MONOTONIC,
+            TstampClock::MonotonicRaw => nix::libc::CLOCK_MONOTONIC_RAW,
+        };
+    }
+
+    /// Current time on this clock, since the epoch for `Realtime` and
+    /// since boot otherwise.
+    pub fn now(self) -> Duration {
+        let mut ts = nix::libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Can't fail for a valid clock id and pointer
+        unsafe { nix::libc::clock_gettime(self.id(), &mut ts) };
+        return Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+    }
+}
+
+impl FromStr for TstampClock {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TstampClock, String> {
+        return match s {
+            "realtime" => Ok(TstampClock::Realtime),
+            "monotonic" => Ok(TstampClock::Monotonic),
+            "monotonic-raw" => Ok(TstampClock::MonotonicRaw),
+            _ => Err(format!(
+                "Unknown timestamp clock {}, expected realtime, monotonic or monotonic-raw",
+                s
+            )),
+        };
+    }
+}
+
+/// Maps times of a `TstampClock` to the system clock. The offset between
+/// the two is measured over and over but followed at most `max_slew`
+/// seconds per second, so a step of the system clock is spread out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timebase {
+    clock: TstampClock,
+    max_slew: f64,
+    /// System clock minus `clock` in seconds
+    offset: Option<f64>,
+    /// Time on `clock` of the last measurement
+    last: Duration,
+}
+
+impl Timebase {
+    pub fn new(clock: TstampClock, max_slew: f64) -> Timebase {
+        return Timebase {
+            clock,
+            max_slew,
+            offset: if clock == TstampClock::Realtime {
+                Some(0.)
+            } else {
+                None
+            },
+            last: Duration::new(0, 0),
+        };
+    }
+
+    pub fn clock(&self) -> TstampClock {
+        return self.clock;
+    }
+
+    /// System clock minus `clock` in seconds, `None` before the first
+    /// measurement.
+    pub fn offset(&self) -> Option<f64> {
+        return self.offset;
+    }
+
+    /// Takes `measured`, the system clock minus `clock` at `time` on
+    /// `clock`. The first measurement is taken as is.
+    pub fn update(&mut self, time: Duration, measured: f64) {
+        if self.clock == TstampClock::Realtime {
+            return;
+        }
+        self.offset = Some(match self.offset {
+            Some(offset) => {
+                let limit = self.max_slew * time.saturating_sub(self.last).as_secs_f64();
+                offset + (measured - offset).clamp(-limit, limit)
+            }
+            None => measured,
+        });
+        self.last = self.last.max(time);
+    }
+
+    /// Measures the offset, reading `clock` on both sides of the system
+    /// clock.
+    pub fn measure(&mut self) {
+        if self.clock == TstampClock::Realtime {
+            return;
+        }
+        let before = self.clock.now();
+        let system = TstampClock::Realtime.now();
+        let after = self.clock.now();
+        let time = before + (after - before) / 2;
+        self.update(time, system.as_secs_f64() - time.as_secs_f64());
+    }
+
+    /// System time of `time` on `clock`, measuring the offset first when
+    /// it is unknown.
+    pub fn to_system(&mut self, time: Duration) -> SystemTime {
+        if self.offset.is_none() {
+            self.measure();
+        }
+        return shift(UNIX_EPOCH + time, self.offset.unwrap_or(0.));
+    }
+}
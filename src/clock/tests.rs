@@ -0,0 +1,52 @@
+use super::*;
+
+#[test]
+fn test_shift_and_diff() {
+    let base = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    for &seconds in &[0., 1.25, -3.5] {
+        assert!((diff(shift(base, seconds), base) - seconds).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn test_timebase_slews_steps() {
+    let mut timebase = Timebase::new(TstampClock::Monotonic, MAX_SLEW);
+    assert_eq!(timebase.offset(), None);
+    let offset = 1_600_000_000.;
+    timebase.update(Duration::from_secs(100), offset);
+    assert_eq!(timebase.offset(), Some(offset));
+    // Slewing of the system clock is followed
+    timebase.update(Duration::from_secs(110), offset + 1e-3);
+    assert!((timebase.offset().unwrap() - offset - 1e-3).abs() < 1e-6);
+    // A step of a second is spread over 2000 seconds
+    timebase.update(Duration::from_secs(120), offset + 1.001);
+    assert!((timebase.offset().unwrap() - offset - 6e-3).abs() < 1e-6);
+    let mut time = 120;
+    while time < 2120 {
+        time += 10;
+        timebase.update(Duration::from_secs(time), offset + 1.001);
+    }
+    assert!((timebase.offset().unwrap() - offset - 1.001).abs() < 1e-6);
+    // Measurements out of order don't move it backwards in time
+    timebase.update(Duration::from_secs(100), offset);
+    assert!((timebase.offset().unwrap() - offset - 1.001).abs() < 1e-6);
+
+    // The system clock needs no mapping
+    let mut realtime = Timebase::new(TstampClock::Realtime, MAX_SLEW);
+    realtime.update(Duration::from_secs(100), offset);
+    assert_eq!(realtime.offset(), Some(0.));
+}
+
+#[test]
+fn test_timebase_maps_to_system_clock() {
+    for &clock in &[TstampClock::Monotonic, TstampClock::MonotonicRaw] {
+        let mut timebase = Timebase::new(clock, MAX_SLEW);
+        let mapped = timebase.to_system(clock.now());
+        let error = diff(mapped, SystemTime::now());
+        assert!(error.abs() < 1e-3, "{:?}: {} s off", clock, error);
+    }
+    let error = diff(UNIX_EPOCH + TstampClock::Realtime.now(), SystemTime::now());
+    assert!(error.abs() < 1e-3, "{} s off", error);
+    assert_eq!("monotonic-raw".parse(), Ok(TstampClock::MonotonicRaw));
+    assert!("tai".parse::<TstampClock>().is_err());
+}
@@ -0,0 +1,306 @@
+//! Long running slave that takes its sessions from the network, see the
+//! `protocol` module for the commands it understands.
+
+#[cfg(test)]
+mod tests;
+
+use crate::clock::{self, TimeSource};
+use crate::fade::Level;
+use crate::protocol::{self, Message};
+use crate::session::Session;
+use crate::timeline::{Cue, Timeline};
+
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+
+/// Plays a session until it ends or the flag gets set, following the
+/// commands added to the timeline meanwhile.
+pub type Player = dyn Fn(Session, Arc<AtomicBool>, Arc<Mutex<Timeline>>) + Send + Sync;
+
+struct Playback {
+    stop: Arc<AtomicBool>,
+    timeline: Arc<Mutex<Timeline>>,
+    handle: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct State {
+    session: Option<Session>,
+    playback: Option<Playback>,
+    /// Playbacks told to stop that are still fading out
+    stopping: usize,
+}
+
+pub struct Daemon {
+    player: Arc<Player>,
+    /// Output level of the slave, kept across sessions
+    level: Arc<Level>,
+    /// Network time the sessions are scheduled on, the system clock without
+    /// one
+    time_source: Option<Arc<dyn TimeSource>>,
+    state: Mutex<State>,
+}
+
+impl Playback {
+    fn join(self) {
+        if self.handle.join().is_err() {
+            println!("[ERR] Playback thread panicked");
+        }
+    }
+}
+
+impl State {
+    fn reap(&mut self) {
+        if self
+            .playback
+            .as_ref()
+            .is_some_and(|playback| playback.handle.is_finished())
+        {
+            self.playback.take().unwrap().join();
+        }
+    }
+
+    /// Tells the playback to stop and takes it, to be joined by
+    /// `Daemon::join` once the lock is released.
+    fn stop(&mut self) -> Option<Playback> {
+        let playback = self.playback.take()?;
+        playback.stop.store(true, Ordering::Relaxed);
+        self.stopping += 1;
+        return Some(playback);
+    }
+}
+
+impl Daemon {
+    /// Serves sessions to `player`, `GAIN` sets `level` which the player
+    /// is expected to follow. `STATUS` tells the state on the network time
+    /// of `time_source`, like the player schedules the session.
+    pub fn new(
+        player: Box<Player>,
+        level: Arc<Level>,
+        time_source: Option<Arc<dyn TimeSource>>,
+    ) -> Arc<Daemon> {
+        return Arc::new(Daemon {
+            player: Arc::from(player),
+            level,
+            time_source,
+            state: Mutex::new(State::default()),
+        });
+    }
+
+    /// Current network time, the system clock while the offset is unknown
+    fn now(&self) -> SystemTime {
+        let offset = self.time_source.as_ref().and_then(|source| source.offset());
+        return clock::shift(SystemTime::now(), offset.unwrap_or(0.));
+    }
+
+    /// Stops the current playback, if any. The loaded session is kept.
+    pub fn stop(&self) {
+        let playback = self.state.lock().unwrap().stop();
+        self.join(playback);
+    }
+
+    /// Waits for a playback taken by `State::stop` to finish, other
+    /// commands are served meanwhile.
+    fn join(&self, playback: Option<Playback>) {
+        if let Some(playback) = playback {
+            playback.join();
+            self.state.lock().unwrap().stopping -= 1;
+        }
+    }
+
+    fn load(&self, state: &mut State, msg: &Message) -> Message {
+        if state.playback.is_some() {
+            return Message::nak("Playback in progress");
+        }
+        return match Session::from_message(msg) {
+            Ok(session) => {
+                if let Err(err) = std::fs::metadata(&session.testfile) {
+                    return Message::nak(&format!("{}: {}", session.testfile, err));
+                }
+                state.session = Some(session);
+                Message::new("ACK")
+            }
+            Err(err) => Message::nak(&err),
+        };
+    }
+
+    fn arm(&self, state: &mut State, msg: &Message) -> Message {
+        if state.playback.is_some() {
+            return Message::nak("Playback in progress");
+        }
+        if state.stopping > 0 {
+            return Message::nak("Playback still stopping");
+        }
+        let startat = match msg.parse::<u64>("startat") {
+            Ok(Some(startat)) => startat,
+            Ok(None) => return Message::nak("Missing startat"),
+            Err(err) => return Message::nak(&err),
+        };
+        let session = match state.session.as_mut() {
+            Some(session) => session,
+            None => return Message::nak("Nothing loaded"),
+        };
+        session.startat = startat;
+        let session = session.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let timeline = Arc::new(Mutex::new(Timeline::new(startat)));
+        let player = Arc::clone(&self.player);
+        let thread_stop = Arc::clone(&stop);
+        let thread_timeline = Arc::clone(&timeline);
+        let handle = thread::spawn(move || player(session, thread_stop, thread_timeline));
+        state.playback = Some(Playback {
+            stop,
+            timeline,
+            handle,
+        });
+        return Message::new("ACK");
+    }
+
+    fn cue(&self, state: &State, msg: &Message) -> Message {
+        let playback = match &state.playback {
+            Some(playback) => playback,
+            None => return Message::nak("Nothing playing"),
+        };
+        let result =
+            Cue::from_message(msg).and_then(|cue| playback.timeline.lock().unwrap().add(cue));
+        return match result {
+            Ok(()) => Message::new("ACK"),
+            Err(err) => Message::nak(&err),
+        };
+    }
+
+    fn gain(&self, msg: &Message) -> Message {
+        let gain = match msg.parse::<f64>("gain") {
+            Ok(Some(gain)) if gain.is_finite() => gain,
+            Ok(Some(gain)) => return Message::nak(&format!("Bad gain {}", gain)),
+            Ok(None) => return Message::nak("Missing gain"),
+            Err(err) => return Message::nak(&err),
+        };
+        let ramp = match msg.parse::<f64>("ramp") {
+            Ok(None) => 0.,
+            Ok(Some(ramp)) if ramp >= 0. => ramp,
+            Ok(Some(ramp)) => return Message::nak(&format!("Bad ramp {}", ramp)),
+            Err(err) => return Message::nak(&err),
+        };
+        self.level.set(gain, ramp);
+        return Message::new("ACK");
+    }
+
+    fn status(&self, state: &State) -> Message {
+        let gain = self.level.get().0;
+        let session = match &state.session {
+            Some(session) => session,
+            None => {
+                return Message::new("STATUS")
+                    .with("state", "idle")
+                    .with("gain", gain)
+            }
+        };
+        let time = clock::diff(self.now(), session.startstamp());
+        let state = match &state.playback {
+            None => "loaded",
+            Some(_) if time < 0. => "armed",
+            Some(playback) => match playback.timeline.lock().unwrap().position_at(time) {
+                Some(_) => "playing",
+                None => "paused",
+            },
+        };
+        return Message::new("STATUS")
+            .with("state", state)
+            .with("testfile", &session.testfile)
+            .with("startat", session.startat)
+            .with("gain", gain);
+    }
+
+    pub fn handle(&self, msg: &Message) -> Message {
+        let mut state = self.state.lock().unwrap();
+        state.reap();
+        return match msg.command.as_str() {
+            "SESSION" => {
+                if msg.get("startat").is_none() {
+                    return Message::nak("Missing startat");
+                }
+                let reply = self.load(&mut state, msg);
+                if reply.command != "ACK" {
+                    return reply;
+                }
+                self.arm(&mut state, msg)
+            }
+            "LOAD" => self.load(&mut state, msg),
+            "ARM" => self.arm(&mut state, msg),
+            "STOP" => {
+                let playback = state.stop();
+                drop(state);
+                self.join(playback);
+                Message::new("ACK")
+            }
+            "PAUSE" | "RESUME" | "SEEK" => self.cue(&state, msg),
+            "GAIN" => self.gain(msg),
+            "STATUS" => self.status(&state),
+            other => Message::nak(&format!("Unknown command {}", other)),
+        };
+    }
+
+    fn serve_tcp(self: Arc<Self>, stream: TcpStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        while let Some(msg) = Message::read_from(&mut reader)? {
+            self.handle(&msg).write_to(&mut writer)?;
+        }
+        return Ok(());
+    }
+
+    fn serve_udp(self: Arc<Self>, socket: UdpSocket) {
+        let mut buf = [0; 65536];
+        let mut warned = false;
+        loop {
+            let (len, peer) =
+                match protocol::recv_from(&socket, &mut buf, "UDP receive", &mut warned) {
+                    Some(res) => res,
+                    None => continue,
+                };
+            let reply = match Message::read_from(&mut &buf[..len]) {
+                Ok(Some(msg)) => self.handle(&msg),
+                Ok(None) => continue,
+                Err(err) => Message::nak(&err.to_string()),
+            };
+            let mut out = Vec::new();
+            reply.write_to(&mut out).unwrap();
+            if let Err(err) = socket.send_to(&out, peer) {
+                println!("[WRN] Couldn't reply to {}: {}", peer, err);
+            }
+        }
+    }
+
+    /// Starts accepting commands on both TCP and UDP at `addr`, returns the
+    /// actually bound address (useful when binding port 0).
+    pub fn serve(self: Arc<Self>, addr: SocketAddr) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let socket = UdpSocket::bind(addr)?;
+
+        let daemon = Arc::clone(&self);
+        thread::spawn(move || daemon.serve_udp(socket));
+        thread::spawn(move || {
+            let mut warned = false;
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let daemon = Arc::clone(&self);
+                        thread::spawn(move || {
+                            if let Err(err) = daemon.serve_tcp(stream) {
+                                println!("[WRN] Control connection failed: {}", err);
+                            }
+                        });
+                    }
+                    Err(err) => protocol::back_off("Accepting a connection", &err, &mut warned),
+                }
+            }
+        });
+        return Ok(addr);
+    }
+}
@@ -0,0 +1,271 @@
+use super::*;
+use crate::protocol::request;
+use crate::resampler::Quality;
+use crate::wfs::Vec2;
+use std::time::{Duration, UNIX_EPOCH};
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Harness {
+    addr: SocketAddr,
+    played: Arc<Mutex<Vec<Session>>>,
+    level: Arc<Level>,
+    _daemon: Arc<Daemon>,
+}
+
+impl Harness {
+    fn new() -> Harness {
+        return Harness::with_time_source(None);
+    }
+
+    /// Starts a daemon on loopback whose player records the sessions it gets
+    /// and plays until stopped, or returns immediately for `startat == 1`.
+    fn with_time_source(time_source: Option<Arc<dyn TimeSource>>) -> Harness {
+        let played = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&played);
+        let level = Arc::new(Level::new(0.));
+        let daemon = Daemon::new(
+            Box::new(move |session: Session, stop: Arc<AtomicBool>, _| {
+                let finite = session.startat == 1;
+                recorder.lock().unwrap().push(session);
+                while !finite && !stop.load(Ordering::Relaxed) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }),
+            Arc::clone(&level),
+            time_source,
+        );
+        let addr = Arc::clone(&daemon)
+            .serve("127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        return Harness {
+            addr,
+            played,
+            level,
+            _daemon: daemon,
+        };
+    }
+    fn tcp(&self, msg: Message) -> Message {
+        return request(&self.addr, &msg, TIMEOUT).unwrap();
+    }
+    fn udp(&self, msg: Message) -> Message {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut out = Vec::new();
+        msg.write_to(&mut out).unwrap();
+        socket.send_to(&out, self.addr).unwrap();
+        let mut buf = [0; 65536];
+        let len = socket.recv(&mut buf).unwrap();
+        return Message::read_from(&mut &buf[..len]).unwrap().unwrap();
+    }
+    fn state(&self) -> String {
+        return self
+            .tcp(Message::new("STATUS"))
+            .get("state")
+            .unwrap()
+            .to_string();
+    }
+}
+
+fn testfile() -> String {
+    return concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml").to_string();
+}
+
+fn now_ns() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+}
+
+#[test]
+fn test_load_arm_stop() {
+    let harness = Harness::new();
+    assert_eq!(harness.state(), "idle");
+    let reply = harness.tcp(Message::new("ARM").with("startat", now_ns()));
+    assert_eq!(reply.command, "NAK");
+    let reply = harness.tcp(Message::new("LOAD").with("testfile", "/nonexistent.wav"));
+    assert_eq!(reply.command, "NAK");
+
+    let reply = harness.tcp(
+        Message::new("LOAD")
+            .with("testfile", testfile())
+            .with("quality", "high"),
+    );
+    assert_eq!(reply.command, "ACK");
+    assert_eq!(harness.state(), "loaded");
+
+    let startat = now_ns() + 3_600_000_000_000;
+    let reply = harness.tcp(Message::new("ARM").with("startat", startat));
+    assert_eq!(reply.command, "ACK");
+    let status = harness.tcp(Message::new("STATUS"));
+    assert_eq!(status.get("state"), Some("armed"));
+    assert_eq!(status.parse::<u64>("startat"), Ok(Some(startat)));
+    let reply = harness.tcp(Message::new("ARM").with("startat", startat));
+    assert_eq!(reply.command, "NAK");
+
+    assert_eq!(harness.tcp(Message::new("STOP")).command, "ACK");
+    assert_eq!(harness.state(), "loaded");
+
+    let played = harness.played.lock().unwrap();
+    assert_eq!(played.len(), 1);
+    assert_eq!(played[0].startat, startat);
+    assert_eq!(played[0].quality, Quality::High);
+    assert_eq!(played[0].desync_avg, 1000);
+}
+
+/// Network time an hour ahead of the system clock
+struct Ahead;
+
+impl TimeSource for Ahead {
+    fn offset(&self) -> Option<f64> {
+        return Some(3600.);
+    }
+
+    fn name(&self) -> String {
+        return "Ahead".to_string();
+    }
+}
+
+#[test]
+fn test_status_on_network_time() {
+    let harness = Harness::with_time_source(Some(Arc::new(Ahead)));
+    let msg = Message::new("SESSION")
+        .with("startat", now_ns() + 1_800_000_000_000)
+        .with("testfile", testfile());
+    assert_eq!(harness.tcp(msg).command, "ACK");
+    assert_eq!(harness.state(), "playing");
+    assert_eq!(harness.tcp(Message::new("STOP")).command, "ACK");
+}
+
+#[test]
+fn test_session_over_udp() {
+    let harness = Harness::new();
+    let session = Session {
+        startat: now_ns() - 1_000_000_000,
+        testfile: testfile(),
+        quality: Quality::Fast,
+        desync_avg: 100,
+        estimation_avg: 200,
+        source: Some("point:0,-1".parse().unwrap()),
+        trajectory: Some("0:-1,-1;2.5:1,-1".parse().unwrap()),
+        reference: Vec2::new(0., 2.),
+        predelay: 0.1,
+        geometry: Some(
+            "[[speaker]]\nid = \"a\"\nposition = [0, 0]\nangle = 90\nhost = \"pi\"\nchannel = 0\n"
+                .to_string(),
+        ),
+        looping: true,
+    };
+    assert_eq!(harness.udp(session.to_message()).command, "ACK");
+    assert_eq!(
+        harness.udp(Message::new("STATUS")).get("state"),
+        Some("playing")
+    );
+    assert_eq!(harness.udp(Message::new("BOGUS")).command, "NAK");
+    assert_eq!(harness.udp(Message::new("STOP")).command, "ACK");
+    let invalid = Session {
+        geometry: None,
+        ..session.clone()
+    }
+    .to_message()
+    .with("geometry", "speaker = 1");
+    assert_eq!(harness.udp(invalid).command, "NAK");
+    assert_eq!(harness.played.lock().unwrap()[..], [session]);
+}
+
+#[test]
+fn test_timeline_commands() {
+    let harness = Harness::new();
+    let cue = |command: &str, at: u64| Message::new(command).with("at", at);
+    let startat = now_ns() - 1_000_000_000;
+    assert_eq!(harness.tcp(cue("PAUSE", startat)).command, "NAK");
+    let msg = Message::new("SESSION")
+        .with("startat", startat)
+        .with("testfile", testfile());
+    assert_eq!(harness.tcp(msg).command, "ACK");
+    assert_eq!(harness.tcp(cue("PAUSE", startat - 1)).command, "NAK");
+    assert_eq!(harness.tcp(cue("PAUSE", now_ns())).command, "ACK");
+    assert_eq!(harness.state(), "paused");
+    assert_eq!(harness.tcp(cue("SEEK", now_ns())).command, "NAK");
+    let seek = cue("SEEK", now_ns()).with("position", 2.5);
+    assert_eq!(harness.tcp(seek).command, "ACK");
+    assert_eq!(harness.tcp(cue("RESUME", now_ns())).command, "ACK");
+    assert_eq!(harness.state(), "playing");
+    assert_eq!(harness.tcp(cue("RESUME", startat)).command, "NAK");
+    assert_eq!(harness.tcp(Message::new("STOP")).command, "ACK");
+}
+
+#[test]
+fn test_commands_while_stopping() {
+    let daemon = Daemon::new(
+        Box::new(|_, stop: Arc<AtomicBool>, _| {
+            while !stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            // Fading out
+            thread::sleep(Duration::from_millis(500));
+        }),
+        Arc::new(Level::new(0.)),
+        None,
+    );
+    let msg = Message::new("SESSION")
+        .with("startat", now_ns())
+        .with("testfile", testfile());
+    assert_eq!(daemon.handle(&msg).command, "ACK");
+    let stopping = Arc::clone(&daemon);
+    let stop = thread::spawn(move || stopping.handle(&Message::new("STOP")));
+    thread::sleep(Duration::from_millis(100));
+    let start = std::time::Instant::now();
+    let status = daemon.handle(&Message::new("STATUS"));
+    assert!(start.elapsed() < Duration::from_millis(100));
+    assert_eq!(status.get("state"), Some("loaded"));
+    let arm = Message::new("ARM").with("startat", now_ns());
+    assert_eq!(daemon.handle(&arm).command, "NAK");
+    assert_eq!(stop.join().unwrap().command, "ACK");
+    assert_eq!(daemon.handle(&arm).command, "ACK");
+    daemon.stop();
+}
+
+#[test]
+fn test_gain() {
+    let harness = Harness::new();
+    let gain = Message::new("GAIN").with("gain", -6.).with("ramp", 0.5);
+    assert_eq!(harness.udp(gain).command, "ACK");
+    assert_eq!(harness.level.get(), (-6., 0.5));
+    let status = harness.tcp(Message::new("STATUS"));
+    assert_eq!(status.parse::<f64>("gain"), Ok(Some(-6.)));
+    assert_eq!(harness.tcp(Message::new("GAIN")).command, "NAK");
+    let backwards = Message::new("GAIN").with("gain", 0.).with("ramp", -1.);
+    assert_eq!(harness.tcp(backwards).command, "NAK");
+    assert_eq!(harness.level.get(), (-6., 0.5));
+    let step = Message::new("GAIN").with("gain", 3.);
+    assert_eq!(harness.tcp(step).command, "ACK");
+    assert_eq!(harness.level.get(), (3., 0.));
+}
+
+#[test]
+fn test_playback_ends() {
+    let harness = Harness::new();
+    let msg = Message::new("SESSION")
+        .with("startat", 1)
+        .with("testfile", testfile());
+    assert_eq!(harness.tcp(msg.clone()).command, "ACK");
+    let mut state = harness.state();
+    for _ in 0..1000 {
+        if state == "loaded" {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+        state = harness.state();
+    }
+    assert_eq!(state, "loaded");
+    assert_eq!(harness.tcp(msg).command, "ACK");
+    for _ in 0..1000 {
+        if harness.played.lock().unwrap().len() == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(harness.played.lock().unwrap().len(), 2);
+}
@@ -0,0 +1,176 @@
+//! Audio file decoders behind one interface. The synchronization loop reads
+//! a period at a time and seeks by a few frames whenever it corrects the
+//! desync, so every decoder has to seek to an exact frame. WAV files seek
+//! directly, compressed formats decode front to back from the closest point
+//! their index allows and are made seekable by `Seekable`. `Mixed` picks and
+//! mixes the channels of a multichannel file for the outputs of one device. `Playlist` and `Looped` put several
+//! files or endless repetitions of one on the timeline of a session.
+
+mod flac;
+mod mix;
+mod playlist;
+mod vorbis;
+mod wav;
+
+#[cfg(test)]
+pub mod encode;
+#[cfg(test)]
+mod tests;
+
+pub use self::flac::FlacBlocks;
+pub use self::mix::{ChannelMap, Input, Mixed, Route};
+pub use self::playlist::{length, Looped, Playlist};
+pub use self::vorbis::VorbisBlocks;
+pub use self::wav::WavDecoder;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+
+/// Frames kept behind the read position for backward seeks.
+pub const HISTORY: u32 = 1 << 16;
+
+pub trait Decoder {
+    fn channels(&self) -> usize;
+    fn sample_rate(&self) -> u32;
+    /// Length of the stream, if the file tells it upfront
+    fn frames(&self) -> Option<u64>;
+    /// Index of the frame read next, on a timeline that may outlast the
+    /// 32-bit frame numbers of a single file
+    fn position(&self) -> u64;
+    /// Moves to `frame`, beyond the end nothing is read anymore.
+    fn seek(&mut self, frame: u64) -> Result<(), String>;
+    /// Appends interleaved samples, full scale at ±1, until `buf` holds
+    /// `len` of them or the stream ends.
+    fn read(&mut self, buf: &mut Vec<f32>, len: usize) -> Result<(), String>;
+}
+
+/// Opens `path` with the decoder its contents call for, `.m3u` and `.m3u8`
+/// files as a `Playlist`.
+pub fn open(path: &str) -> Result<Box<dyn Decoder>, String> {
+    if path.ends_with(".m3u") || path.ends_with(".m3u8") {
+        return Ok(Box::new(Playlist::open(path)?));
+    }
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map_err(|err| format!("Couldn't read {}: {}", path, err))?;
+    return match &magic {
+        b"RIFF" => Ok(Box::new(WavDecoder::open(path)?)),
+        b"fLaC" => Ok(Box::new(Seekable::new(FlacBlocks::open(path)?))),
+        b"OggS" => Ok(Box::new(Seekable::new(VorbisBlocks::open(path)?))),
+        _ => Err(format!("{}: Unknown file format", path)),
+    };
+}
+
+/// Source of consecutive blocks of frames, as compressed formats decode.
+pub trait Blocks {
+    fn channels(&self) -> usize;
+    fn sample_rate(&self) -> u32;
+    fn frames(&self) -> Option<u32>;
+    /// Appends the next block of interleaved samples, false at the end.
+    fn next_block(&mut self, buf: &mut Vec<f32>) -> Result<bool, String>;
+    /// Starts over from the first frame.
+    fn rewind(&mut self) -> Result<(), String>;
+    /// Goes on from a frame at or before `frame` found in the index of the
+    /// file, returns that frame or `None` without an index to seek with,
+    /// leaving the position as it was.
+    fn seek(&mut self, frame: u32) -> Result<Option<u32>, String>;
+}
+
+/// Seeks to any frame of `Blocks`. The last `HISTORY` frames stay decoded
+/// so the short backward jumps of the correction are cheap. Seeking further
+/// back or more than `HISTORY` frames ahead starts from the index of the
+/// file. Without one it decodes on up to the frame, or again from the start
+/// when the frame is behind.
+pub struct Seekable<B: Blocks> {
+    blocks: B,
+    /// Decoded samples starting at frame `start`
+    decoded: VecDeque<f32>,
+    start: u32,
+    position: u32,
+    ended: bool,
+}
+
+impl<B: Blocks> Seekable<B> {
+    pub fn new(blocks: B) -> Seekable<B> {
+        return Seekable {
+            blocks,
+            decoded: VecDeque::new(),
+            start: 0,
+            position: 0,
+            ended: false,
+        };
+    }
+
+    fn decode(&mut self) -> Result<(), String> {
+        let mut block = Vec::new();
+        self.ended = !self.blocks.next_block(&mut block)?;
+        self.decoded.extend(block);
+        let channels = self.blocks.channels();
+        let behind = (self.position - self.start).min((self.decoded.len() / channels) as u32);
+        if behind > HISTORY {
+            self.decoded.drain(..(behind - HISTORY) as usize * channels);
+            self.start += behind - HISTORY;
+        }
+        return Ok(());
+    }
+}
+
+impl<B: Blocks> Decoder for Seekable<B> {
+    fn channels(&self) -> usize {
+        return self.blocks.channels();
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return self.blocks.sample_rate();
+    }
+
+    fn frames(&self) -> Option<u64> {
+        return self.blocks.frames().map(u64::from);
+    }
+
+    fn position(&self) -> u64 {
+        return self.position as u64;
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        // Past the end of any file
+        let frame = frame.min(u32::MAX as u64) as u32;
+        let decoded = self.start + (self.decoded.len() / self.channels()) as u32;
+        if frame < self.start || frame > decoded.saturating_add(HISTORY) {
+            let start = match self.blocks.seek(frame)? {
+                Some(start) if start <= frame => Some(start),
+                None if frame >= self.start => None,
+                _ => {
+                    self.blocks.rewind()?;
+                    Some(0)
+                }
+            };
+            if let Some(start) = start {
+                self.decoded.clear();
+                self.ended = false;
+                self.start = start;
+            }
+        }
+        self.position = frame;
+        return Ok(());
+    }
+
+    fn read(&mut self, buf: &mut Vec<f32>, len: usize) -> Result<(), String> {
+        let channels = self.channels();
+        while buf.len() + channels <= len {
+            let offset = (self.position - self.start) as usize * channels;
+            if offset < self.decoded.len() {
+                let count = (len - buf.len()).min(self.decoded.len() - offset) / channels;
+                buf.extend(self.decoded.range(offset..offset + count * channels));
+                self.position += count as u32;
+            } else if self.ended {
+                break;
+            } else {
+                self.decode()?;
+            }
+        }
+        return Ok(());
+    }
+}
@@ -0,0 +1,338 @@
+//! Minimal FLAC encoder storing every subframe verbatim and minimal Ogg
+//! Vorbis encoder with a single codebook for the spectrum, enough to write
+//! test files without external tools.
+
+use std::path::Path;
+
+const BLOCK_SIZE: usize = 4096;
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used in the last byte
+    used: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        for bit in (0..bits).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            *self.bytes.last_mut().unwrap() |= (((value >> bit) & 1) as u8) << (7 - self.used);
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte as u64, 8);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    return crc;
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    return crc;
+}
+
+/// Frame number in the UTF-8 like coding of frame headers.
+fn coded_number(number: u32) -> Vec<u8> {
+    return if number < 0x80 {
+        vec![number as u8]
+    } else if number < 0x800 {
+        vec![0xC0 | (number >> 6) as u8, 0x80 | (number & 0x3F) as u8]
+    } else {
+        vec![
+            0xE0 | (number >> 12) as u8,
+            0x80 | ((number >> 6) & 0x3F) as u8,
+            0x80 | (number & 0x3F) as u8,
+        ]
+    };
+}
+
+/// Writes interleaved `samples` of 8, 12, 16, 20 or 24 bits, with a seek
+/// point at every frame.
+pub fn write_flac(path: &Path, channels: u32, rate: u32, bits: u32, samples: &[i32]) {
+    let size_code = match bits {
+        8 => 1,
+        12 => 2,
+        16 => 4,
+        20 => 5,
+        24 => 6,
+        _ => panic!("Unsupported sample size {}", bits),
+    };
+    let mut frames = Vec::new();
+    let mut seek_table = BitWriter::default();
+    let mut offset = 0;
+    for (number, block) in samples.chunks(BLOCK_SIZE * channels as usize).enumerate() {
+        let mut frame = BitWriter::default();
+        frame.write(0b11_1111_1111_1110, 14);
+        frame.write(0, 2);
+        // Block size in 16 bits after the number, sample rate from STREAMINFO
+        frame.write(0b0111, 4);
+        frame.write(0, 4);
+        frame.write(channels as u64 - 1, 4);
+        frame.write(size_code, 3);
+        frame.write(0, 1);
+        frame.write_bytes(&coded_number(number as u32));
+        frame.write((block.len() / channels as usize) as u64 - 1, 16);
+        let crc = crc8(&frame.bytes);
+        frame.write(crc as u64, 8);
+        for channel in 0..channels as usize {
+            // Verbatim subframe without wasted bits
+            frame.write(0b0000_0010, 8);
+            for sample in block.iter().skip(channel).step_by(channels as usize) {
+                frame.write(*sample as u64 & ((1 << bits) - 1), bits);
+            }
+        }
+        let crc = crc16(&frame.bytes);
+        frame.used = 0;
+        frame.write(crc as u64, 16);
+        seek_table.write((number * BLOCK_SIZE) as u64, 64);
+        seek_table.write(offset, 64);
+        seek_table.write((block.len() / channels as usize) as u64, 16);
+        offset += frame.bytes.len() as u64;
+        frames.extend(frame.bytes);
+    }
+
+    let mut out = BitWriter::default();
+    out.write_bytes(b"fLaC");
+    // STREAMINFO, 34 bytes long
+    out.write(0, 1);
+    out.write(0, 7);
+    out.write(34, 24);
+    out.write(BLOCK_SIZE as u64, 16);
+    out.write(BLOCK_SIZE as u64, 16);
+    out.write(0, 24);
+    out.write(0, 24);
+    out.write(rate as u64, 20);
+    out.write(channels as u64 - 1, 3);
+    out.write(bits as u64 - 1, 5);
+    out.write((samples.len() / channels as usize) as u64, 36);
+    out.write_bytes(&[0; 16]);
+    // Last metadata block, SEEKTABLE
+    out.write(1, 1);
+    out.write(3, 7);
+    out.write(seek_table.bytes.len() as u64, 24);
+    out.write_bytes(&seek_table.bytes);
+    out.write_bytes(&frames);
+    std::fs::write(path, out.bytes).unwrap();
+}
+
+/// Blocksize of every Vorbis packet, as a power of two
+const VORBIS_BLOCKSIZE: u32 = 8;
+/// Vorbis packets on every Ogg page
+const PACKETS_PER_PAGE: usize = 16;
+
+/// Packs bits from the least significant one up, as Vorbis does.
+#[derive(Default)]
+struct LsbWriter {
+    bytes: Vec<u8>,
+    /// Bits used in the last byte
+    used: u32,
+}
+
+impl LsbWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        for bit in 0..bits {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            *self.bytes.last_mut().unwrap() |= (((value >> bit) & 1) as u8) << self.used;
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte as u64, 8);
+        }
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for byte in bytes {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    return crc;
+}
+
+/// Appends an Ogg page holding `packets`, none longer than 254 bytes.
+fn write_page(out: &mut Vec<u8>, flags: u8, granule: u64, sequence: u32, packets: &[Vec<u8>]) {
+    let start = out.len();
+    out.extend_from_slice(b"OggS");
+    out.push(0);
+    out.push(flags);
+    out.extend_from_slice(&granule.to_le_bytes());
+    out.extend_from_slice(&0x7069_7766u32.to_le_bytes());
+    out.extend_from_slice(&sequence.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.push(packets.len() as u8);
+    for packet in packets {
+        assert!(packet.len() < 255);
+        out.push(packet.len() as u8);
+    }
+    for packet in packets {
+        out.extend_from_slice(packet);
+    }
+    let crc = crc32(&out[start..]);
+    out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Vorbis header of `kind`, followed by what `body` writes.
+fn vorbis_header(kind: u64, body: impl FnOnce(&mut LsbWriter)) -> Vec<u8> {
+    let mut header = LsbWriter::default();
+    header.write(kind, 8);
+    header.write_bytes(b"vorbis");
+    body(&mut header);
+    header.write(1, 1);
+    return header.bytes;
+}
+
+/// Writes `packets` Vorbis packets of pseudo random spectra, which decode
+/// to `(packets - 1) * 128` frames. Every packet is a short block with a
+/// flat floor, the residue of each of the 128 bins is one of -1.5, -0.5,
+/// 0.5 and 1.5.
+pub fn write_vorbis(path: &Path, channels: u32, rate: u32, packets: usize) {
+    let ident = vorbis_header(1, |header| {
+        header.write(0, 32);
+        header.write(channels as u64, 8);
+        header.write(rate as u64, 32);
+        for _ in 0..3 {
+            header.write(0, 32);
+        }
+        header.write(VORBIS_BLOCKSIZE as u64, 4);
+        header.write(VORBIS_BLOCKSIZE as u64, 4);
+    });
+    let comment = vorbis_header(3, |header| {
+        header.write(5, 32);
+        header.write_bytes(b"piwfs");
+        header.write(0, 32);
+    });
+    let bins = 1u64 << (VORBIS_BLOCKSIZE - 1);
+    let setup = vorbis_header(5, |header| {
+        header.write(1, 8);
+        // Classbook, two entries of one bit
+        header.write(0x56_4342, 24);
+        header.write(1, 16);
+        header.write(2, 24);
+        header.write(0, 2);
+        header.write(0, 5);
+        header.write(0, 5);
+        header.write(0, 4);
+        // Residue book, four entries of two bits for -1.5 + 0..3
+        header.write(0x56_4342, 24);
+        header.write(1, 16);
+        header.write(4, 24);
+        header.write(0, 2);
+        for _ in 0..4 {
+            header.write(1, 5);
+        }
+        header.write(1, 4);
+        header.write(0x8000_0000 | 787 << 21 | 3, 32);
+        header.write(788 << 21 | 1, 32);
+        header.write(1, 4);
+        header.write(0, 1);
+        for multiplicand in 0..4 {
+            header.write(multiplicand, 2);
+        }
+        // Time domain transform
+        header.write(0, 6);
+        header.write(0, 16);
+        // Floor 1 with only the end points
+        header.write(0, 6);
+        header.write(1, 16);
+        header.write(0, 5);
+        header.write(0, 2);
+        header.write(VORBIS_BLOCKSIZE as u64 - 1, 4);
+        // Residue 1 in a single partition, its class 1 uses the residue book
+        header.write(0, 6);
+        header.write(1, 16);
+        header.write(0, 24);
+        header.write(bins, 24);
+        header.write(bins - 1, 24);
+        header.write(1, 6);
+        header.write(0, 8);
+        header.write(0, 4);
+        header.write(1, 4);
+        header.write(1, 8);
+        // Mapping without coupling
+        header.write(0, 6);
+        header.write(0, 16);
+        header.write(0, 4);
+        header.write(0, 24);
+        // Mode of short blocks
+        header.write(0, 6);
+        header.write(0, 1);
+        header.write(0, 40);
+    });
+
+    let mut out = Vec::new();
+    write_page(&mut out, 0x02, 0, 0, &[ident]);
+    write_page(&mut out, 0, 0, 1, &[comment, setup]);
+    let mut random = 1u32;
+    let audio: Vec<Vec<u8>> = (0..packets)
+        .map(|_| {
+            let mut packet = LsbWriter::default();
+            packet.write(0, 1);
+            for _ in 0..channels {
+                packet.write(1, 1);
+                packet.write(200, 8);
+                packet.write(200, 8);
+            }
+            for _ in 0..channels {
+                packet.write(1, 1);
+            }
+            for _ in 0..channels {
+                for _ in 0..bins {
+                    random = random.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    let entry = random >> 30;
+                    packet.write((entry >> 1) as u64, 1);
+                    packet.write((entry & 1) as u64, 1);
+                }
+            }
+            packet.bytes
+        })
+        .collect();
+    for (number, page) in audio.chunks(PACKETS_PER_PAGE).enumerate() {
+        let last = number * PACKETS_PER_PAGE + page.len() - 1;
+        let flags = if last == packets - 1 { 0x04 } else { 0 };
+        let granule = last as u64 * bins;
+        write_page(&mut out, flags, granule, number as u32 + 2, page);
+    }
+    std::fs::write(path, out).unwrap();
+}
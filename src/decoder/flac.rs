@@ -0,0 +1,136 @@
+use super::Blocks;
+
+use claxon::frame::FrameReader;
+use claxon::input::BufferedReader;
+use claxon::metadata::StreamInfo;
+use claxon::FlacReader;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Metadata block type of the seek table
+const SEEKTABLE: u8 = 3;
+/// Sample number of unused seek points
+const PLACEHOLDER: u64 = u64::MAX;
+
+/// FLAC files, decoded a frame at a time. Seeks jump to the closest point of
+/// the seek table, if the file has one.
+pub struct FlacBlocks {
+    path: String,
+    streaminfo: StreamInfo,
+    /// Byte offset of the first frame
+    first_frame: u64,
+    /// Sample number and byte offset from the first frame of every seek
+    /// point, in order
+    seek_points: Vec<(u64, u64)>,
+    frames: FrameReader<BufferedReader<File>>,
+    /// Reused between frames
+    buffer: Vec<i32>,
+}
+
+/// Offset of the first frame and the seek points of the FLAC file `path`.
+fn read_metadata(path: &str) -> std::io::Result<(u64, Vec<(u64, u64)>)> {
+    let mut file = File::open(path)?;
+    let mut offset = 4;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut seek_points = Vec::new();
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        offset += 4 + length;
+        if header[0] & 0x7F == SEEKTABLE {
+            let mut table = vec![0u8; length as usize];
+            file.read_exact(&mut table)?;
+            for point in table.chunks_exact(18) {
+                let sample = u64::from_be_bytes(point[..8].try_into().unwrap());
+                let bytes = u64::from_be_bytes(point[8..16].try_into().unwrap());
+                if sample != PLACEHOLDER {
+                    seek_points.push((sample, bytes));
+                }
+            }
+        } else {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        if header[0] & 0x80 != 0 {
+            return Ok((offset, seek_points));
+        }
+    }
+}
+
+/// Frames of `path` starting `offset` bytes into the file
+fn frames_at(path: &str, offset: u64) -> Result<FrameReader<BufferedReader<File>>, String> {
+    let mut file = File::open(path).map_err(|err| format!("Couldn't open {}: {}", path, err))?;
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|err| format!("Couldn't seek in {}: {}", path, err))?;
+    return Ok(FrameReader::new(BufferedReader::new(file)));
+}
+
+impl FlacBlocks {
+    pub fn open(path: &str) -> Result<FlacBlocks, String> {
+        let reader =
+            FlacReader::open(path).map_err(|err| format!("Couldn't open {}: {}", path, err))?;
+        let (first_frame, seek_points) =
+            read_metadata(path).map_err(|err| format!("Couldn't open {}: {}", path, err))?;
+        return Ok(FlacBlocks {
+            path: path.to_string(),
+            streaminfo: reader.streaminfo(),
+            first_frame,
+            seek_points,
+            frames: frames_at(path, first_frame)?,
+            buffer: Vec::new(),
+        });
+    }
+}
+
+impl Blocks for FlacBlocks {
+    fn channels(&self) -> usize {
+        return self.streaminfo.channels as usize;
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return self.streaminfo.sample_rate;
+    }
+
+    fn frames(&self) -> Option<u32> {
+        return self.streaminfo.samples.map(|samples| samples as u32);
+    }
+
+    fn next_block(&mut self, buf: &mut Vec<f32>) -> Result<bool, String> {
+        let buffer = std::mem::take(&mut self.buffer);
+        let block = match self.frames.read_next_or_eof(buffer) {
+            Ok(Some(block)) => block,
+            Ok(None) => return Ok(false),
+            Err(err) => return Err(err.to_string()),
+        };
+        let scale = 1. / (1i64 << (self.streaminfo.bits_per_sample - 1)) as f32;
+        for frame in 0..block.duration() {
+            for channel in 0..block.channels() {
+                buf.push(block.sample(channel, frame) as f32 * scale);
+            }
+        }
+        self.buffer = block.into_buffer();
+        return Ok(true);
+    }
+
+    fn rewind(&mut self) -> Result<(), String> {
+        self.frames = frames_at(&self.path, self.first_frame)?;
+        return Ok(());
+    }
+
+    fn seek(&mut self, frame: u32) -> Result<Option<u32>, String> {
+        let point = self
+            .seek_points
+            .iter()
+            .rev()
+            .find(|&&(sample, _)| sample <= frame as u64);
+        return match point {
+            Some(&(sample, bytes)) => {
+                self.frames = frames_at(&self.path, self.first_frame + bytes)?;
+                Ok(Some(sample as u32))
+            }
+            None => Ok(None),
+        };
+    }
+}
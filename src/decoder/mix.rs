@@ -0,0 +1,186 @@
+use super::Decoder;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// File channels feeding one output channel, `All` mixes every channel at
+/// an equal share.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    /// Zero-based channel of the file
+    Channel(usize),
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Route {
+    pub input: Input,
+    /// Zero-based output channel
+    pub output: usize,
+    pub gain: f32,
+}
+
+/// Which file channels go to which output channels with what gain, parsed
+/// from comma-separated entries `SRC[:DST][*GAIN]` with one-based channel
+/// numbers. An entry without a destination goes to the output numbered
+/// like its position in the list, so `5,6` plays channels 5 and 6 on the
+/// first two outputs. Routes to the same output add up, `1:1*0.5,2:1*0.5`
+/// downmixes stereo, and `*:1` averages every channel of the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelMap {
+    pub routes: Vec<Route>,
+}
+
+impl ChannelMap {
+    /// Output channels the map feeds
+    pub fn outputs(&self) -> usize {
+        return self
+            .routes
+            .iter()
+            .map(|route| route.output + 1)
+            .max()
+            .unwrap_or(0);
+    }
+
+    /// Gains of every file channel for every output channel, for a file
+    /// of `inputs` channels.
+    pub fn matrix(&self, inputs: usize) -> Result<Vec<Vec<f32>>, String> {
+        let mut matrix = vec![vec![0.; inputs]; self.outputs()];
+        for route in &self.routes {
+            let row = &mut matrix[route.output];
+            match route.input {
+                Input::Channel(channel) if channel >= inputs => {
+                    return Err(format!(
+                        "Channel {} mapped but the file has {} channels",
+                        channel + 1,
+                        inputs
+                    ));
+                }
+                Input::Channel(channel) => row[channel] += route.gain,
+                Input::All => {
+                    for gain in row.iter_mut() {
+                        *gain += route.gain / inputs as f32;
+                    }
+                }
+            }
+        }
+        return Ok(matrix);
+    }
+}
+
+impl FromStr for ChannelMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ChannelMap, String> {
+        let channel = |text: &str| -> Result<usize, String> {
+            return match text.trim().parse::<usize>() {
+                Ok(channel) if channel > 0 => Ok(channel - 1),
+                _ => Err(format!("Bad channel number {}", text)),
+            };
+        };
+        let mut routes = Vec::new();
+        for (index, entry) in s.split(',').enumerate() {
+            let (entry, gain) = match entry.rfind('*').filter(|&at| at > 0) {
+                Some(at) => (
+                    &entry[..at],
+                    entry[at + 1..]
+                        .trim()
+                        .parse::<f32>()
+                        .map_err(|_| format!("Bad gain in {}", entry))?,
+                ),
+                None => (entry, 1.),
+            };
+            let (input, output) = match entry.find(':') {
+                Some(at) => (&entry[..at], channel(&entry[at + 1..])?),
+                None => (entry, index),
+            };
+            let input = match input.trim() {
+                "*" => Input::All,
+                input => Input::Channel(channel(input)?),
+            };
+            routes.push(Route {
+                input,
+                output,
+                gain,
+            });
+        }
+        return Ok(ChannelMap { routes });
+    }
+}
+
+impl fmt::Display for ChannelMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, route) in self.routes.iter().enumerate() {
+            if index > 0 {
+                write!(f, ",")?;
+            }
+            match route.input {
+                Input::Channel(channel) => write!(f, "{}", channel + 1)?,
+                Input::All => write!(f, "*")?,
+            }
+            write!(f, ":{}", route.output + 1)?;
+            if route.gain != 1. {
+                write!(f, "*{}", route.gain)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Decoder whose channels are mixed from the channels of another one by a
+/// `ChannelMap`.
+pub struct Mixed {
+    decoder: Box<dyn Decoder>,
+    matrix: Vec<Vec<f32>>,
+}
+
+impl Mixed {
+    pub fn new(decoder: Box<dyn Decoder>, map: &ChannelMap) -> Result<Mixed, String> {
+        let matrix = map.matrix(decoder.channels())?;
+        if matrix.is_empty() {
+            return Err("Channel map has no outputs".to_string());
+        }
+        return Ok(Mixed { decoder, matrix });
+    }
+}
+
+impl Decoder for Mixed {
+    fn channels(&self) -> usize {
+        return self.matrix.len();
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return self.decoder.sample_rate();
+    }
+
+    fn frames(&self) -> Option<u64> {
+        return self.decoder.frames();
+    }
+
+    fn position(&self) -> u64 {
+        return self.decoder.position();
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        return self.decoder.seek(frame);
+    }
+
+    fn read(&mut self, buf: &mut Vec<f32>, len: usize) -> Result<(), String> {
+        let inputs = self.decoder.channels();
+        let frames = len.saturating_sub(buf.len()) / self.matrix.len();
+        let mut input = Vec::new();
+        let result = self.decoder.read(&mut input, frames.saturating_mul(inputs));
+        for frame in input.chunks_exact(inputs) {
+            for gains in &self.matrix {
+                buf.push(
+                    gains
+                        .iter()
+                        .zip(frame)
+                        .map(|(gain, sample)| gain * sample)
+                        .sum(),
+                );
+            }
+        }
+        return result;
+    }
+}
@@ -0,0 +1,273 @@
+use super::Decoder;
+
+use std::path::Path;
+
+/// Frames read at a time to measure streams that don't tell their length
+const CHUNK: usize = 1 << 16;
+
+/// Length of `decoder` in frames. Streams that don't tell it upfront are
+/// decoded once to the end, then rewound.
+pub fn length(decoder: &mut dyn Decoder) -> Result<u64, String> {
+    if let Some(frames) = decoder.frames() {
+        return Ok(frames);
+    }
+    decoder.seek(0)?;
+    let channels = decoder.channels();
+    let mut buf = Vec::with_capacity(CHUNK * channels);
+    let mut frames = 0u64;
+    loop {
+        buf.clear();
+        decoder.read(&mut buf, CHUNK * channels)?;
+        if buf.is_empty() {
+            break;
+        }
+        frames += (buf.len() / channels) as u64;
+    }
+    decoder.seek(0)?;
+    return Ok(frames);
+}
+
+struct Item {
+    /// First frame of the item on the timeline
+    start: u64,
+    frames: u64,
+    decoder: Box<dyn Decoder>,
+}
+
+/// Files played one after another on a single timeline starting at frame 0,
+/// which the player puts at `startat`. Every item starts either where the
+/// previous one ends or at a given time, the gap before it is silence. As
+/// the timeline only depends on the files, every slave switches items at
+/// the same frame.
+///
+/// A playlist file lists one path per line, relative to the directory of
+/// the playlist. Lines starting with `#` are comments as in M3U, except
+/// `#PIWFS-START:<SECONDS>` which starts the next item that many seconds
+/// after `startat`.
+pub struct Playlist {
+    items: Vec<Item>,
+    channels: usize,
+    sample_rate: u32,
+    position: u64,
+}
+
+impl Playlist {
+    /// Puts `entries` on the timeline, an entry without a start time
+    /// follows the previous one gaplessly. All of them need the same
+    /// channels and sample rate.
+    pub fn new(entries: Vec<(Box<dyn Decoder>, Option<f64>)>) -> Result<Playlist, String> {
+        let (channels, sample_rate) = match entries.first() {
+            Some((decoder, _)) => (decoder.channels(), decoder.sample_rate()),
+            None => return Err("Empty playlist".to_string()),
+        };
+        let mut items: Vec<Item> = Vec::new();
+        for (index, (mut decoder, start)) in entries.into_iter().enumerate() {
+            if decoder.channels() != channels || decoder.sample_rate() != sample_rate {
+                return Err(format!(
+                    "Item {} has {} channels at {} Hz, the first one {} channels at {} Hz",
+                    index + 1,
+                    decoder.channels(),
+                    decoder.sample_rate(),
+                    channels,
+                    sample_rate
+                ));
+            }
+            let end = items.last().map_or(0, |item| item.start + item.frames);
+            let start = match start {
+                Some(seconds) if seconds < 0. => {
+                    return Err(format!("Item {} starts before startat", index + 1));
+                }
+                Some(seconds) => (seconds * sample_rate as f64).round() as u64,
+                None => end,
+            };
+            if start < end {
+                return Err(format!(
+                    "Item {} starts before the previous one ends",
+                    index + 1
+                ));
+            }
+            let frames = length(decoder.as_mut())?;
+            if start.checked_add(frames).is_none() {
+                return Err(format!("Item {} ends too late", index + 1));
+            }
+            items.push(Item {
+                start,
+                frames,
+                decoder,
+            });
+        }
+        return Ok(Playlist {
+            items,
+            channels,
+            sample_rate,
+            position: 0,
+        });
+    }
+
+    /// Opens a playlist file and every item listed in it.
+    pub fn open(path: &str) -> Result<Playlist, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Couldn't read {}: {}", path, err))?;
+        let base = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        let mut entries = Vec::new();
+        let mut start = None;
+        for line in text.lines().map(str::trim) {
+            if let Some(seconds) = line.strip_prefix("#PIWFS-START:") {
+                start = Some(
+                    seconds
+                        .trim()
+                        .parse::<f64>()
+                        .map_err(|_| format!("{}: Bad start time {}", path, seconds))?,
+                );
+            } else if !line.is_empty() && !line.starts_with('#') {
+                let item = base.join(line);
+                let item = item.to_str().ok_or_else(|| format!("Bad path {}", line))?;
+                entries.push((super::open(item)?, start.take()));
+            }
+        }
+        return Playlist::new(entries).map_err(|err| format!("{}: {}", path, err));
+    }
+
+    /// First frame of every item on the timeline
+    pub fn starts(&self) -> Vec<u64> {
+        return self.items.iter().map(|item| item.start).collect();
+    }
+}
+
+impl Decoder for Playlist {
+    fn channels(&self) -> usize {
+        return self.channels;
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return self.sample_rate;
+    }
+
+    fn frames(&self) -> Option<u64> {
+        return self.items.last().map(|item| item.start + item.frames);
+    }
+
+    fn position(&self) -> u64 {
+        return self.position;
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        self.position = frame.min(self.frames().unwrap_or(0));
+        return Ok(());
+    }
+
+    fn read(&mut self, buf: &mut Vec<f32>, len: usize) -> Result<(), String> {
+        let channels = self.channels;
+        let end = self.frames().unwrap_or(0);
+        while buf.len() + channels <= len && self.position < end {
+            let wanted = ((len - buf.len()) / channels) as u64;
+            let index = self
+                .items
+                .iter()
+                .rposition(|item| item.start <= self.position);
+            let item = match index {
+                Some(index)
+                    if self.position < self.items[index].start + self.items[index].frames =>
+                {
+                    &mut self.items[index]
+                }
+                _ => {
+                    // Silence until the next item starts
+                    let next = self
+                        .items
+                        .iter()
+                        .find(|item| item.start > self.position)
+                        .map_or(end, |item| item.start);
+                    let silence = wanted.min(next - self.position);
+                    buf.resize(buf.len() + silence as usize * channels, 0.);
+                    self.position += silence;
+                    continue;
+                }
+            };
+            let local = self.position - item.start;
+            if item.decoder.position() != local {
+                item.decoder.seek(local)?;
+            }
+            let count = wanted.min(item.frames - local);
+            let before = buf.len();
+            item.decoder.read(buf, before + count as usize * channels)?;
+            let read = ((buf.len() - before) / channels) as u64;
+            if read < count {
+                // Shorter than measured, the timeline stays as it is
+                buf.resize(before + count as usize * channels, 0.);
+            }
+            self.position += count;
+        }
+        return Ok(());
+    }
+}
+
+/// Plays another decoder over and over, its last frame followed by the
+/// first one. Positions keep counting up, frame `n` of the timeline is
+/// frame `n % length` of the file, for far longer than any installation
+/// runs.
+pub struct Looped {
+    decoder: Box<dyn Decoder>,
+    length: u64,
+    position: u64,
+}
+
+impl Looped {
+    pub fn new(mut decoder: Box<dyn Decoder>) -> Result<Looped, String> {
+        let length = length(decoder.as_mut())?;
+        if length == 0 {
+            return Err("Can't loop an empty file".to_string());
+        }
+        return Ok(Looped {
+            decoder,
+            length,
+            position: 0,
+        });
+    }
+
+    /// Frames of one pass through the file
+    pub fn length(&self) -> u64 {
+        return self.length;
+    }
+}
+
+impl Decoder for Looped {
+    fn channels(&self) -> usize {
+        return self.decoder.channels();
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return self.decoder.sample_rate();
+    }
+
+    fn frames(&self) -> Option<u64> {
+        return None;
+    }
+
+    fn position(&self) -> u64 {
+        return self.position;
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        self.position = frame;
+        return self.decoder.seek(frame % self.length);
+    }
+
+    fn read(&mut self, buf: &mut Vec<f32>, len: usize) -> Result<(), String> {
+        let channels = self.decoder.channels();
+        while buf.len() + channels <= len {
+            let local = self.position % self.length;
+            if self.decoder.position() != local {
+                self.decoder.seek(local)?;
+            }
+            let count = (((len - buf.len()) / channels) as u64).min(self.length - local);
+            let before = buf.len();
+            self.decoder.read(buf, before + count as usize * channels)?;
+            if buf.len() < before + count as usize * channels {
+                buf.resize(before + count as usize * channels, 0.);
+            }
+            self.position += count;
+        }
+        return Ok(());
+    }
+}
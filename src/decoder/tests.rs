@@ -0,0 +1,467 @@
+use super::encode::{write_flac, write_vorbis};
+use super::*;
+
+use std::path::PathBuf;
+
+/// Counts up, every sample tells its frame and channel.
+struct Ramp {
+    channels: usize,
+    frames: u32,
+    block: u32,
+    next: u32,
+    rewinds: usize,
+    /// Frames between the points of the index, none without one
+    index: Option<u32>,
+    seeks: usize,
+}
+
+fn ramp_sample(frame: u32, channel: usize) -> f32 {
+    return frame as f32 + channel as f32 / 4.;
+}
+
+impl Blocks for Ramp {
+    fn channels(&self) -> usize {
+        return self.channels;
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return 48000;
+    }
+
+    fn frames(&self) -> Option<u32> {
+        return None;
+    }
+
+    fn next_block(&mut self, buf: &mut Vec<f32>) -> Result<bool, String> {
+        if self.next == self.frames {
+            return Ok(false);
+        }
+        let end = (self.next + self.block).min(self.frames);
+        for frame in self.next..end {
+            for channel in 0..self.channels {
+                buf.push(ramp_sample(frame, channel));
+            }
+        }
+        self.next = end;
+        return Ok(true);
+    }
+
+    fn rewind(&mut self) -> Result<(), String> {
+        self.next = 0;
+        self.rewinds += 1;
+        return Ok(());
+    }
+
+    fn seek(&mut self, frame: u32) -> Result<Option<u32>, String> {
+        let index = match self.index {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        self.seeks += 1;
+        self.next = frame.min(self.frames) / index * index;
+        return Ok(Some(self.next));
+    }
+}
+
+fn ramp(frames: u32) -> Seekable<Ramp> {
+    return Seekable::new(Ramp {
+        channels: 2,
+        frames,
+        block: 1000,
+        next: 0,
+        rewinds: 0,
+        index: Some(5000),
+        seeks: 0,
+    });
+}
+
+/// Checks that `buf` holds consecutive frames starting at `first`.
+fn assert_frames(buf: &[f32], first: u32) {
+    for (frame, samples) in buf.chunks(2).enumerate() {
+        assert_eq!(samples[0], ramp_sample(first + frame as u32, 0));
+        assert_eq!(samples[1], ramp_sample(first + frame as u32, 1));
+    }
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    return std::env::temp_dir().join(format!("piwfs-decoder-{}", name));
+}
+
+#[test]
+fn test_seekable_read() {
+    let mut decoder = ramp(5500);
+    let mut buf = Vec::new();
+    decoder.read(&mut buf, 2 * 1500).unwrap();
+    assert_eq!(buf.len(), 2 * 1500);
+    assert_frames(&buf, 0);
+    assert_eq!(decoder.position(), 1500);
+    // Odd lengths stop at the last whole frame
+    let mut buf = vec![0.];
+    decoder.read(&mut buf, 2 * 700 + 2).unwrap();
+    assert_eq!(buf.len(), 2 * 700 + 1);
+    assert_frames(&buf[1..], 1500);
+    let mut buf = Vec::new();
+    decoder.read(&mut buf, usize::MAX).unwrap();
+    assert_eq!(buf.len(), 2 * 3300);
+    assert_frames(&buf, 2200);
+    assert_eq!(decoder.position(), 5500);
+}
+
+#[test]
+fn test_seekable_seek() {
+    let mut decoder = ramp(200_000);
+    let mut buf = Vec::new();
+    decoder.seek(1234).unwrap();
+    decoder.read(&mut buf, 2 * 100).unwrap();
+    assert_frames(&buf, 1234);
+    decoder.seek(1200).unwrap();
+    assert_eq!(decoder.position(), 1200);
+    buf.clear();
+    decoder.read(&mut buf, 2 * 100).unwrap();
+    assert_frames(&buf, 1200);
+    assert_eq!(decoder.blocks.rewinds, 0);
+
+    decoder.seek((150_000 - HISTORY) as u64).unwrap();
+    buf.clear();
+    decoder.read(&mut buf, 2 * 10).unwrap();
+    assert_frames(&buf, 150_000 - HISTORY);
+    // Far ahead it goes on from the index instead of decoding up to there
+    assert_eq!(decoder.blocks.seeks, 1);
+    decoder.seek(150_000).unwrap();
+    buf.clear();
+    decoder.read(&mut buf, 2 * 10).unwrap();
+    assert_frames(&buf, 150_000);
+    assert!(decoder.decoded.len() <= 2 * (HISTORY as usize + 1000));
+    decoder.seek((150_000 - HISTORY) as u64).unwrap();
+    buf.clear();
+    decoder.read(&mut buf, 2 * 10).unwrap();
+    assert_frames(&buf, 150_000 - HISTORY);
+    assert_eq!(decoder.blocks.seeks, 1);
+
+    decoder.seek(3).unwrap();
+    buf.clear();
+    decoder.read(&mut buf, 2 * 10).unwrap();
+    assert_frames(&buf, 3);
+    assert_eq!(decoder.blocks.seeks, 2);
+    assert_eq!(decoder.blocks.rewinds, 0);
+
+    // Without an index it decodes on ahead, and again from the start behind
+    decoder.blocks.index = None;
+    decoder.seek(120_000).unwrap();
+    buf.clear();
+    decoder.read(&mut buf, 2 * 10).unwrap();
+    assert_frames(&buf, 120_000);
+    assert_eq!(decoder.blocks.rewinds, 0);
+    decoder.seek(5).unwrap();
+    buf.clear();
+    decoder.read(&mut buf, 2 * 10).unwrap();
+    assert_frames(&buf, 5);
+    assert_eq!(decoder.blocks.rewinds, 1);
+
+    decoder.seek(300_000).unwrap();
+    buf.clear();
+    decoder.read(&mut buf, 2 * 10).unwrap();
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_wav() {
+    let formats = [
+        (hound::SampleFormat::Int, 16),
+        (hound::SampleFormat::Int, 24),
+        (hound::SampleFormat::Int, 32),
+        (hound::SampleFormat::Float, 32),
+    ];
+    for &(sample_format, bits) in formats.iter() {
+        let path = temp_path(&format!("{:?}{}.wav", sample_format, bits));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: bits,
+            sample_format,
+        };
+        let scale = (1i64 << (bits - 1)) as f32;
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for frame in 0..3000 {
+            for channel in 0..2 {
+                // Multiples of the least significant bit
+                let sample = ramp_sample(frame, channel) * 4. - 6000.;
+                match sample_format {
+                    hound::SampleFormat::Float => writer.write_sample(sample / scale),
+                    hound::SampleFormat::Int => writer.write_sample(sample as i32),
+                }
+                .unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+
+        let mut decoder = open(path.to_str().unwrap()).unwrap();
+        assert_eq!(decoder.channels(), 2);
+        assert_eq!(decoder.sample_rate(), 44100);
+        assert_eq!(decoder.frames(), Some(3000));
+        decoder.seek(2000).unwrap();
+        let mut buf = Vec::new();
+        decoder.read(&mut buf, usize::MAX).unwrap();
+        assert_eq!(decoder.position(), 3000);
+        let buf: Vec<f32> = buf
+            .iter()
+            .map(|sample| (sample * scale + 6000.) / 4.)
+            .collect();
+        assert_frames(&buf, 2000);
+        decoder.seek(4000).unwrap();
+        assert_eq!(decoder.position(), 3000);
+    }
+}
+
+#[test]
+fn test_flac() {
+    for &bits in &[16, 24] {
+        let path = temp_path(&format!("{}.flac", bits));
+        let samples: Vec<i32> = (0..10_000)
+            .flat_map(|frame| {
+                let value = (frame * 7919) % (1 << (bits - 1));
+                vec![value, -value]
+            })
+            .collect();
+        write_flac(&path, 2, 48000, bits, &samples);
+
+        let mut decoder = open(path.to_str().unwrap()).unwrap();
+        assert_eq!(decoder.channels(), 2);
+        assert_eq!(decoder.sample_rate(), 48000);
+        assert_eq!(decoder.frames(), Some(10_000));
+        let scale = (1i64 << (bits - 1)) as f32;
+        let mut check = |first: u32, len: usize| {
+            decoder.seek(first as u64).unwrap();
+            let mut buf = Vec::new();
+            decoder.read(&mut buf, 2 * len).unwrap();
+            assert_eq!(decoder.position(), first as u64 + (buf.len() / 2) as u64);
+            let expected = &samples[2 * first as usize..][..buf.len()];
+            for (sample, expected) in buf.iter().zip(expected) {
+                assert_eq!((sample * scale) as i32, *expected);
+            }
+            return buf.len() / 2;
+        };
+        assert_eq!(check(0, 5000), 5000);
+        assert_eq!(check(4900, 300), 300);
+        assert_eq!(check(9000, 2000), 1000);
+        assert_eq!(check(17, 10), 10);
+    }
+}
+
+#[test]
+fn test_flac_seek_table() {
+    let path = temp_path("long.flac");
+    let frames = 3 * HISTORY as i32;
+    let samples: Vec<i32> = (0..frames).map(|frame| frame % 30_000).collect();
+    write_flac(&path, 1, 48000, 16, &samples);
+    let mut decoder = open(path.to_str().unwrap()).unwrap();
+    for &first in &[2 * HISTORY + 123, 5, HISTORY + 4096, 3 * HISTORY - 10] {
+        decoder.seek(first as u64).unwrap();
+        let mut buf = Vec::new();
+        decoder.read(&mut buf, 100).unwrap();
+        assert_eq!(buf.len(), 100.min(frames as usize - first as usize));
+        for (offset, sample) in buf.iter().enumerate() {
+            let expected = samples[first as usize + offset];
+            assert_eq!((sample * 32768.) as i32, expected, "at {}", first);
+        }
+    }
+}
+
+#[test]
+fn test_vorbis() {
+    let path = temp_path("long.ogg");
+    let frames = 6 * HISTORY;
+    write_vorbis(&path, 2, 48000, (frames / 128) as usize + 1);
+    let path = path.to_str().unwrap();
+    let mut decoder = open(path).unwrap();
+    assert_eq!(decoder.channels(), 2);
+    assert_eq!(decoder.sample_rate(), 48000);
+    let mut samples = Vec::new();
+    decoder.read(&mut samples, usize::MAX).unwrap();
+    assert_eq!(samples.len(), 2 * frames as usize);
+    assert!(samples.iter().any(|&sample| sample != 0.));
+
+    for &first in &[
+        4 * HISTORY + 123,
+        5,
+        HISTORY + 4096,
+        frames - 10,
+        3 * HISTORY,
+    ] {
+        decoder.seek(first as u64).unwrap();
+        let mut buf = Vec::new();
+        decoder.read(&mut buf, 2 * 100).unwrap();
+        assert_eq!(buf.len(), 2 * 100.min(frames - first) as usize);
+        assert_eq!(
+            buf,
+            samples[2 * first as usize..][..buf.len()],
+            "at {}",
+            first
+        );
+    }
+
+    // The page found ends before the frame and not far from it
+    let mut blocks = VorbisBlocks::open(path).unwrap();
+    for &frame in &[5 * HISTORY, 2 * HISTORY + 1000, 100] {
+        let start = blocks.seek(frame).unwrap().unwrap();
+        assert!(
+            start <= frame && start + 20_000 > frame,
+            "{} for {}",
+            start,
+            frame
+        );
+        let mut buf = Vec::new();
+        blocks.next_block(&mut buf).unwrap();
+        let offset = 2 * start as usize;
+        assert_eq!(buf, samples[offset..offset + buf.len()]);
+    }
+}
+
+#[test]
+fn test_open_errors() {
+    assert!(open("/nonexistent/piwfs.wav").is_err());
+    for &(name, contents) in &[
+        ("unknown.bin", &b"ID3\x04data"[..]),
+        ("broken.ogg", b"OggS\x00\x02"),
+    ] {
+        let path = temp_path(name);
+        std::fs::write(&path, contents).unwrap();
+        assert!(open(path.to_str().unwrap()).is_err());
+    }
+}
+
+#[test]
+fn test_channel_map() {
+    let map: ChannelMap = "5,6".parse().unwrap();
+    assert_eq!(map.outputs(), 2);
+    assert_eq!(
+        map.routes[1],
+        Route {
+            input: Input::Channel(5),
+            output: 1,
+            gain: 1.
+        }
+    );
+    assert_eq!(map.to_string(), "5:1,6:2");
+    let map: ChannelMap = "1:2*0.5,2:2*0.5,*:1*2".parse().unwrap();
+    assert_eq!(map.to_string(), "1:2*0.5,2:2*0.5,*:1*2");
+    assert_eq!(
+        map.matrix(4).unwrap(),
+        vec![vec![0.5; 4], vec![0.5, 0.5, 0., 0.]]
+    );
+    assert!(map.matrix(1).is_err());
+    for bad in &["", "0", "1:0", "a:1", "1*x", "1:2:3"] {
+        assert!(bad.parse::<ChannelMap>().is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_mixed() {
+    // Channels swapped, then both of them at half their level
+    let map = "2:1,1:2,1:3*0.5,2:3*0.5".parse().unwrap();
+    let mut decoder = Mixed::new(Box::new(ramp(5000)), &map).unwrap();
+    assert_eq!(decoder.channels(), 3);
+    decoder.seek(1000).unwrap();
+    let mut buf = vec![0.];
+    decoder.read(&mut buf, 3 * 100 + 2).unwrap();
+    assert_eq!(buf.len(), 3 * 100 + 1);
+    assert_eq!(decoder.position(), 1100);
+    for (frame, samples) in buf[1..].chunks(3).enumerate() {
+        let frame = 1000 + frame as u32;
+        let (left, right) = (ramp_sample(frame, 0), ramp_sample(frame, 1));
+        assert_eq!(samples, [right, left, (left + right) / 2.]);
+    }
+    let mut buf = Vec::new();
+    decoder.read(&mut buf, usize::MAX).unwrap();
+    assert_eq!(buf.len(), 3 * 3900);
+    assert!(Mixed::new(Box::new(ramp(10)), &"3".parse().unwrap()).is_err());
+}
+
+#[test]
+fn test_playlist() {
+    // Gapless, then 100 frames of silence before the third item
+    let entries: Vec<(Box<dyn Decoder>, Option<f64>)> = vec![
+        (Box::new(ramp(3000)), None),
+        (Box::new(ramp(2000)), None),
+        (Box::new(ramp(1000)), Some(5100. / 48000.)),
+    ];
+    let mut playlist = Playlist::new(entries).unwrap();
+    assert_eq!(playlist.starts(), [0, 3000, 5100]);
+    assert_eq!(playlist.frames(), Some(6100));
+    playlist.seek(2900).unwrap();
+    let mut buf = Vec::new();
+    playlist.read(&mut buf, 2 * 300).unwrap();
+    assert_frames(&buf[..2 * 100], 2900);
+    assert_frames(&buf[2 * 100..], 0);
+    playlist.seek(4950).unwrap();
+    let mut buf = Vec::new();
+    playlist.read(&mut buf, usize::MAX).unwrap();
+    assert_eq!(buf.len(), 2 * 1150);
+    assert_frames(&buf[..2 * 50], 1950);
+    assert!(buf[2 * 50..2 * 150].iter().all(|&sample| sample == 0.));
+    assert_frames(&buf[2 * 150..], 0);
+    assert_eq!(playlist.position(), 6100);
+
+    let overlapping: Vec<(Box<dyn Decoder>, Option<f64>)> = vec![
+        (Box::new(ramp(3000)), None),
+        (Box::new(ramp(1000)), Some(0.05)),
+    ];
+    assert!(Playlist::new(overlapping).is_err());
+    assert!(Playlist::new(Vec::new()).is_err());
+}
+
+#[test]
+fn test_playlist_file() {
+    let wav = temp_path("item.wav");
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: 48000,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&wav, spec).unwrap();
+    for _ in 0..2 * 4800 {
+        writer.write_sample(1000i16).unwrap();
+    }
+    writer.finalize().unwrap();
+    let path = temp_path("list.m3u");
+    let name = wav.file_name().unwrap().to_str().unwrap();
+    std::fs::write(
+        &path,
+        format!("#EXTM3U\n{0}\n\n#PIWFS-START:0.5\n{0}\n{0}\n", name),
+    )
+    .unwrap();
+    let decoder = open(path.to_str().unwrap()).unwrap();
+    assert_eq!(decoder.channels(), 2);
+    assert_eq!(decoder.frames(), Some(24000 + 2 * 4800));
+
+    std::fs::write(&path, format!("{}\n#PIWFS-START:x\n", name)).unwrap();
+    assert!(open(path.to_str().unwrap()).is_err());
+    std::fs::write(&path, "missing.wav\n").unwrap();
+    assert!(open(path.to_str().unwrap()).is_err());
+}
+
+#[test]
+fn test_looped() {
+    let mut looped = Looped::new(Box::new(ramp(1000))).unwrap();
+    assert_eq!(looped.frames(), None);
+    looped.seek(2900).unwrap();
+    let mut buf = Vec::new();
+    looped.read(&mut buf, 2 * 300).unwrap();
+    assert_eq!(looped.position(), 3200);
+    assert_frames(&buf[..2 * 100], 900);
+    assert_frames(&buf[2 * 100..], 0);
+    assert!(Looped::new(Box::new(ramp(0))).is_err());
+
+    // Goes on past 2^32 frames, 24.8 hours at 48 kHz
+    looped.seek((1 << 32) - 100).unwrap();
+    let mut buf = Vec::new();
+    looped.read(&mut buf, 2 * 300).unwrap();
+    assert_eq!(buf.len(), 2 * 300);
+    assert_eq!(looped.position(), (1 << 32) + 200);
+    assert_frames(&buf, 196);
+    looped.seek(10 << 32).unwrap();
+    let mut buf = Vec::new();
+    looped.read(&mut buf, 2 * 10).unwrap();
+    assert_frames(&buf, ((10u64 << 32) % 1000) as u32);
+}
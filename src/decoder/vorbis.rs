@@ -0,0 +1,111 @@
+use super::Blocks;
+
+use lewton::audio::AudioReadError;
+use lewton::inside_ogg::OggStreamReader;
+use lewton::samples::InterleavedSamples;
+use lewton::VorbisError;
+
+use std::fs::File;
+use std::io::BufReader;
+
+/// Ogg Vorbis files, decoded a packet at a time. The length is not known
+/// before the last page. Seeks jump to a page ending at or before the frame
+/// and go on from the end of it, where the granule position tells the
+/// exact frame.
+pub struct VorbisBlocks {
+    path: String,
+    reader: OggStreamReader<BufReader<File>>,
+}
+
+impl VorbisBlocks {
+    pub fn open(path: &str) -> Result<VorbisBlocks, String> {
+        let file = File::open(path).map_err(|err| format!("Couldn't open {}: {}", path, err))?;
+        let reader = OggStreamReader::new(BufReader::new(file))
+            .map_err(|err| format!("Couldn't open {}: {}", path, err))?;
+        return Ok(VorbisBlocks {
+            path: path.to_string(),
+            reader,
+        });
+    }
+}
+
+/// Frames the first step back of a seek covers, doubling with every further
+/// one
+const FIRST_STEP: u64 = 4096;
+
+impl VorbisBlocks {
+    /// Goes on from a page around granule `target`, returns the frame of
+    /// the end of the page or `None` when it's past the end of the stream
+    /// or the headers.
+    fn seek_page(&mut self, target: u64) -> Result<Option<u64>, String> {
+        self.reader
+            .seek_absgp_pg(target)
+            .map_err(|err| err.to_string())?;
+        // The position is known again after the last packet of a page
+        while self.reader.get_last_absgp().is_none() {
+            let packet = match self
+                .reader
+                .read_dec_packet_generic::<InterleavedSamples<f32>>()
+            {
+                Ok(packet) => packet,
+                Err(VorbisError::BadAudio(AudioReadError::AudioIsHeader)) => return Ok(None),
+                Err(err) => return Err(err.to_string()),
+            };
+            if packet.is_none() {
+                return Ok(None);
+            }
+        }
+        return Ok(self.reader.get_last_absgp());
+    }
+}
+
+impl Blocks for VorbisBlocks {
+    fn channels(&self) -> usize {
+        return self.reader.ident_hdr.audio_channels as usize;
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return self.reader.ident_hdr.audio_sample_rate;
+    }
+
+    fn frames(&self) -> Option<u32> {
+        return None;
+    }
+
+    fn next_block(&mut self, buf: &mut Vec<f32>) -> Result<bool, String> {
+        return match self
+            .reader
+            .read_dec_packet_generic::<InterleavedSamples<f32>>()
+        {
+            Ok(Some(packet)) => {
+                buf.extend(packet.samples);
+                Ok(true)
+            }
+            Ok(None) => Ok(false),
+            Err(err) => Err(err.to_string()),
+        };
+    }
+
+    fn rewind(&mut self) -> Result<(), String> {
+        *self = VorbisBlocks::open(&self.path)?;
+        return Ok(());
+    }
+
+    fn seek(&mut self, frame: u32) -> Result<Option<u32>, String> {
+        // The page found usually ends after the granule asked for, so the
+        // target steps back further until a page ends at or before `frame`
+        let mut target = frame as u64;
+        let mut step = FIRST_STEP;
+        while target > 0 {
+            match self.seek_page(target)? {
+                Some(absgp) if absgp <= frame as u64 => return Ok(Some(absgp as u32)),
+                _ => (),
+            }
+            target = target.saturating_sub(step);
+            step *= 2;
+        }
+        // Within the first page
+        self.rewind()?;
+        return Ok(Some(0));
+    }
+}
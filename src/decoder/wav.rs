@@ -0,0 +1,68 @@
+use super::Decoder;
+
+use hound::{SampleFormat, WavReader};
+
+use std::fs::File;
+use std::io::BufReader;
+
+/// 16, 24 and 32 bit integer or 32 bit float WAV files.
+pub struct WavDecoder {
+    reader: WavReader<BufReader<File>>,
+    /// Samples read or skipped, hound does not expose its position
+    samples: u32,
+}
+
+impl WavDecoder {
+    pub fn open(path: &str) -> Result<WavDecoder, String> {
+        let reader =
+            WavReader::open(path).map_err(|err| format!("Couldn't open {}: {}", path, err))?;
+        return Ok(WavDecoder { reader, samples: 0 });
+    }
+}
+
+impl Decoder for WavDecoder {
+    fn channels(&self) -> usize {
+        return self.reader.spec().channels as usize;
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return self.reader.spec().sample_rate;
+    }
+
+    fn frames(&self) -> Option<u64> {
+        return Some(self.reader.duration() as u64);
+    }
+
+    fn position(&self) -> u64 {
+        return (self.samples / self.channels() as u32) as u64;
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        let frame = frame.min(self.reader.duration() as u64) as u32;
+        self.reader.seek(frame).map_err(|err| err.to_string())?;
+        self.samples = frame * self.channels() as u32;
+        return Ok(());
+    }
+
+    fn read(&mut self, buf: &mut Vec<f32>, len: usize) -> Result<(), String> {
+        let count = len.saturating_sub(buf.len());
+        let before = buf.len();
+        let spec = self.reader.spec();
+        let result = match spec.sample_format {
+            SampleFormat::Float => self
+                .reader
+                .samples::<f32>()
+                .take(count)
+                .try_for_each(|sample| sample.map(|sample| buf.push(sample))),
+            SampleFormat::Int => {
+                let scale = 1. / (1i64 << (spec.bits_per_sample - 1)) as f32;
+                self.reader
+                    .samples::<i32>()
+                    .take(count)
+                    .try_for_each(|sample| sample.map(|sample| buf.push(sample as f32 * scale)))
+            }
+        };
+        self.samples += (buf.len() - before) as u32;
+        return result.map_err(|err| err.to_string());
+    }
+}
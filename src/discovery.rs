@@ -0,0 +1,208 @@
+//! Slaves in listen mode periodically send an `ANNOUNCE` message to a
+//! multicast group, the master collects them into a roster of slaves it can
+//! send sessions to. An announcement carries `hostname`, `device`,
+//! `channels`, `rate`, `version` and the control `port`, the control address
+//! is the source address of the datagram combined with that port.
+
+#[cfg(test)]
+mod tests;
+
+use crate::protocol::{self, Message};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use clap::ArgMatches;
+
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 74, 57);
+pub const PORT: u16 = 7458;
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Parses the `--discovery-group` option shared by master and slave.
+pub fn group_from_args(args: &ArgMatches) -> SocketAddrV4 {
+    return match args.value_of("discovery-group") {
+        Some(group) => group
+            .parse()
+            .expect("[ERR] Couldn't parse discovery group as IP:PORT"),
+        None => SocketAddrV4::new(GROUP, PORT),
+    };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announcement {
+    pub hostname: String,
+    pub device: String,
+    pub channels: u32,
+    pub rate: u32,
+    pub version: String,
+    /// Port the slave accepts control messages on
+    pub port: u16,
+}
+
+impl Announcement {
+    pub fn to_message(&self) -> Message {
+        return Message::new("ANNOUNCE")
+            .with("hostname", &self.hostname)
+            .with("device", &self.device)
+            .with("channels", self.channels)
+            .with("rate", self.rate)
+            .with("version", &self.version)
+            .with("port", self.port);
+    }
+
+    pub fn from_message(msg: &Message) -> Result<Announcement, String> {
+        if msg.command != "ANNOUNCE" {
+            return Err(format!("Unexpected {}", msg.command));
+        }
+        let field = |key: &str| {
+            msg.get(key)
+                .map(String::from)
+                .ok_or_else(|| format!("Missing {}", key))
+        };
+        return Ok(Announcement {
+            hostname: field("hostname")?,
+            device: field("device")?,
+            channels: msg.parse("channels")?.unwrap_or(0),
+            rate: msg.parse("rate")?.unwrap_or(0),
+            version: field("version")?,
+            port: msg
+                .parse("port")?
+                .ok_or_else(|| "Missing port".to_string())?,
+        });
+    }
+}
+
+/// Sends `announcement` to `group` every `interval` until `stop` is set.
+pub fn spawn_announcer(
+    announcement: Announcement,
+    group: SocketAddrV4,
+    interval: Duration,
+    stop: Arc<AtomicBool>,
+) -> io::Result<JoinHandle<()>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_ttl_v4(1)?;
+    socket.set_multicast_loop_v4(true)?;
+    let mut datagram = Vec::new();
+    announcement.to_message().write_to(&mut datagram)?;
+    return Ok(thread::spawn(move || {
+        let mut warned = false;
+        while !stop.load(Ordering::Relaxed) {
+            if let Err(err) = socket.send_to(&datagram, group) {
+                if !warned {
+                    println!("[WRN] Couldn't announce on {}: {}", group, err);
+                    warned = true;
+                }
+            }
+            thread::sleep(interval);
+        }
+    }));
+}
+
+/// Slaves heard from recently, keyed by their control address.
+pub struct Roster {
+    timeout: Duration,
+    slaves: HashMap<SocketAddr, (Announcement, Instant)>,
+}
+
+impl Roster {
+    pub fn new(timeout: Duration) -> Roster {
+        return Roster {
+            timeout,
+            slaves: HashMap::new(),
+        };
+    }
+
+    /// Returns true if the slave was not in the roster before.
+    pub fn update(&mut self, addr: SocketAddr, announcement: Announcement, now: Instant) -> bool {
+        return self.slaves.insert(addr, (announcement, now)).is_none();
+    }
+
+    /// Removes and returns slaves that were not heard from within the timeout.
+    pub fn expire(&mut self, now: Instant) -> Vec<(SocketAddr, Announcement)> {
+        let timeout = self.timeout;
+        let expired: Vec<SocketAddr> = self
+            .slaves
+            .iter()
+            .filter(|(_, (_, seen))| now.saturating_duration_since(*seen) > timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+        return expired
+            .into_iter()
+            .map(|addr| (addr, self.slaves.remove(&addr).unwrap().0))
+            .collect();
+    }
+
+    /// Current slaves ordered by hostname.
+    pub fn slaves(&self) -> Vec<(SocketAddr, Announcement)> {
+        let mut slaves: Vec<_> = self
+            .slaves
+            .iter()
+            .map(|(addr, (announcement, _))| (*addr, announcement.clone()))
+            .collect();
+        slaves.sort_by(|a, b| (&a.1.hostname, a.0).cmp(&(&b.1.hostname, b.0)));
+        return slaves;
+    }
+}
+
+/// Live roster fed by a background thread listening on the multicast group.
+pub struct Discovery {
+    roster: Arc<Mutex<Roster>>,
+}
+
+impl Discovery {
+    pub fn listen(
+        group: SocketAddrV4,
+        iface: Ipv4Addr,
+        timeout: Duration,
+    ) -> io::Result<Discovery> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port()))?;
+        socket.join_multicast_v4(group.ip(), &iface)?;
+        let roster = Arc::new(Mutex::new(Roster::new(timeout)));
+        let thread_roster = Arc::clone(&roster);
+        thread::spawn(move || {
+            let mut buf = [0; 65536];
+            let mut warned = false;
+            loop {
+                let received =
+                    protocol::recv_from(&socket, &mut buf, "Discovery receive", &mut warned);
+                let (len, peer) = match received {
+                    Some(res) => res,
+                    None => continue,
+                };
+                let announcement = match Message::read_from(&mut &buf[..len]) {
+                    Ok(Some(msg)) => Announcement::from_message(&msg),
+                    Ok(None) => continue,
+                    Err(err) => Err(err.to_string()),
+                };
+                match announcement {
+                    Ok(announcement) => {
+                        let addr = SocketAddr::new(peer.ip(), announcement.port);
+                        let hostname = announcement.hostname.clone();
+                        if thread_roster
+                            .lock()
+                            .unwrap()
+                            .update(addr, announcement, Instant::now())
+                        {
+                            println!("[INF] Discovered {} ({})", hostname, addr);
+                        }
+                    }
+                    Err(err) => println!("[WRN] Bad announcement from {}: {}", peer, err),
+                }
+            }
+        });
+        return Ok(Discovery { roster });
+    }
+
+    pub fn roster(&self) -> Vec<(SocketAddr, Announcement)> {
+        let mut roster = self.roster.lock().unwrap();
+        for (addr, announcement) in roster.expire(Instant::now()) {
+            println!("[WRN] Lost {} ({})", announcement.hostname, addr);
+        }
+        return roster.slaves();
+    }
+}
@@ -0,0 +1,81 @@
+use super::*;
+
+fn announcement(hostname: &str, port: u16) -> Announcement {
+    return Announcement {
+        hostname: hostname.to_string(),
+        device: "hw:0".to_string(),
+        channels: 2,
+        rate: 48000,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        port,
+    };
+}
+
+#[test]
+fn test_message_roundtrip() {
+    let ann = announcement("pi01", 7457);
+    assert_eq!(Announcement::from_message(&ann.to_message()), Ok(ann));
+    assert!(Announcement::from_message(&Message::new("ANNOUNCE").with("hostname", "x")).is_err());
+    assert!(Announcement::from_message(&Message::new("STATUS")).is_err());
+}
+
+#[test]
+fn test_roster_expiry() {
+    let start = Instant::now();
+    let mut roster = Roster::new(Duration::from_secs(3));
+    let addr_a: SocketAddr = "10.0.0.2:7457".parse().unwrap();
+    let addr_b: SocketAddr = "10.0.0.1:7457".parse().unwrap();
+    assert!(roster.update(addr_a, announcement("pi02", 7457), start));
+    assert!(roster.update(addr_b, announcement("pi01", 7457), start));
+    assert!(!roster.update(
+        addr_a,
+        announcement("pi02", 7457),
+        start + Duration::from_secs(2)
+    ));
+    let hosts: Vec<_> = roster
+        .slaves()
+        .into_iter()
+        .map(|(_, a)| a.hostname)
+        .collect();
+    assert_eq!(hosts, ["pi01", "pi02"]);
+
+    assert!(roster.expire(start + Duration::from_secs(3)).is_empty());
+    let expired = roster.expire(start + Duration::from_secs(4));
+    assert_eq!(expired, [(addr_b, announcement("pi01", 7457))]);
+    assert_eq!(roster.slaves(), [(addr_a, announcement("pi02", 7457))]);
+    assert_eq!(roster.expire(start + Duration::from_secs(6)).len(), 1);
+    assert!(roster.slaves().is_empty());
+}
+
+#[test]
+fn test_multicast_loopback() {
+    let group = SocketAddrV4::new(GROUP, PORT + 1000);
+    let interval = Duration::from_millis(20);
+    let discovery = Discovery::listen(group, Ipv4Addr::UNSPECIFIED, interval * 5).unwrap();
+    let stops: Vec<_> = (0..4).map(|_| Arc::new(AtomicBool::new(false))).collect();
+    for (idx, stop) in stops.iter().enumerate() {
+        let ann = announcement(&format!("pi{:02}", idx), 8000 + idx as u16);
+        spawn_announcer(ann, group, interval, Arc::clone(stop)).unwrap();
+    }
+    let wait_for = |count: usize| {
+        for _ in 0..200 {
+            if discovery.roster().len() == count {
+                break;
+            }
+            thread::sleep(interval);
+        }
+        return discovery.roster();
+    };
+    let roster = wait_for(4);
+    let ports: Vec<_> = roster.iter().map(|(addr, _)| addr.port()).collect();
+    assert_eq!(ports, [8000, 8001, 8002, 8003]);
+    assert!(roster.iter().all(|(addr, ann)| addr.port() == ann.port));
+
+    stops[1].store(true, Ordering::Relaxed);
+    let roster = wait_for(3);
+    let hosts: Vec<_> = roster.into_iter().map(|(_, a)| a.hostname).collect();
+    assert_eq!(hosts, ["pi00", "pi02", "pi03"]);
+    for stop in stops {
+        stop.store(true, Ordering::Relaxed);
+    }
+}
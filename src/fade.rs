@@ -0,0 +1,132 @@
+//! Gain changes without clicks. A `Curve` shapes every fade of the player,
+//! at the start, when stopping, around timeline commands and at loop
+//! points. A `Ramp` moves the gain of a slave to a new level over a while,
+//! set at runtime through its shared `Level`.
+
+#[cfg(test)]
+mod tests;
+
+use std::str::FromStr;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// Keeps the power of two signals crossfaded with it constant
+    EqualPower,
+    /// Half a period of a cosine, smooth at both ends
+    RaisedCosine,
+}
+
+impl Curve {
+    /// Gain of a fade-in `x` of the way through, a fade-out is the same
+    /// backwards.
+    pub fn gain(self, x: f64) -> f32 {
+        let x = x.clamp(0., 1.);
+        return match self {
+            Curve::Linear => x,
+            Curve::EqualPower => (x * std::f64::consts::FRAC_PI_2).sin(),
+            Curve::RaisedCosine => (1. - (x * std::f64::consts::PI).cos()) / 2.,
+        } as f32;
+    }
+}
+
+impl FromStr for Curve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Curve, String> {
+        return match s {
+            "linear" => Ok(Curve::Linear),
+            "equal-power" => Ok(Curve::EqualPower),
+            "raised-cosine" => Ok(Curve::RaisedCosine),
+            _ => Err(format!(
+                "Unknown fade curve {}, expected linear, equal-power or raised-cosine",
+                s
+            )),
+        };
+    }
+}
+
+/// Gain going from one value to another over a number of frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ramp {
+    from: f32,
+    to: f32,
+    frames: usize,
+    done: usize,
+    curve: Curve,
+}
+
+impl Ramp {
+    pub fn new(from: f32, to: f32, frames: usize, curve: Curve) -> Ramp {
+        return Ramp {
+            from,
+            to,
+            frames,
+            done: 0,
+            curve,
+        };
+    }
+
+    /// Stays at `gain`
+    pub fn constant(gain: f32) -> Ramp {
+        return Ramp::new(gain, gain, 0, Curve::Linear);
+    }
+
+    /// Gain of the next frame
+    pub fn step(&mut self) -> f32 {
+        if self.done >= self.frames {
+            return self.to;
+        }
+        self.done += 1;
+        return self.value();
+    }
+
+    /// Gain of the last frame
+    pub fn value(&self) -> f32 {
+        if self.done >= self.frames {
+            return self.to;
+        }
+        let x = self.done as f64 / self.frames as f64;
+        return self.from + (self.to - self.from) * self.curve.gain(x);
+    }
+
+    pub fn target(&self) -> f32 {
+        return self.to;
+    }
+
+    pub fn is_done(&self) -> bool {
+        return self.done >= self.frames;
+    }
+}
+
+/// Output level of a slave in dB, shared with whoever changes it while it
+/// plays. The player ramps to a new level over the given time.
+#[derive(Debug, Default)]
+pub struct Level {
+    /// Level in dB and seconds to ramp to it
+    target: Mutex<(f64, f64)>,
+}
+
+impl Level {
+    pub fn new(db: f64) -> Level {
+        return Level {
+            target: Mutex::new((db, 0.)),
+        };
+    }
+
+    pub fn set(&self, db: f64, ramp: f64) {
+        *self.target.lock().unwrap() = (db, ramp);
+    }
+
+    /// Level in dB and the time to ramp to it in seconds
+    pub fn get(&self) -> (f64, f64) {
+        return *self.target.lock().unwrap();
+    }
+}
+
+/// Gain factor of `db`
+pub fn gain(db: f64) -> f32 {
+    return 10f64.powf(db / 20.) as f32;
+}
@@ -0,0 +1,44 @@
+use super::*;
+
+#[test]
+fn test_curves() {
+    for &curve in &[Curve::Linear, Curve::EqualPower, Curve::RaisedCosine] {
+        assert_eq!(curve.gain(0.), 0.);
+        assert!((curve.gain(1.) - 1.).abs() < 1e-6);
+        assert_eq!(curve.gain(-1.), 0.);
+        assert!((curve.gain(2.) - 1.).abs() < 1e-6);
+        let mut last = 0.;
+        for step in 1..=100 {
+            let gain = curve.gain(step as f64 / 100.);
+            assert!(gain >= last, "{:?} falls", curve);
+            last = gain;
+        }
+    }
+    assert_eq!(Curve::Linear.gain(0.25), 0.25);
+    // Crossfading with equal power keeps the power
+    let x = 0.3;
+    let power = Curve::EqualPower.gain(x).powi(2) + Curve::EqualPower.gain(1. - x).powi(2);
+    assert!((power - 1.).abs() < 1e-6);
+    assert!((Curve::RaisedCosine.gain(0.5) - 0.5).abs() < 1e-6);
+    assert_eq!("equal-power".parse(), Ok(Curve::EqualPower));
+    assert!("cubic".parse::<Curve>().is_err());
+}
+
+#[test]
+fn test_ramp() {
+    let mut ramp = Ramp::new(1., 0.5, 4, Curve::Linear);
+    assert_eq!(ramp.value(), 1.);
+    let gains: Vec<f32> = (0..6).map(|_| ramp.step()).collect();
+    assert_eq!(gains, [0.875, 0.75, 0.625, 0.5, 0.5, 0.5]);
+    assert!(ramp.is_done());
+    assert_eq!(ramp.target(), 0.5);
+    let mut constant = Ramp::constant(0.25);
+    assert_eq!(constant.step(), 0.25);
+
+    let level = Level::new(-6.);
+    assert_eq!(level.get(), (-6., 0.));
+    level.set(3., 0.5);
+    assert_eq!(level.get(), (3., 0.5));
+    assert!((gain(-20.) - 0.1).abs() < 1e-6);
+    assert_eq!(gain(0.), 1.);
+}
@@ -0,0 +1,273 @@
+//! Description of the whole loudspeaker array, shared by every slave. The
+//! file is TOML with one `[[speaker]]` table per loudspeaker:
+//!
+//! ```toml
+//! linear = true       # optional, all loudspeakers have to lie on a line
+//! alias-freq = 1500   # optional, derived from the spacing otherwise
+//!
+//! [[speaker]]
+//! id = "front-1"
+//! position = [-0.5, 2.0]  # metres
+//! angle = -90             # direction the loudspeaker faces in degrees
+//! host = "pi1"
+//! device = "hw:0"         # optional
+//! channel = 0
+//! ```
+
+#[cfg(test)]
+mod tests;
+
+use crate::decoder::ChannelMap;
+use crate::wfs::{Loudspeaker, Vec2, SPEED_OF_SOUND};
+
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+/// Distance in metres a loudspeaker of a linear array may be off the line.
+pub const LINE_TOLERANCE: f64 = 0.01;
+
+fn default_device() -> String {
+    return "hw:0".to_string();
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Speaker {
+    pub id: String,
+    pub position: [f64; 2],
+    pub angle: f64,
+    pub host: String,
+    #[serde(default = "default_device")]
+    pub device: String,
+    pub channel: usize,
+}
+
+impl Speaker {
+    pub fn loudspeaker(&self) -> Loudspeaker {
+        return Loudspeaker {
+            position: Vec2::new(self.position[0], self.position[1]),
+            normal: Vec2::from_angle(self.angle),
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Geometry {
+    #[serde(default)]
+    pub linear: bool,
+    pub alias_freq: Option<f64>,
+    #[serde(rename = "speaker", default)]
+    pub speakers: Vec<Speaker>,
+}
+
+/// Part of the array driven by a single slave.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub device: String,
+    /// Loudspeaker of every output channel, `None` for unused channels
+    pub speakers: Vec<Option<Loudspeaker>>,
+    pub alias_freq: Option<f64>,
+    /// File channels played on the output channels, all of them in order
+    /// when `None`
+    pub channels: Option<ChannelMap>,
+}
+
+impl Geometry {
+    /// Parses and validates a geometry file, see `warnings` for problems
+    /// that do not make the geometry unusable.
+    pub fn parse(text: &str) -> Result<Geometry, String> {
+        let geometry: Geometry = toml::from_str(text).map_err(|err| err.to_string())?;
+        geometry.validate()?;
+        return Ok(geometry);
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.speakers.is_empty() {
+            return Err("No speakers defined".to_string());
+        }
+        let mut ids = HashSet::new();
+        let mut outputs = HashSet::new();
+        for speaker in &self.speakers {
+            if !ids.insert(&speaker.id) {
+                return Err(format!("Duplicate speaker id {}", speaker.id));
+            }
+            if !speaker.position.iter().all(|x| x.is_finite()) || !speaker.angle.is_finite() {
+                return Err(format!(
+                    "Speaker {} has to have a finite position and angle",
+                    speaker.id
+                ));
+            }
+            if !outputs.insert((&speaker.host, &speaker.device, speaker.channel)) {
+                return Err(format!(
+                    "Speaker {} uses channel {} of {} on {} again",
+                    speaker.id, speaker.channel, speaker.device, speaker.host
+                ));
+            }
+        }
+        if let Some(freq) = self.alias_freq {
+            if !(freq > 0. && freq.is_finite()) {
+                return Err("Aliasing frequency has to be positive".to_string());
+            }
+        }
+        if self.linear {
+            if let Some((id, distance)) = self.farthest_from_line() {
+                if distance > LINE_TOLERANCE {
+                    return Err(format!(
+                        "Speaker {} is {:.3} m off the line of the array",
+                        id, distance
+                    ));
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn positions(&self) -> Vec<Vec2> {
+        return self
+            .speakers
+            .iter()
+            .map(|speaker| speaker.loudspeaker().position)
+            .collect();
+    }
+
+    /// Speaker farthest from the least squares line through all speakers.
+    fn farthest_from_line(&self) -> Option<(&str, f64)> {
+        let positions = self.positions();
+        let center = positions
+            .iter()
+            .fold(Vec2::default(), |acc, pos| acc + *pos)
+            * (1. / positions.len() as f64);
+        let (sxx, syy, sxy) = positions.iter().fold((0., 0., 0.), |(sxx, syy, sxy), pos| {
+            let d = *pos - center;
+            (sxx + d.x * d.x, syy + d.y * d.y, sxy + d.x * d.y)
+        });
+        let direction = Vec2::from_angle((2. * sxy).atan2(sxx - syy).to_degrees() / 2.);
+        let normal = Vec2::new(-direction.y, direction.x);
+        return self
+            .speakers
+            .iter()
+            .zip(positions)
+            .map(|(speaker, pos)| (speaker.id.as_str(), (pos - center).dot(normal).abs()))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+    }
+
+    /// Distance of every speaker to its nearest neighbour.
+    fn neighbour_distances(&self) -> Vec<f64> {
+        let positions = self.positions();
+        return positions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, a)| {
+                positions
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, b)| (*a - *b).norm())
+                    .min_by(|x, y| x.total_cmp(y))
+            })
+            .collect();
+    }
+
+    /// Largest distance between a loudspeaker and its nearest neighbour.
+    pub fn spacing(&self) -> Option<f64> {
+        return self
+            .neighbour_distances()
+            .into_iter()
+            .max_by(|x, y| x.total_cmp(y));
+    }
+
+    /// Frequency above which the array aliases, `c / (2 * spacing)`.
+    pub fn spatial_alias_freq(&self) -> Option<f64> {
+        return self
+            .spacing()
+            .map(|spacing| SPEED_OF_SOUND / (2. * spacing));
+    }
+
+    /// Aliasing frequency the pre-equalization should use.
+    pub fn alias_freq(&self) -> Option<f64> {
+        return self.alias_freq.or_else(|| self.spatial_alias_freq());
+    }
+
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let (Some(given), Some(derived)) = (self.alias_freq, self.spatial_alias_freq()) {
+            if given > derived {
+                warnings.push(format!(
+                    "Aliasing frequency {:.0} Hz is above {:.0} Hz allowed by the {:.3} m spacing",
+                    given,
+                    derived,
+                    self.spacing().unwrap()
+                ));
+            }
+        }
+        if self.neighbour_distances().contains(&0.) {
+            warnings.push("Some speakers share the same position".to_string());
+        }
+        return warnings;
+    }
+
+    /// Hosts driving the array in order of appearance.
+    pub fn hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = Vec::new();
+        for speaker in &self.speakers {
+            if !hosts.contains(&speaker.host) {
+                hosts.push(speaker.host.clone());
+            }
+        }
+        return hosts;
+    }
+
+    /// Picks the speakers with the given ids, or all speakers of `host`
+    /// when there are none.
+    pub fn select(&self, ids: &[String], host: &str) -> Result<Layout, String> {
+        let selected: Vec<&Speaker> = if ids.is_empty() {
+            self.speakers
+                .iter()
+                .filter(|speaker| speaker.host == host)
+                .collect()
+        } else {
+            ids.iter()
+                .map(|id| {
+                    self.speakers
+                        .iter()
+                        .find(|speaker| &speaker.id == id)
+                        .ok_or_else(|| format!("No speaker with id {}", id))
+                })
+                .collect::<Result<_, _>>()?
+        };
+        let first = match selected.first() {
+            Some(first) => *first,
+            None => return Err(format!("No speakers for host {}", host)),
+        };
+        if let Some(other) = selected
+            .iter()
+            .find(|speaker| speaker.device != first.device)
+        {
+            return Err(format!(
+                "Speakers {} and {} use different devices",
+                first.id, other.id
+            ));
+        }
+        let channels = selected
+            .iter()
+            .map(|speaker| speaker.channel)
+            .max()
+            .unwrap()
+            + 1;
+        let mut speakers = vec![None; channels];
+        for speaker in &selected {
+            if speakers[speaker.channel].is_some() {
+                return Err(format!("Channel {} selected twice", speaker.channel));
+            }
+            speakers[speaker.channel] = Some(speaker.loudspeaker());
+        }
+        return Ok(Layout {
+            device: first.device.clone(),
+            speakers,
+            alias_freq: self.alias_freq(),
+            channels: None,
+        });
+    }
+}
@@ -0,0 +1,117 @@
+use super::*;
+
+const EPS: f64 = 1e-9;
+
+fn speaker(id: &str, x: f64, y: f64, host: &str, channel: usize) -> String {
+    return format!(
+        "[[speaker]]\nid = \"{}\"\nposition = [{}, {}]\nangle = 90\nhost = \"{}\"\nchannel = {}\n",
+        id, x, y, host, channel
+    );
+}
+
+/// Four loudspeakers 0.2 m apart on the x axis, two per host.
+fn array(header: &str) -> String {
+    return header.to_string()
+        + &speaker("a", -0.3, 0., "pi1", 0)
+        + &speaker("b", -0.1, 0., "pi1", 1)
+        + &speaker("c", 0.1, 0., "pi2", 1)
+        + &speaker("d", 0.3, 0., "pi2", 3);
+}
+
+#[test]
+fn test_parse() {
+    let geometry = Geometry::parse(&array("linear = true\n")).unwrap();
+    assert!(geometry.linear);
+    assert_eq!(geometry.speakers.len(), 4);
+    assert_eq!(geometry.speakers[0].device, "hw:0");
+    assert_eq!(geometry.hosts(), vec!["pi1", "pi2"]);
+    let sp = geometry.speakers[2].loudspeaker();
+    assert!((sp.position - Vec2::new(0.1, 0.)).norm() < EPS);
+    assert!((sp.normal - Vec2::new(0., 1.)).norm() < EPS);
+
+    assert!(Geometry::parse("").is_err());
+    assert!(Geometry::parse(&array("colour = \"red\"\n")).is_err());
+    assert!(Geometry::parse(&array("alias-freq = -1\n")).is_err());
+    assert!(Geometry::parse(&array("alias-freq = nan\n")).is_err());
+}
+
+#[test]
+fn test_non_finite() {
+    for (x, y) in &[("nan", "0"), ("0", "inf"), ("-inf", "nan")] {
+        let text = array("")
+            + &speaker("e", 0., 0., "pi3", 0).replace("[0, 0]", &format!("[{}, {}]", x, y));
+        let err = Geometry::parse(&text).unwrap_err();
+        assert!(err.contains("Speaker e"), "{}", err);
+    }
+    let text = array("") + &speaker("e", 0.5, 0., "pi3", 0).replace("angle = 90", "angle = nan");
+    assert!(Geometry::parse(&text).is_err());
+    // Received over the network the same way
+    let msg = crate::protocol::Message::new("SESSION")
+        .with("startat", 1)
+        .with("testfile", "a.wav")
+        .with("geometry", text);
+    assert!(crate::session::Session::from_message(&msg).is_err());
+}
+
+#[test]
+fn test_duplicates() {
+    let duplicate_id = array("") + &speaker("a", 0.5, 0., "pi3", 0);
+    assert!(Geometry::parse(&duplicate_id).unwrap_err().contains("id a"));
+    let duplicate_output = array("") + &speaker("e", 0.5, 0., "pi1", 1);
+    assert!(Geometry::parse(&duplicate_output)
+        .unwrap_err()
+        .contains("channel 1"));
+    let other_device = array("")
+        + &speaker("e", 0.5, 0., "pi1", 1).replace("channel", "device = \"hw:1\"\nchannel");
+    assert!(Geometry::parse(&other_device).is_ok());
+}
+
+#[test]
+fn test_collinearity() {
+    let bent = array("linear = true\n") + &speaker("e", 0.5, 0.1, "pi3", 0);
+    assert!(Geometry::parse(&bent).unwrap_err().contains("off the line"));
+    let nearly = array("linear = true\n") + &speaker("e", 0.5, 0.005, "pi3", 0);
+    assert!(Geometry::parse(&nearly).is_ok());
+    let unconstrained = array("") + &speaker("e", 0.5, 0.1, "pi3", 0);
+    assert!(Geometry::parse(&unconstrained).is_ok());
+}
+
+#[test]
+fn test_spacing() {
+    let geometry = Geometry::parse(&array("")).unwrap();
+    assert!((geometry.spacing().unwrap() - 0.2).abs() < EPS);
+    let derived = SPEED_OF_SOUND / 0.4;
+    assert!((geometry.alias_freq().unwrap() - derived).abs() < EPS);
+    assert!(geometry.warnings().is_empty());
+
+    let geometry = Geometry::parse(&array("alias-freq = 500\n")).unwrap();
+    assert_eq!(geometry.alias_freq(), Some(500.));
+    assert!(geometry.warnings().is_empty());
+
+    let geometry = Geometry::parse(&array("alias-freq = 2000\n")).unwrap();
+    assert_eq!(geometry.alias_freq(), Some(2000.));
+    assert_eq!(geometry.warnings().len(), 1);
+
+    let stacked = array("") + &speaker("e", 0.3, 0., "pi3", 0);
+    assert_eq!(Geometry::parse(&stacked).unwrap().warnings().len(), 1);
+}
+
+#[test]
+fn test_select() {
+    let geometry = Geometry::parse(&array("")).unwrap();
+    let layout = geometry.select(&[], "pi2").unwrap();
+    assert_eq!(layout.device, "hw:0");
+    assert_eq!(layout.speakers.len(), 4);
+    assert!(layout.speakers[0].is_none() && layout.speakers[2].is_none());
+    assert!((layout.speakers[3].unwrap().position - Vec2::new(0.3, 0.)).norm() < EPS);
+
+    let layout = geometry.select(&["b".to_string()], "pi2").unwrap();
+    assert_eq!(layout.speakers.len(), 2);
+    assert!(layout.speakers[0].is_none() && layout.speakers[1].is_some());
+
+    assert!(geometry.select(&[], "pi3").is_err());
+    assert!(geometry.select(&["x".to_string()], "pi1").is_err());
+    assert!(geometry
+        .select(&["b".to_string(), "c".to_string()], "pi1")
+        .is_err());
+}
@@ -0,0 +1,23 @@
+//! Wave Field Synthesis on a network of Raspberry Pis. A master hands out
+//! sessions, every slave plays its share of the loudspeakers in sync with
+//! the others. `player::SyncedPlayer` is the synchronization loop, usable
+//! without the `piwfs` binary.
+
+#![allow(clippy::needless_return)]
+
+pub mod clock;
+pub mod daemon;
+pub mod decoder;
+pub mod discovery;
+pub mod fade;
+pub mod geometry;
+pub mod ntp;
+pub mod player;
+pub mod protocol;
+pub mod ptp;
+pub mod resampler;
+pub mod servo;
+pub mod session;
+pub mod sink;
+pub mod timeline;
+pub mod wfs;
@@ -1,8 +1,83 @@
+#![allow(clippy::needless_return)]
+
 use clap::{App, Arg, SubCommand};
 
 mod master;
 mod slave;
 
+fn session_args(testfile: Arg<'static, 'static>) -> Vec<Arg<'static, 'static>> {
+    return vec![
+        testfile
+            .short("t")
+            .long("testfile")
+            .value_name("PATH")
+            .help("Sets path to file or .m3u playlist to play")
+            .takes_value(true),
+        Arg::with_name("desync-avg")
+            .long("desync-avg")
+            .short("a")
+            .value_name("AVG_SIZE")
+            .help("Sets length of moving average for desync calculation")
+            .takes_value(true),
+        Arg::with_name("estimation-avg")
+            .long("estimation-avg")
+            .value_name("AVG_SIZE")
+            .help("Sets length of moving average for sample length estimation")
+            .takes_value(true),
+        Arg::with_name("quality")
+            .short("q")
+            .long("quality")
+            .value_name("PRESET")
+            .help("Sets resampler quality, fast, medium, high or best [default: medium]")
+            .takes_value(true),
+        Arg::with_name("source")
+            .long("source")
+            .value_name("SOURCE")
+            .help("Renders the mono file as a virtual source, point:X,Y or plane:ANGLE")
+            .takes_value(true),
+        Arg::with_name("trajectory")
+            .long("trajectory")
+            .value_name("T:X,Y;...")
+            .help("Renders the mono file as a point source moving through keyframes")
+            .conflicts_with("source")
+            .takes_value(true),
+        Arg::with_name("reference")
+            .long("reference")
+            .value_name("X,Y")
+            .help("Sets the point where the rendered amplitude is correct [default: 0,0]")
+            .takes_value(true),
+        Arg::with_name("predelay")
+            .long("predelay")
+            .value_name("MILLISECONDS")
+            .help("Sets rendering delay common to all loudspeakers [default: 50]")
+            .takes_value(true),
+        Arg::with_name("geometry")
+            .long("geometry")
+            .value_name("PATH")
+            .help("Sets loudspeaker array geometry file")
+            .takes_value(true),
+        Arg::with_name("loop")
+            .long("loop")
+            .help("Plays the file or playlist over and over"),
+    ];
+}
+
+fn discovery_group_arg() -> Arg<'static, 'static> {
+    return Arg::with_name("discovery-group")
+        .long("discovery-group")
+        .value_name("GROUP:PORT")
+        .help("Sets multicast group used for slave announcements [default: 239.255.74.57:7458]")
+        .takes_value(true);
+}
+
+fn ptp_domain_arg() -> Arg<'static, 'static> {
+    return Arg::with_name("ptp-domain")
+        .long("ptp-domain")
+        .value_name("DOMAIN")
+        .help("Sets PTP domain number [default: 0]")
+        .takes_value(true);
+}
+
 fn main() {
     let matches = App::new("piwfs")
         .version("0.2.3")
@@ -11,8 +86,82 @@ fn main() {
         .subcommand(
             SubCommand::with_name("master")
                 .about("The authoritative instance")
-                .version("0")
-                .author("Noone"),
+                .version("0.2.3")
+                .author("Szymon Mikulicz <szymon.mikulicz@posteo.net>")
+                .arg(
+                    Arg::with_name("slave")
+                        .long("slave")
+                        .value_name("ADDR")
+                        .help("Adds a slave to the session (host or host:port) [default: geometry hosts]")
+                        .required_unless_one(&["discover", "geometry"])
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("startat")
+                        .short("s")
+                        .long("startat")
+                        .value_name("TIMESTAMP")
+                        .help("Sets start point for playback (defaults to now + delay)")
+                        .conflicts_with("delay")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("delay")
+                        .long("delay")
+                        .value_name("SECONDS")
+                        .help("Sets how far in the future playback starts [default: 10]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .value_name("MILLISECONDS")
+                        .help("Sets how long to wait for each slave to acknowledge [default: 1000]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("discover")
+                        .long("discover")
+                        .value_name("SECONDS")
+                        .help("Listens for slave announcements and adds every slave found")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("discovery-iface")
+                        .long("discovery-iface")
+                        .value_name("IP")
+                        .help("Sets the address of the interface to listen for announcements on")
+                        .requires("discover")
+                        .takes_value(true),
+                )
+                .arg(discovery_group_arg())
+                .arg(
+                    Arg::with_name("ptp")
+                        .long("ptp")
+                        .help("Acts as PTP grandmaster for the slaves until interrupted"),
+                )
+                .arg(ptp_domain_arg())
+                .arg(
+                    Arg::with_name("log-sync-interval")
+                        .long("log-sync-interval")
+                        .value_name("LOG2")
+                        .help("Sets interval between PTP Sync messages to 2^LOG2 seconds [default: 0]")
+                        .requires("ptp")
+                        .allow_hyphen_values(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("log-announce-interval")
+                        .long("log-announce-interval")
+                        .value_name("LOG2")
+                        .help("Sets interval between PTP Announce messages to 2^LOG2 seconds [default: 1]")
+                        .requires("ptp")
+                        .allow_hyphen_values(true)
+                        .takes_value(true),
+                )
+                .args(&session_args(Arg::with_name("testfile").required(true))),
         )
         .subcommand(
             SubCommand::with_name("slave")
@@ -32,40 +181,68 @@ fn main() {
                         .short("s")
                         .long("startat")
                         .value_name("TIMESTAMP")
-                        .required(true)
+                        .required_unless("listen")
                         .help("Sets start point for playback")
                         .takes_value(true),
                 )
+                .args(&session_args(
+                    Arg::with_name("testfile").required_unless("listen"),
+                ))
                 .arg(
-                    Arg::with_name("testfile")
-                        .short("t")
-                        .long("testfile")
-                        .value_name("PATH")
-                        .help("Sets path to file to play")
-                        .required(true)
+                    Arg::with_name("listen")
+                        .short("l")
+                        .long("listen")
+                        .value_name("ADDR")
+                        .help("Waits for sessions from the master on ADDR (host or host:port)")
+                        .conflicts_with_all(&["startat", "testfile"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no-announce")
+                        .long("no-announce")
+                        .requires("listen")
+                        .help("Disables multicast announcements in listen mode"),
+                )
+                .arg(discovery_group_arg())
+                .arg(
+                    Arg::with_name("speaker")
+                        .long("speaker")
+                        .value_name("X,Y,ANGLE")
+                        .help("Adds an output channel driving a loudspeaker facing ANGLE degrees")
+                        .conflicts_with("geometry")
+                        .multiple(true)
+                        .number_of_values(1)
                         .takes_value(true),
                 )
                 .arg(
-                    Arg::with_name("desync-avg")
-                        .long("desync-avg")
-                        .short("a")
-                        .value_name("AVG_SIZE")
-                        .help("Sets length of moving average for desync calculation")
+                    Arg::with_name("speaker-id")
+                        .long("speaker-id")
+                        .value_name("ID")
+                        .help("Drives the speaker with ID from the geometry [default: all of this host]")
+                        .conflicts_with("speaker")
+                        .multiple(true)
+                        .number_of_values(1)
                         .takes_value(true),
                 )
                 .arg(
-                    Arg::with_name("estimation-avg")
-                        .long("estimation-avg")
-                        .value_name("AVG_SIZE")
-                        .help("Sets length of moving average for sample length estimation")
+                    Arg::with_name("alias-freq")
+                        .long("alias-freq")
+                        .value_name("HZ")
+                        .help("Sets aliasing frequency of the loudspeaker array [default: from geometry or 1500]")
                         .takes_value(true),
                 )
                 .arg(
-                    Arg::with_name("quality")
-                        .short("q")
-                        .long("quality")
-                        .value_name("SINC_SAMPLES")
-                        .help("Interpolation quality")
+                    Arg::with_name("channels")
+                        .long("channels")
+                        .value_name("MAP")
+                        .help("Selects file channels for the outputs as SRC[:DST][*GAIN],... e.g. 5,6 or 1:1*0.5,2:1*0.5, *:1 downmixes all [default: all in order]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tstamp-clock")
+                        .long("tstamp-clock")
+                        .value_name("CLOCK")
+                        .help("Sets clock the sound card timestamps with, monotonic clocks are mapped to the system clock without following its steps [default: realtime]")
                         .takes_value(true),
                 )
                 .arg(
@@ -73,6 +250,172 @@ fn main() {
                         .long("no-correction")
                         .help("Disables resampling"),
                 )
+                .arg(
+                    Arg::with_name("asrc")
+                        .long("asrc")
+                        .help("Resamples continuously at the estimated clock ratio, seeks only large offsets")
+                        .conflicts_with("no-correction"),
+                )
+                .arg(
+                    Arg::with_name("servo")
+                        .long("servo")
+                        .help("Steers the correction with a PI servo instead of the regression")
+                        .conflicts_with("no-correction"),
+                )
+                .arg(
+                    Arg::with_name("servo-kp")
+                        .long("servo-kp")
+                        .value_name("GAIN")
+                        .help("Sets proportional gain of the servo in ppm per us of offset [default: 4]")
+                        .requires("servo")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("servo-ki")
+                        .long("servo-ki")
+                        .value_name("GAIN")
+                        .help("Sets integral gain of the servo in ppm per us of offset and second [default: 4]")
+                        .requires("servo")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("step-threshold")
+                        .long("step-threshold")
+                        .value_name("SECONDS")
+                        .help("Sets offset beyond which the servo jumps instead of slewing [default: 0.002]")
+                        .requires("servo")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-slew")
+                        .long("max-slew")
+                        .value_name("PPM")
+                        .help("Sets largest frequency correction of the servo [default: 500]")
+                        .requires("servo")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("integral-limit")
+                        .long("integral-limit")
+                        .value_name("PPM")
+                        .help("Sets largest drift the servo integrates [default: 300]")
+                        .requires("servo")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("kalman")
+                        .long("kalman")
+                        .help("Tracks desync and sample length with Kalman filters instead of sliding windows"),
+                )
+                .arg(
+                    Arg::with_name("process-noise")
+                        .long("process-noise")
+                        .value_name("DENSITY")
+                        .help("Sets random walk of the clock rate in samples^2/s^3 [default: 0.001]")
+                        .requires("kalman")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("measurement-noise")
+                        .long("measurement-noise")
+                        .value_name("VARIANCE")
+                        .help("Sets variance of a single measurement in samples^2 [default: 1]")
+                        .requires("kalman")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ptp")
+                        .long("ptp")
+                        .help("Follows a PTP grandmaster without adjusting the system clock, instead of ptp4l"),
+                )
+                .arg(ptp_domain_arg())
+                .arg(
+                    Arg::with_name("ntp")
+                        .long("ntp")
+                        .help("Plays once NTP synchronized the system clock, tolerating the error it estimates")
+                        .conflicts_with_all(&["ptp", "ntp-shm"]),
+                )
+                .arg(
+                    Arg::with_name("ntp-shm")
+                        .long("ntp-shm")
+                        .value_name("UNIT")
+                        .help("Follows the reference clock in the NTP shared memory segment of UNIT without adjusting the system clock")
+                        .conflicts_with("ptp")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ptp4l")
+                        .long("ptp4l")
+                        .help("Watches ptp4l disciplining the system clock over its management socket")
+                        .conflicts_with("ptp"),
+                )
+                .arg(
+                    Arg::with_name("ptp4l-socket")
+                        .long("ptp4l-socket")
+                        .value_name("PATH")
+                        .help("Sets management socket of ptp4l [default: /var/run/ptp4l]")
+                        .requires("ptp4l")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("lock-policy")
+                        .long("lock-policy")
+                        .value_name("POLICY")
+                        .help("Sets what to do when ptp4l loses the grandmaster or drifts off: warn, mute or stop [default: warn]")
+                        .requires("ptp4l")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-offset")
+                        .long("max-offset")
+                        .value_name("MICROSECONDS")
+                        .help("Sets largest ptp4l master offset tolerated [default: 1000]")
+                        .requires("ptp4l")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("gain")
+                        .long("gain")
+                        .value_name("DB")
+                        .help("Sets output level, changed at runtime by control --gain [default: 0]")
+                        .allow_hyphen_values(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("fade")
+                        .long("fade")
+                        .value_name("MILLISECONDS")
+                        .help("Sets fade-in at the start and fade-out when stopping [default: 10]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("cue-fade")
+                        .long("cue-fade")
+                        .value_name("MILLISECONDS")
+                        .help("Sets fade around pause, resume and seek commands [default: 10]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("join-fade")
+                        .long("join-fade")
+                        .value_name("MILLISECONDS")
+                        .help("Sets fade-in after joining a session late [default: 50]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("loop-fade")
+                        .long("loop-fade")
+                        .value_name("MILLISECONDS")
+                        .help("Sets fade around the loop point of looped files [default: 0]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("fade-curve")
+                        .long("fade-curve")
+                        .value_name("CURVE")
+                        .help("Sets shape of fades and gain ramps: linear, equal-power or raised-cosine [default: linear]")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("no-spinning")
                         .long("no-spinning")
@@ -84,10 +427,88 @@ fn main() {
                         .help("Disables sample length estimation"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("control")
+                .about("Pauses, resumes or seeks the session the slaves play, or sets their gain")
+                .version("0.2.3")
+                .author("Szymon Mikulicz <szymon.mikulicz@posteo.net>")
+                .arg(
+                    Arg::with_name("slave")
+                        .long("slave")
+                        .value_name("ADDR")
+                        .help("Adds a slave to send the command to (host or host:port)")
+                        .required(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pause")
+                        .long("pause")
+                        .help("Pauses playback")
+                        .required_unless_one(&["resume", "seek", "gain"])
+                        .conflicts_with_all(&["resume", "seek", "gain"]),
+                )
+                .arg(
+                    Arg::with_name("resume")
+                        .long("resume")
+                        .help("Resumes paused playback")
+                        .conflicts_with_all(&["seek", "gain"]),
+                )
+                .arg(
+                    Arg::with_name("seek")
+                        .long("seek")
+                        .value_name("SECONDS")
+                        .help("Goes on from this point of the file")
+                        .conflicts_with("gain")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("gain")
+                        .long("gain")
+                        .value_name("DB")
+                        .help("Sets output level of the slaves right away")
+                        .allow_hyphen_values(true)
+                        .conflicts_with_all(&["at", "delay"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ramp")
+                        .long("ramp")
+                        .value_name("MILLISECONDS")
+                        .help("Sets how long the slaves take to reach the gain [default: 500]")
+                        .requires("gain")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("at")
+                        .long("at")
+                        .value_name("TIMESTAMP")
+                        .help("Sets when the command takes effect (defaults to now + delay)")
+                        .conflicts_with("delay")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("delay")
+                        .long("delay")
+                        .value_name("SECONDS")
+                        .help("Sets how far in the future the command takes effect [default: 1]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .value_name("MILLISECONDS")
+                        .help("Sets how long to wait for each slave to acknowledge [default: 1000]")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
     if let Some(matches) = matches.subcommand_matches("master") {
         master::main(matches);
     } else if let Some(matches) = matches.subcommand_matches("slave") {
         slave::main(matches);
+    } else if let Some(matches) = matches.subcommand_matches("control") {
+        master::control(matches);
     }
 }
@@ -1,5 +1,244 @@
+use piwfs::discovery::{self, Discovery};
+use piwfs::geometry::Geometry;
+use piwfs::protocol::{self, Message};
+use piwfs::ptp::grandmaster::{Grandmaster, GrandmasterConfig};
+use piwfs::ptp::{self, grandmaster};
+use piwfs::session::Session;
+use piwfs::timeline::{Action, Cue};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use clap::ArgMatches;
 
-pub fn main(_args: &ArgMatches) {
-    return;
+fn announce(addr: &str, msg: &Message, timeout: Duration) -> Result<(), String> {
+    let sock_addr = protocol::resolve(addr).map_err(|err| err.to_string())?;
+    let reply = protocol::request(&sock_addr, msg, timeout).map_err(|err| err.to_string())?;
+    return match reply.command.as_str() {
+        "ACK" => Ok(()),
+        "NAK" => Err(reply.get("reason").unwrap_or("no reason given").to_string()),
+        other => Err(format!("Unexpected reply {}", other)),
+    };
+}
+
+/// Value of the log2 interval option `name`, `default` when not given.
+fn log_interval(args: &ArgMatches, name: &str, default: i8) -> i8 {
+    return args.value_of(name).map_or(default, |value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("[ERR] Couldn't parse {} as an integer", name))
+    });
+}
+
+fn start_grandmaster(args: &ArgMatches) -> Grandmaster {
+    let default = GrandmasterConfig::default();
+    let config = GrandmasterConfig {
+        domain: ptp::domain_from_args(args),
+        log_sync_interval: log_interval(args, "log-sync-interval", default.log_sync_interval),
+        log_announce_interval: log_interval(
+            args,
+            "log-announce-interval",
+            default.log_announce_interval,
+        ),
+        ..default
+    };
+    let grandmaster = Grandmaster::start(config).expect("[ERR] Couldn't bind PTP ports");
+    println!(
+        "[INF] PTP grandmaster {} in domain {}, Sync every {:?}",
+        grandmaster.identity(),
+        config.domain,
+        grandmaster::interval(config.log_sync_interval)
+    );
+    return grandmaster;
+}
+
+/// Keeps the grandmaster running until interrupted, reporting the slaves
+/// following it every `report` interval.
+fn serve_ptp(grandmaster: Grandmaster, report: Duration) {
+    let sigint = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&sigint))
+        .expect("[ERR] Error setting SIGINT hook");
+    let mut last_report = Instant::now();
+    while !sigint.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(100));
+        if last_report.elapsed() < report {
+            continue;
+        }
+        last_report = Instant::now();
+        for (id, client) in grandmaster.clients() {
+            let interval = client
+                .interval
+                .map_or("-".to_string(), |interval| format!("{:.2} s", interval));
+            let delay = client.delay.map_or("-".to_string(), |(median, deviation)| {
+                format!("{:+.1}±{:.1} us", median * 1e6, deviation * 1e6)
+            });
+            println!(
+                "[INF] PTP slave {} ({}): {} requests every {}, Delay: {}",
+                id, client.addr, client.requests, interval, delay
+            );
+        }
+    }
+}
+
+/// Sends `msg` to all slaves in parallel, returns how many acknowledged.
+fn send(slaves: &[String], msg: &Message, timeout: Duration) -> usize {
+    let handles: Vec<_> = slaves
+        .iter()
+        .map(|addr| {
+            let addr = addr.clone();
+            let msg = msg.clone();
+            thread::spawn(move || announce(&addr, &msg, timeout))
+        })
+        .collect();
+    let mut acked = 0;
+    for (addr, handle) in slaves.iter().zip(handles) {
+        match handle.join().unwrap() {
+            Ok(()) => {
+                acked += 1;
+                println!("[INF] {}: acknowledged", addr);
+            }
+            Err(err) => println!("[ERR] {}: {}", addr, err),
+        }
+    }
+    println!("[INF] {}/{} slaves acknowledged", acked, slaves.len());
+    return acked;
+}
+
+fn timeout(args: &ArgMatches) -> Duration {
+    return Duration::from_millis(
+        args.value_of("timeout")
+            .unwrap_or("1000")
+            .parse::<u64>()
+            .expect("[ERR] Couldn't parse timeout as an unsigned integer"),
+    );
+}
+
+/// Sends a pause, resume or seek command to the slaves.
+pub fn control(args: &ArgMatches) {
+    let slaves: Vec<String> = args.values_of("slave").unwrap().map(String::from).collect();
+    if let Some(gain) = args.value_of("gain") {
+        let gain = gain
+            .parse::<f64>()
+            .expect("[ERR] Couldn't parse gain as a number");
+        let ramp = args
+            .value_of("ramp")
+            .unwrap_or("500")
+            .parse::<f64>()
+            .expect("[ERR] Couldn't parse ramp as a number");
+        let msg = Message::new("GAIN")
+            .with("gain", gain)
+            .with("ramp", ramp / 1e3);
+        println!("[INF] Gain {:+.1} dB over {} ms", gain, ramp);
+        if send(&slaves, &msg, timeout(args)) < slaves.len() {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let at = match args.value_of("at") {
+        Some(at) => at
+            .parse::<u64>()
+            .expect("[ERR] Couldn't parse at as a unsigned integer number"),
+        None => {
+            let delay = args
+                .value_of("delay")
+                .unwrap_or("1")
+                .parse::<f64>()
+                .expect("[ERR] Couldn't parse delay as a number");
+            (SystemTime::now() + Duration::from_secs_f64(delay))
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        }
+    };
+    let action = if args.is_present("pause") {
+        Action::Pause
+    } else if args.is_present("resume") {
+        Action::Resume
+    } else {
+        Action::Seek(
+            args.value_of("seek")
+                .unwrap()
+                .parse::<f64>()
+                .expect("[ERR] Couldn't parse seek position as a number"),
+        )
+    };
+    println!("[INF] {:?} at {}", action, at);
+    if send(&slaves, &Cue { at, action }.to_message(), timeout(args)) < slaves.len() {
+        std::process::exit(1);
+    }
+}
+
+pub fn main(args: &ArgMatches) {
+    let session = Session::from_args(args);
+    let grandmaster = if args.is_present("ptp") {
+        Some(start_grandmaster(args))
+    } else {
+        None
+    };
+    let timeout = timeout(args);
+    let mut slaves: Vec<String> = args
+        .values_of("slave")
+        .map_or(Vec::new(), |slaves| slaves.map(String::from).collect());
+
+    if let Some(seconds) = args.value_of("discover") {
+        let seconds = seconds
+            .parse::<f64>()
+            .expect("[ERR] Couldn't parse discovery time as a number");
+        let iface = args
+            .value_of("discovery-iface")
+            .unwrap_or("0.0.0.0")
+            .parse()
+            .expect("[ERR] Couldn't parse discovery interface as an IP address");
+        let group = discovery::group_from_args(args);
+        let discovery = Discovery::listen(group, iface, 3 * discovery::INTERVAL)
+            .expect("[ERR] Couldn't join discovery group");
+        println!("[INF] Discovering slaves on {} for {} s", group, seconds);
+        thread::sleep(Duration::from_secs_f64(seconds));
+        for (addr, slave) in discovery.roster() {
+            println!(
+                "[INF] {} ({}): {}, {} channels, {} Hz, piwfs {}",
+                slave.hostname, addr, slave.device, slave.channels, slave.rate, slave.version
+            );
+            if slave.version != env!("CARGO_PKG_VERSION") {
+                println!(
+                    "[WRN] {} runs a different piwfs version ({})",
+                    slave.hostname, slave.version
+                );
+            }
+            let addr = addr.to_string();
+            if !slaves.contains(&addr) {
+                slaves.push(addr);
+            }
+        }
+    }
+    if let Some(text) = &session.geometry {
+        let geometry = Geometry::parse(text).unwrap();
+        println!(
+            "[INF] Geometry: {} speakers on {} hosts",
+            geometry.speakers.len(),
+            geometry.hosts().len()
+        );
+        if slaves.is_empty() && !args.is_present("discover") {
+            slaves = geometry.hosts();
+        }
+    }
+    if slaves.is_empty() {
+        println!("[ERR] No slaves to send the session to");
+        std::process::exit(1);
+    }
+
+    println!(
+        "[INF] Session: {}, starting at {}",
+        session.testfile, session.startat
+    );
+    let acked = send(&slaves, &session.to_message(), timeout);
+    // The slaves that acknowledged follow the grandmaster either way
+    if let Some(grandmaster) = grandmaster {
+        serve_ptp(grandmaster, Duration::from_secs(10));
+    }
+    if acked < slaves.len() {
+        std::process::exit(1);
+    }
 }
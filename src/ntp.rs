@@ -0,0 +1,193 @@
+//! Time sources for venues with NTP only. `Kernel` trusts the system clock
+//! as long as the kernel says `chronyd` or `ntpd` keeps it synchronized and
+//! reports the error they estimate. `Shm` reads the samples a reference
+//! clock driver (`gpsd`, another time daemon) leaves in an NTP shared memory
+//! segment for `chronyd`'s SHM refclock, the offset of the reference from
+//! the system clock is the network offset then.
+
+#[cfg(test)]
+mod tests;
+
+use crate::clock::{self, TimeSource};
+
+use nix::libc;
+
+use std::io;
+use std::ptr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Key of the segment of unit 0, "NTP0"
+pub const SHM_KEY: i32 = 0x4e54_5030;
+/// Samples older than this are not used
+pub const MAX_AGE: Duration = Duration::from_secs(10);
+
+/// What the kernel clock discipline says about the system clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KernelStatus {
+    pub synchronized: bool,
+    /// Estimated and maximum error in seconds
+    pub est_error: f64,
+    pub max_error: f64,
+}
+
+impl KernelStatus {
+    /// Reads the status with `adjtimex`, without changing anything.
+    pub fn read() -> io::Result<KernelStatus> {
+        // All zeros is a valid timex, modes 0 only reads
+        let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+        let state = unsafe { libc::adjtimex(&mut timex) };
+        if state < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(KernelStatus {
+            synchronized: state != libc::TIME_ERROR && timex.status & libc::STA_UNSYNC == 0,
+            est_error: timex.esterror as f64 * 1e-6,
+            max_error: timex.maxerror as f64 * 1e-6,
+        });
+    }
+}
+
+/// System clock disciplined by an NTP daemon, the network time itself
+/// while synchronized.
+pub struct Kernel;
+
+impl TimeSource for Kernel {
+    fn offset(&self) -> Option<f64> {
+        return match KernelStatus::read() {
+            Ok(status) if status.synchronized => Some(0.),
+            _ => None,
+        };
+    }
+
+    fn error(&self) -> Option<f64> {
+        return KernelStatus::read().ok().map(|status| status.est_error);
+    }
+
+    fn name(&self) -> String {
+        return "NTP".to_string();
+    }
+}
+
+/// Layout of an NTP shared memory segment, `struct shmTime` of ntpd.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ShmTime {
+    pub mode: i32,
+    pub count: i32,
+    pub clock_sec: libc::time_t,
+    pub clock_usec: i32,
+    pub receive_sec: libc::time_t,
+    pub receive_usec: i32,
+    pub leap: i32,
+    /// Log2 of the precision of the reference in seconds
+    pub precision: i32,
+    pub nsamples: i32,
+    pub valid: i32,
+    pub clock_nsec: u32,
+    pub receive_nsec: u32,
+    pub dummy: [i32; 8],
+}
+
+/// One sample of a reference clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShmSample {
+    /// Time of the reference
+    pub clock: SystemTime,
+    /// System time the sample was taken at
+    pub receive: SystemTime,
+    /// Precision of the reference in seconds
+    pub precision: f64,
+}
+
+fn time(sec: libc::time_t, nsec: u32) -> SystemTime {
+    return UNIX_EPOCH + Duration::new(sec.max(0) as u64, nsec.min(999_999_999));
+}
+
+/// Reference clock samples in the NTP shared memory segment of a unit,
+/// attached read-only so `chronyd` may read the same segment.
+pub struct Shm {
+    unit: i32,
+    segment: *const ShmTime,
+}
+
+// The segment is only read, with volatile reads checked by its count
+unsafe impl Send for Shm {}
+unsafe impl Sync for Shm {}
+
+impl Shm {
+    /// Attaches to the segment of `unit`, which the reference clock driver
+    /// has to have created. Units 0 and 1 are only readable by root.
+    pub fn attach(unit: i32) -> io::Result<Shm> {
+        let id = unsafe { libc::shmget(SHM_KEY + unit, std::mem::size_of::<ShmTime>(), 0) };
+        if id < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let segment = unsafe { libc::shmat(id, ptr::null(), libc::SHM_RDONLY) };
+        if segment as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(Shm {
+            unit,
+            segment: segment as *const ShmTime,
+        });
+    }
+
+    /// The last sample, `None` when the writer is in the middle of one, it
+    /// was invalidated or there never was one. The count is checked in
+    /// both modes like `chronyd` and `ntpd` do, writers of mode 0 bump it
+    /// too.
+    pub fn sample(&self) -> Option<ShmSample> {
+        let count = unsafe { ptr::addr_of!((*self.segment).count).read_volatile() };
+        let shm = unsafe { self.segment.read_volatile() };
+        let after = unsafe { ptr::addr_of!((*self.segment).count).read_volatile() };
+        if count != after || shm.valid == 0 || (shm.receive_sec == 0 && shm.receive_nsec == 0) {
+            return None;
+        }
+        // Writers of mode 0 may leave the nanoseconds out
+        let nanoseconds = |sec_usec: i32, nsec: u32| {
+            if nsec / 1000 == sec_usec as u32 {
+                nsec
+            } else {
+                sec_usec.max(0) as u32 * 1000
+            }
+        };
+        return Some(ShmSample {
+            clock: time(shm.clock_sec, nanoseconds(shm.clock_usec, shm.clock_nsec)),
+            receive: time(
+                shm.receive_sec,
+                nanoseconds(shm.receive_usec, shm.receive_nsec),
+            ),
+            precision: 2f64.powi(shm.precision),
+        });
+    }
+
+    /// The last sample when it is younger than `MAX_AGE` at `now`
+    pub fn fresh_sample(&self, now: SystemTime) -> Option<ShmSample> {
+        return self
+            .sample()
+            .filter(|sample| clock::diff(now, sample.receive).abs() <= MAX_AGE.as_secs_f64());
+    }
+}
+
+impl TimeSource for Shm {
+    fn offset(&self) -> Option<f64> {
+        let sample = self.fresh_sample(SystemTime::now())?;
+        return Some(clock::diff(sample.clock, sample.receive));
+    }
+
+    fn error(&self) -> Option<f64> {
+        return self
+            .fresh_sample(SystemTime::now())
+            .map(|sample| sample.precision);
+    }
+
+    fn name(&self) -> String {
+        return format!("SHM {}", self.unit);
+    }
+}
+
+impl Drop for Shm {
+    fn drop(&mut self) {
+        unsafe { libc::shmdt(self.segment as *const libc::c_void) };
+    }
+}
@@ -0,0 +1,105 @@
+use super::*;
+
+/// Shared memory segment of a unit nobody else uses, writable like the
+/// one of a reference clock driver.
+struct Segment {
+    id: i32,
+    segment: *mut ShmTime,
+    unit: i32,
+}
+
+impl Segment {
+    fn create() -> Segment {
+        let unit = 0x1000 + (std::process::id() % 0x1000) as i32;
+        let id = unsafe {
+            libc::shmget(
+                SHM_KEY + unit,
+                std::mem::size_of::<ShmTime>(),
+                libc::IPC_CREAT | 0o600,
+            )
+        };
+        assert!(id >= 0, "{}", io::Error::last_os_error());
+        let segment = unsafe { libc::shmat(id, ptr::null(), 0) } as *mut ShmTime;
+        assert_ne!(segment as isize, -1, "{}", io::Error::last_os_error());
+        unsafe { segment.write_volatile(ShmTime::default()) };
+        return Segment { id, segment, unit };
+    }
+
+    fn write(&self, clock: SystemTime, receive: SystemTime, precision: i32) {
+        let clock = clock.duration_since(UNIX_EPOCH).unwrap();
+        let receive = receive.duration_since(UNIX_EPOCH).unwrap();
+        unsafe {
+            let mut shm = self.segment.read_volatile();
+            shm.mode = 1;
+            shm.count += 1;
+            shm.clock_sec = clock.as_secs() as libc::time_t;
+            shm.clock_usec = clock.subsec_micros() as i32;
+            shm.clock_nsec = clock.subsec_nanos();
+            shm.receive_sec = receive.as_secs() as libc::time_t;
+            shm.receive_usec = receive.subsec_micros() as i32;
+            shm.receive_nsec = receive.subsec_nanos();
+            shm.precision = precision;
+            shm.valid = 1;
+            shm.count += 1;
+            self.segment.write_volatile(shm);
+        }
+    }
+
+    /// Marks the sample as consumed, as `chronyd` does after reading it
+    fn invalidate(&self) {
+        unsafe {
+            let mut shm = self.segment.read_volatile();
+            shm.valid = 0;
+            self.segment.write_volatile(shm);
+        }
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe {
+            libc::shmdt(self.segment as *const libc::c_void);
+            libc::shmctl(self.id, libc::IPC_RMID, ptr::null_mut());
+        }
+    }
+}
+
+#[test]
+fn test_shm() {
+    let segment = Segment::create();
+    let shm = Shm::attach(segment.unit).unwrap();
+    assert_eq!(shm.name(), format!("SHM {}", segment.unit));
+    // Nothing written yet
+    assert_eq!(shm.sample(), None);
+    assert_eq!(TimeSource::offset(&shm), None);
+
+    let receive = SystemTime::now();
+    let clock = clock::shift(receive, 0.0125);
+    segment.write(clock, receive, -10);
+    let sample = shm.sample().unwrap();
+    assert_eq!(sample.clock, clock);
+    assert_eq!(sample.receive, receive);
+    let offset = TimeSource::offset(&shm).unwrap();
+    assert!((offset - 0.0125).abs() < 1e-9, "{} s", offset);
+    assert_eq!(shm.error(), Some(1. / 1024.));
+    segment.invalidate();
+    assert_eq!(shm.sample(), None);
+    assert_eq!(TimeSource::offset(&shm), None);
+
+    // Samples of a driver that stopped writing go stale
+    let receive = clock::shift(SystemTime::now(), -60.);
+    segment.write(clock::shift(receive, 0.0125), receive, -10);
+    assert!(shm.sample().is_some());
+    assert_eq!(TimeSource::offset(&shm), None);
+    assert_eq!(shm.error(), None);
+
+    assert!(Shm::attach(segment.unit + 1).is_err());
+}
+
+#[test]
+fn test_kernel_status() {
+    let status = KernelStatus::read().unwrap();
+    assert!(status.est_error >= 0. && status.max_error >= 0.);
+    assert_eq!(Kernel.offset().is_some(), status.synchronized);
+    assert_eq!(Kernel.name(), "NTP");
+}
@@ -0,0 +1,1137 @@
+//! Synchronized playback of a session on an audio sink. Every period the
+//! player collects sink statuses, estimates when the next written frame is
+//! heard and how long a frame really takes, regresses the desync between
+//! the file position and the schedule and corrects it by seeking and
+//! interpolating. In ASRC mode the file is resampled continuously at the
+//! regressed clock ratio instead and only large offsets are seeked. Either
+//! way a PI servo can take the place of the regression. Kalman filters can
+//! replace the sliding windows of the regression and of the frame duration
+//! median. With a `TimeSource` the schedule follows the network time it
+//! reports instead of the system clock. A `Timeline` pauses, resumes and
+//! seeks the session at given instants. Starts, stops, commands and loop
+//! points are faded along a `fade::Curve` and a shared `fade::Level` sets
+//! the gain. The loop itself is left to the caller, see `SyncedPlayer::step`.
+
+#[cfg(test)]
+mod tests;
+
+use indicator::{Average, Indicator, Kalman, LinearRegression, Median, Variance};
+
+use crate::clock::{self, TimeSource};
+use crate::decoder::{self, Decoder, Looped, Mixed};
+use crate::fade::{self, Curve, Level, Ramp};
+use crate::geometry::Layout;
+use crate::resampler::{FractionalDelay, VariableRate};
+use crate::servo::{PiServo, ServoConfig, ServoState};
+use crate::session::Session;
+use crate::sink::{AudioSink, SinkError, SinkState};
+use crate::timeline::Timeline;
+use crate::wfs::{Driving, Loudspeaker, Renderer, DEFAULT_ALIAS_FREQ};
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Average duration of a frame between consecutive statuses, `fallback`
+/// for pairs that went backwards.
+pub fn measure_sample_duration(stamps: &[SystemTime], delays: &[i64], fallback: f64) -> f64 {
+    return stamps
+        .windows(2)
+        .zip(delays.windows(2))
+        .fold(0., |acc, (stampw, delayw)| {
+            let mtime = clock::diff(stampw[1], stampw[0]) / (delayw[0] - delayw[1]) as f64;
+            acc + if mtime > 0. {
+                mtime
+            } else {
+                println!("[WRN] Non-continous status times or delays");
+                fallback
+            }
+        })
+        / (stamps.len() - 1) as f64;
+}
+
+/// Time at which the frame written next is heard, averaged over statuses.
+pub fn next_sample_time(stamps: &[SystemTime], delays: &[i64], sample_duration: f64) -> SystemTime {
+    return UNIX_EPOCH
+        + stamps
+            .iter()
+            .zip(delays.iter())
+            .fold(Duration::new(0, 0), |acc, (stamp, delay)| {
+                acc + (stamp.duration_since(UNIX_EPOCH).unwrap()
+                    + Duration::from_secs_f64(sample_duration * *delay as f64))
+                    / stamps.len().try_into().unwrap()
+            });
+}
+
+/// Driving functions of the loudspeakers for the source at `time` seconds
+/// after the start, silence for unused channels.
+fn drivings(session: &Session, speakers: &[Option<Loudspeaker>], time: f64) -> Vec<Driving> {
+    let source = session.source_at(time).unwrap();
+    return speakers
+        .iter()
+        .map(|speaker| match speaker {
+            Some(speaker) => source.driving(speaker, session.reference),
+            None => Driving {
+                delay: 0.,
+                gain: 0.,
+            },
+        })
+        .collect();
+}
+
+/// Time over which ASRC absorbs an offset from the schedule, in seconds
+const ASRC_RESPONSE: f64 = 0.5;
+/// Largest deviation of the ASRC step from one frame per frame
+const ASRC_MAX_DEVIATION: f64 = 1e-3;
+
+/// Noise the Kalman filters assume, the same for the desync and for the
+/// frames played.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KalmanConfig {
+    /// Density of the random walk of the clock rate, in samples squared per
+    /// second cubed
+    pub process: f64,
+    /// Variance of a single measurement, in samples squared
+    pub measurement: f64,
+}
+
+impl Default for KalmanConfig {
+    fn default() -> KalmanConfig {
+        return KalmanConfig {
+            process: 1e-3,
+            measurement: 1.,
+        };
+    }
+}
+
+/// Line through `(time, value)` measurements, over a sliding window or
+/// tracked by a Kalman filter.
+enum Trend {
+    Regression(LinearRegression<f64>),
+    Kalman(Kalman<f64>),
+}
+
+impl Trend {
+    fn new(size: usize, kalman: Option<KalmanConfig>) -> Trend {
+        return match kalman {
+            Some(noise) => Trend::Kalman(Kalman::with_noise(noise.process, noise.measurement)),
+            None => Trend::Regression(LinearRegression::new(size).unwrap()),
+        };
+    }
+
+    fn next(&mut self, el: (f64, f64)) {
+        match self {
+            Trend::Regression(regression) => regression.next(el),
+            Trend::Kalman(kalman) => kalman.next(el),
+        }
+    }
+
+    /// Offset and slope of the line
+    fn value(&self) -> Option<(f64, f64)> {
+        return match self {
+            Trend::Regression(regression) => regression.value(),
+            Trend::Kalman(kalman) => kalman.value(),
+        };
+    }
+}
+
+/// Device-independent switches of the synchronization loop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayerConfig {
+    /// Seeks and resamples to follow the desync
+    pub is_correction: bool,
+    /// Queries the sink repeatedly while waiting for room in the buffer
+    pub is_spinning: bool,
+    /// Measures the true frame duration of the sink
+    pub is_estimation: bool,
+    /// Largest seek in one period, in samples
+    pub max_jump: i64,
+    /// Resamples continuously at the estimated clock ratio instead of
+    /// seeking every period
+    pub is_asrc: bool,
+    /// Offset from the schedule beyond which ASRC seeks, in samples
+    pub seek_threshold: f64,
+    /// Steers the correction with a PI servo instead of the regression
+    pub servo: Option<ServoConfig>,
+    /// Tracks the desync and the frames played with Kalman filters instead
+    /// of the sliding windows of the session
+    pub kalman: Option<KalmanConfig>,
+    /// Fade-in after joining a session late, in seconds
+    pub join_fade: f64,
+    /// Fade-out before and fade-in after a timeline command, in seconds
+    pub cue_fade: f64,
+    /// Fade-in at the start of the session and fade-out when stopping, in
+    /// seconds
+    pub start_fade: f64,
+    /// Fade-out before and fade-in after the loop point, in seconds
+    pub loop_fade: f64,
+    /// Shape of all fades and gain ramps
+    pub fade_curve: Curve,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> PlayerConfig {
+        return PlayerConfig {
+            is_correction: true,
+            is_spinning: true,
+            is_estimation: true,
+            max_jump: 100,
+            is_asrc: false,
+            seek_threshold: 100.,
+            servo: None,
+            kalman: None,
+            join_fade: 0.05,
+            cue_fade: 0.01,
+            start_fade: 0.01,
+            loop_fade: 0.,
+            fade_curve: Curve::default(),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// A period was written
+    Playing,
+    /// The sink ran dry and was prepared again
+    Underrun,
+    /// The file is over, nothing was written
+    Finished,
+}
+
+/// Measurements of the last period.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerState {
+    /// Regressed offset of the file position from the schedule in samples,
+    /// minus the time left until the start before playback
+    pub desync: f64,
+    /// Long term average of the measured offset
+    pub diff: f64,
+    /// Frames queued in the sink at the last status
+    pub delay: i64,
+    /// Statuses collected while waiting
+    pub spins: usize,
+    /// Estimated true frame duration of the sink in seconds
+    pub sample_duration: f64,
+    /// Time at which the first frame of the period is heard
+    pub next_sample_time: SystemTime,
+    /// Mean and variance of the error of `next_sample_time` in microseconds
+    pub est_error: (f64, f64),
+    /// File frames read per frame written, one unless resampling with ASRC
+    pub ratio: f64,
+    /// Seeks of the file to correct the desync
+    pub seeks: usize,
+    /// State of the servo with its frequency correction and integrated
+    /// drift in ppm
+    pub servo: Option<(ServoState, f64, f64)>,
+    /// Network time minus local time from the time source, in seconds
+    pub time_offset: Option<f64>,
+    /// Error of the time offset the source claims, in seconds. Seeks and
+    /// servo jumps wait for offsets beyond it.
+    pub time_error: Option<f64>,
+    /// Frames written to the sink
+    pub frames_written: i64,
+    pub underruns: usize,
+    /// Stages of the period with the time at which each one ended
+    pub timings: Vec<(&'static str, Duration)>,
+}
+
+pub struct SyncedPlayer<S: AudioSink> {
+    config: PlayerConfig,
+    session: Session,
+    speakers: Vec<Option<Loudspeaker>>,
+    reader: Box<dyn Decoder>,
+    renderer: Option<Renderer>,
+    sink: S,
+    /// Local time of the start of the session
+    startstamp: SystemTime,
+    time_source: Option<Arc<dyn TimeSource>>,
+    /// Ramps to silence while keeping the schedule
+    muted: bool,
+    /// The file was read from, whether the player joined late is decided
+    /// on the first read
+    joined: bool,
+    /// Frames faded in so far and length of the fade
+    fade: Option<(usize, usize)>,
+    timeline: Arc<Mutex<Timeline>>,
+    /// Segment of the timeline being played
+    segment: usize,
+    /// Frames the timeline moved the schedule by, kept out of the
+    /// regressions so the drift of the clocks they learned stays
+    moved: f64,
+    /// A timeline command moved the file position, the next read seeks
+    /// there directly
+    reanchor: bool,
+    /// Length of the file when looping, in frames
+    loop_frames: Option<u64>,
+    level: Option<Arc<Level>>,
+    /// Gain following the level
+    gain: Ramp,
+    /// Fade-out after which the player stops
+    stopping: Option<Ramp>,
+    fs: u32,
+    num_channels: usize,
+    out_channels: usize,
+    buffer_fill: i64,
+    resampler: FractionalDelay,
+    /// Frames read around each period for the resampler
+    sinc_overlap: usize,
+    asrc: VariableRate,
+    /// File frames played through ASRC since the start
+    asrc_frames: i64,
+    servo: Option<PiServo>,
+    /// File position of the next frame when the servo steers seeking
+    position: f64,
+    sample_duration: f64,
+    desync: Trend,
+    act_desync_avg: Average<f64>,
+    correction: f64,
+    real_sample_duration_avg: Median<f64>,
+    /// Frames played against the time since the start, when the frame
+    /// duration comes from a Kalman filter
+    played: Option<Kalman<f64>>,
+    last_samples_pushed: i64,
+    /// Frames queued when the last period began
+    first_delay: i64,
+    /// Frames written and when they were estimated to be heard, compared
+    /// against later statuses
+    nsts: VecDeque<(i64, SystemTime)>,
+    est_error_var: Variance<f64>,
+    state: PlayerState,
+}
+
+impl<S: AudioSink> SyncedPlayer<S> {
+    /// Prepares `session` on the loudspeakers of `layout`, `open` is given
+    /// the device, channel count and sample rate the session needs.
+    pub fn new<F>(
+        config: PlayerConfig,
+        session: &Session,
+        layout: &Layout,
+        open: F,
+    ) -> Result<SyncedPlayer<S>, String>
+    where
+        F: FnOnce(&str, u32, u32) -> Result<S, SinkError>,
+    {
+        let reader = decoder::open(&session.testfile)?;
+        let (reader, loop_frames): (Box<dyn Decoder>, _) = if session.looping {
+            let looped = Looped::new(reader)?;
+            let length = looped.length();
+            (Box::new(looped), Some(length))
+        } else {
+            (reader, None)
+        };
+        let reader: Box<dyn Decoder> = match &layout.channels {
+            Some(map) => Box::new(Mixed::new(reader, map)?),
+            None => reader,
+        };
+        let fs = reader.sample_rate();
+        let num_channels = reader.channels();
+
+        let renderer = match session.source_at(0.) {
+            Some(_) => {
+                if num_channels != 1 {
+                    return Err("Rendering a virtual source requires a mono file".to_string());
+                }
+                if layout.speakers.is_empty() {
+                    return Err(
+                        "Rendering a virtual source requires at least one speaker".to_string()
+                    );
+                }
+                let mut renderer = Renderer::new(
+                    &drivings(session, &layout.speakers, 0.),
+                    fs as f64,
+                    session.predelay,
+                    layout.alias_freq.unwrap_or(DEFAULT_ALIAS_FREQ),
+                    session.quality,
+                );
+                // The farthest a moving source gets from a loudspeaker is at a keyframe
+                for key in session.trajectory.iter().flat_map(|t| t.keyframes()) {
+                    renderer.reserve(&drivings(session, &layout.speakers, key.time));
+                }
+                Some(renderer)
+            }
+            None => None,
+        };
+        let out_channels = renderer
+            .as_ref()
+            .map_or(num_channels, |renderer| renderer.channels());
+
+        let mut sink = open(&layout.device, out_channels as u32, fs)
+            .map_err(|err| format!("Couldn't open {}: {}", layout.device, err))?;
+        let buffer_fill = 2 * sink.period_size() * num_channels as i64;
+        sink.set_start_threshold(buffer_fill)
+            .map_err(|err| format!("Couldn't set start threshold: {}", err))?;
+        let sample_duration = 1. / (fs as f64);
+        let resampler = FractionalDelay::new(session.quality.design());
+        let asrc = VariableRate::new(FractionalDelay::new(session.quality.design()), num_channels);
+        return Ok(SyncedPlayer {
+            config,
+            session: session.clone(),
+            speakers: layout.speakers.clone(),
+            reader,
+            renderer,
+            sink,
+            startstamp: session.startstamp(),
+            time_source: None,
+            muted: false,
+            joined: false,
+            fade: None,
+            timeline: Arc::new(Mutex::new(Timeline::new(session.startat))),
+            segment: 0,
+            moved: 0.,
+            reanchor: false,
+            loop_frames,
+            level: None,
+            gain: Ramp::constant(1.),
+            stopping: None,
+            fs,
+            num_channels,
+            out_channels,
+            buffer_fill,
+            sinc_overlap: if config.is_correction {
+                resampler.half_width()
+            } else {
+                0
+            },
+            resampler,
+            asrc,
+            asrc_frames: 0,
+            servo: config.servo.map(PiServo::new),
+            position: 0.,
+            sample_duration,
+            desync: Trend::new(session.desync_avg, config.kalman),
+            act_desync_avg: Average::new(10000).unwrap(),
+            correction: 0.,
+            real_sample_duration_avg: Median::new(session.estimation_avg).unwrap(),
+            played: config
+                .kalman
+                .map(|noise| Kalman::with_noise(noise.process, noise.measurement)),
+            last_samples_pushed: 0,
+            first_delay: 0,
+            nsts: VecDeque::new(),
+            est_error_var: Variance::new(1000).unwrap(),
+            state: PlayerState {
+                desync: 0.,
+                diff: 0.,
+                delay: 0,
+                spins: 0,
+                sample_duration,
+                next_sample_time: session.startstamp(),
+                est_error: (0., 0.),
+                ratio: 1.,
+                seeks: 0,
+                servo: None,
+                time_offset: None,
+                time_error: None,
+                frames_written: 0,
+                underruns: 0,
+                timings: Vec::new(),
+            },
+        });
+    }
+
+    pub fn sink(&self) -> &S {
+        return &self.sink;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        return self.fs;
+    }
+
+    /// Channels written to the sink
+    pub fn channels(&self) -> usize {
+        return self.out_channels;
+    }
+
+    pub fn state(&self) -> &PlayerState {
+        return &self.state;
+    }
+
+    /// Schedules the session on the network time of `source` instead of
+    /// the system clock.
+    pub fn set_time_source(&mut self, source: Arc<dyn TimeSource>) {
+        self.time_source = Some(source);
+    }
+
+    /// Takes pause, resume and seek commands from `timeline`, which may
+    /// get more of them while playing.
+    pub fn set_timeline(&mut self, timeline: Arc<Mutex<Timeline>>) {
+        self.timeline = timeline;
+    }
+
+    /// Plays silence instead of the session while `muted`, the session
+    /// goes on and stays synchronized. The gain ramps down and back up over
+    /// the start fade.
+    pub fn set_muted(&mut self, muted: bool) {
+        if muted != self.muted {
+            self.muted = muted;
+            let frames = (self.config.start_fade * self.fs as f64).round() as usize;
+            let target = self.target_gain();
+            self.gain = Ramp::new(self.gain.value(), target, frames, self.config.fade_curve);
+        }
+    }
+
+    /// Gain the level asks for, none while muted
+    fn target_gain(&self) -> f32 {
+        if self.muted {
+            return 0.;
+        }
+        return self
+            .level
+            .as_ref()
+            .map_or(1., |level| fade::gain(level.get().0));
+    }
+
+    /// Plays at the gain of `level`, ramping to it whenever it changes.
+    pub fn set_level(&mut self, level: Arc<Level>) {
+        self.level = Some(level);
+        self.gain = Ramp::constant(self.target_gain());
+    }
+
+    /// Fades out over the start fade, `step` reports the session finished
+    /// once it is silent.
+    pub fn fade_out(&mut self) {
+        if self.stopping.is_none() {
+            let frames = (self.config.start_fade * self.fs as f64).round() as usize;
+            self.stopping = Some(Ramp::new(1., 0., frames, self.config.fade_curve));
+        }
+    }
+
+    fn mark(&mut self, stage: &'static str, start: Instant) {
+        self.state.timings.push((stage, start.elapsed()));
+    }
+
+    /// Collects statuses until there is room for another period.
+    fn spin(&mut self) -> Result<(Vec<SystemTime>, Vec<i64>), SinkError> {
+        let mut stamps = Vec::new();
+        let mut delays = Vec::new();
+        loop {
+            let status = self.sink.status()?;
+            let stamp = status.stamp;
+            if Some(&stamp) == stamps.last() {
+                continue;
+            }
+            let delay = status.delay;
+            if self.config.is_spinning {
+                stamps.push(stamp);
+                delays.push(delay);
+            } else {
+                self.sink
+                    .sleep(Duration::from_secs_f64(self.sample_duration / 2.));
+            }
+            if status.state != SinkState::Running || delay < self.buffer_fill {
+                if !self.config.is_spinning {
+                    stamps.push(stamp);
+                    delays.push(delay);
+                }
+                break;
+            }
+        }
+        return Ok((stamps, delays));
+    }
+
+    /// Compares the statuses against the times estimated for the frames
+    /// they report on.
+    fn estimate_error(&mut self, stamps: &[SystemTime], delays: &[i64]) {
+        for (stamp, delay) in stamps.iter().zip(delays.iter()) {
+            while let Some((ns, nst)) = self.nsts.front() {
+                let cur_ns = self.state.frames_written - delay;
+                if cur_ns == *ns {
+                    let err = clock::diff(*nst, *stamp) * 1_000_000.;
+                    self.est_error_var.next(err);
+                    if let Some(var) = self.est_error_var.value() {
+                        self.state.est_error = (self.est_error_var.average().unwrap(), var);
+                    }
+                    self.nsts.pop_front();
+                } else if cur_ns > *ns {
+                    self.nsts.pop_front();
+                    continue;
+                }
+                break;
+            }
+        }
+    }
+
+    fn estimate_sample_duration(&mut self, stamps: &[SystemTime], delays: &[i64]) {
+        let fallback = self.state.sample_duration;
+        if let Some(played) = self.played.as_mut() {
+            if self.config.is_estimation && self.sink.state() == SinkState::Running {
+                for (stamp, delay) in stamps.iter().zip(delays.iter()) {
+                    played.next((
+                        clock::diff(*stamp, self.session.startstamp()),
+                        (self.state.frames_written - delay) as f64,
+                    ));
+                }
+            }
+            // The slope is the number of frames played per second
+            self.state.sample_duration = match played.value() {
+                Some((_, rate)) if rate > 0. => 1. / rate,
+                _ => fallback,
+            };
+            return;
+        }
+        self.real_sample_duration_avg.next(
+            if self.config.is_estimation
+                && self.sink.state() == SinkState::Running
+                && stamps.len() > 1
+            {
+                measure_sample_duration(stamps, delays, fallback)
+            } else {
+                fallback
+            },
+        );
+        self.state.sample_duration = self.real_sample_duration_avg.value().unwrap();
+    }
+
+    /// Reads the next period of `frames`, seeking and interpolating to
+    /// follow the desync. Empty when the file is over.
+    fn fill(&mut self, start: Instant, frames: usize) -> Vec<f32> {
+        let num_channels = self.num_channels;
+        let sinc_overlap = self.sinc_overlap;
+        let sam_num = frames * num_channels;
+        let sam_num_over = sam_num + (2 * sinc_overlap + 1) * num_channels;
+        let real_sample_duration = self.state.sample_duration;
+        let mut buf: Vec<f32> = Vec::with_capacity(sam_num_over);
+
+        let mut zeros_pushed = 0.;
+        while self.startstamp
+            > self.state.next_sample_time
+                + Duration::from_secs_f64(real_sample_duration * zeros_pushed)
+        {
+            for _ in 0..num_channels {
+                buf.push(0.)
+            }
+            zeros_pushed += 1.;
+            if buf.len() == sam_num {
+                break;
+            } else if buf.len() > sam_num {
+                unreachable!()
+            }
+        }
+        let next_sample_time = self.state.next_sample_time
+            + Duration::from_secs_f64(real_sample_duration * zeros_pushed);
+
+        if buf.len() == sam_num {
+            self.state.desync = -self
+                .startstamp
+                .duration_since(next_sample_time)
+                .unwrap_or(Duration::new(0, 0))
+                .as_secs_f64()
+                / self.sample_duration;
+            self.state.diff = 0.;
+            return buf;
+        }
+
+        let next_sample_time_f64 = next_sample_time
+            .duration_since(self.startstamp)
+            .unwrap()
+            .as_secs_f64();
+        let position = self
+            .timeline
+            .lock()
+            .unwrap()
+            .position(self.segment, next_sample_time_f64);
+        let next_sample = match position {
+            Some(position) => position / self.sample_duration,
+            None => {
+                // Paused, the file stays where it is
+                buf.resize(sam_num, 0.);
+                self.state.desync = 0.;
+                self.state.diff = 0.;
+                return buf;
+            }
+        };
+        // Started after the session, the first read seeks straight to the
+        // schedule instead of catching up by `max_jump` every period
+        let late = !self.joined && next_sample > self.config.max_jump as f64;
+        self.joined = true;
+        if late {
+            let frames = (self.config.join_fade * self.fs as f64) as usize;
+            self.fade = if frames > 0 { Some((0, frames)) } else { None };
+        }
+        if self.config.is_correction && (self.config.is_asrc || self.servo.is_some()) {
+            let position = if self.config.is_asrc {
+                self.asrc.position()
+            } else {
+                self.position
+            };
+            let (jump, step) = if self.reanchor {
+                // Moved on the timeline, the clock ratio stays the same and
+                // the regression goes on from where it stands
+                self.reanchor = false;
+                if let (None, Some((drift_a, drift_b))) = (&self.servo, self.desync.value()) {
+                    self.moved = next_sample
+                        - self.asrc_frames as f64
+                        - (drift_a + drift_b * next_sample_time_f64);
+                }
+                (Some(next_sample), self.state.ratio)
+            } else if self.servo.is_some() {
+                self.steer_servo(next_sample_time_f64, next_sample, position)
+            } else {
+                self.steer_regression(next_sample_time_f64, next_sample)
+            };
+            self.state.diff = self.act_desync_avg.value().unwrap();
+            self.state.ratio = step;
+            return if self.config.is_asrc {
+                self.fill_asrc(buf, frames, jump, step, start)
+            } else {
+                self.fill_steered(buf, frames, jump, step, start)
+            };
+        }
+
+        let reader = &mut self.reader;
+        let next_read = reader.position().saturating_sub(sinc_overlap as u64 + 1);
+        let act_desync = next_sample - next_read as f64;
+        self.act_desync_avg.next(act_desync);
+        let exact = late || self.reanchor;
+        if self.reanchor {
+            // Moved on the timeline, the regression goes on from where it
+            // stands
+            if let Some((desync_a, desync_b)) = self.desync.value() {
+                self.moved =
+                    self.correction + act_desync - (desync_a + desync_b * next_sample_time_f64);
+            }
+            self.reanchor = false;
+        }
+        self.desync.next((
+            next_sample_time_f64,
+            self.correction + act_desync - self.moved,
+        ));
+        let (desync_a, desync_b) = self.desync.value().unwrap_or((0., 0.));
+        let (cur_desync, max_jump) = if exact {
+            (self.correction + act_desync, i64::MAX)
+        } else {
+            (
+                desync_a + desync_b * next_sample_time_f64 + self.moved,
+                self.config.max_jump,
+            )
+        };
+        let jump = ((cur_desync - self.correction).floor() as i64).clamp(-max_jump, max_jump);
+        let jumpto = if jump > 0 {
+            next_read.saturating_add(jump as u64)
+        } else {
+            next_read.saturating_sub((-jump) as u64)
+        }
+        .saturating_sub(sinc_overlap as u64)
+        .min(reader.frames().unwrap_or(u64::MAX));
+
+        if self.config.is_correction || exact {
+            if jumpto != next_read.saturating_sub(sinc_overlap as u64) {
+                self.state.seeks += 1;
+            }
+            self.correction += jumpto as f64 - next_read.saturating_sub(sinc_overlap as u64) as f64;
+            reader.seek(jumpto).unwrap_or_else(|err| {
+                panic!("[ERR] Couldn't seek in {}: {}", self.session.testfile, err)
+            });
+        }
+        self.mark("Seeking", start);
+
+        if let Err(err) = self.reader.read(&mut buf, sam_num_over) {
+            println!("[ERR] Couldn't decode {}: {}", self.session.testfile, err);
+        }
+
+        let ratio = cur_desync - self.correction;
+        if self.config.is_correction {
+            buf = if buf.len() > (2 * sinc_overlap + 1) * num_channels {
+                self.resampler.shift(&buf, ratio, num_channels)
+            } else {
+                // Too short to interpolate, drop the leading context frames
+                buf[(sinc_overlap * num_channels).min(buf.len())..].into()
+            }
+        }
+        self.mark("Interpolation", start);
+
+        self.state.desync = cur_desync - self.moved;
+        self.state.diff = self.act_desync_avg.value().unwrap();
+        return buf;
+    }
+
+    /// Offset from the schedule beyond which to seek in samples, at least
+    /// the error the time source claims.
+    fn seek_threshold(&self) -> f64 {
+        let tolerance = self.state.time_error.unwrap_or(0.) * self.fs as f64;
+        return self.config.seek_threshold.max(tolerance);
+    }
+
+    /// Position to jump to and file frames per frame from the regressed
+    /// drift of the schedule against the frames played through ASRC.
+    fn steer_regression(
+        &mut self,
+        next_sample_time_f64: f64,
+        next_sample: f64,
+    ) -> (Option<f64>, f64) {
+        // Offset of the schedule from the frames played, it only changes
+        // as fast as the clocks drift apart. Until the sink runs the
+        // schedule is a guess and stays out of the regression.
+        let drift = next_sample - self.moved - self.asrc_frames as f64;
+        self.act_desync_avg.next(drift);
+        let (drift_a, drift_b) = if self.sink.state() == SinkState::Running {
+            self.desync.next((next_sample_time_f64, drift));
+            self.desync.value().unwrap_or((drift, 0.))
+        } else {
+            (drift, 0.)
+        };
+        let target =
+            self.asrc_frames as f64 + self.moved + drift_a + drift_b * next_sample_time_f64;
+        let error = target - self.asrc.position();
+        self.state.desync = error;
+        let ratio = 1. + drift_b * self.state.sample_duration;
+        if error.abs() > self.seek_threshold() {
+            return (Some(target), ratio);
+        }
+        let step = (ratio + error / (ASRC_RESPONSE * self.fs as f64))
+            .clamp(1. - ASRC_MAX_DEVIATION, 1. + ASRC_MAX_DEVIATION);
+        return (None, step);
+    }
+
+    /// Position to jump to and file frames per frame as the servo sees fit
+    /// for the offset of the schedule from `position`.
+    fn steer_servo(
+        &mut self,
+        next_sample_time_f64: f64,
+        next_sample: f64,
+        position: f64,
+    ) -> (Option<f64>, f64) {
+        let seek_threshold = self.seek_threshold();
+        let servo = self.servo.as_mut().unwrap();
+        let offset = next_sample - position;
+        self.act_desync_avg.next(offset);
+        self.state.desync = offset;
+        if self.sink.state() != SinkState::Running {
+            // Until the sink runs the schedule is a guess, keep the servo out
+            let jump = offset.abs() > seek_threshold;
+            return (
+                if jump { Some(next_sample) } else { None },
+                1. + servo.drift() * 1e-6,
+            );
+        }
+        let ppm = servo.sample(offset * self.sample_duration, next_sample_time_f64);
+        self.state.servo = Some((servo.state(), ppm, servo.drift()));
+        let jump = if servo.state() == ServoState::Jump {
+            Some(next_sample)
+        } else {
+            None
+        };
+        return (jump, 1. + ppm * 1e-6);
+    }
+
+    /// Fills the rest of `buf` by resampling `step` file frames per frame,
+    /// after jumping to `jump`.
+    fn fill_asrc(
+        &mut self,
+        mut buf: Vec<f32>,
+        frames: usize,
+        jump: Option<f64>,
+        step: f64,
+        start: Instant,
+    ) -> Vec<f32> {
+        let num_channels = self.num_channels;
+        let frames = frames - buf.len() / num_channels;
+        if let Some(target) = jump {
+            if !self.asrc.seek(target) {
+                let frame = self.asrc.end().max(0) as u64;
+                self.reader
+                    .seek(frame.min(self.reader.frames().unwrap_or(u64::MAX)))
+                    .unwrap_or_else(|err| {
+                        panic!("[ERR] Couldn't seek in {}: {}", self.session.testfile, err)
+                    });
+            }
+            self.state.seeks += 1;
+        }
+        self.mark("Seeking", start);
+
+        let mut needed = self.asrc.needed(frames, step);
+        if self.asrc.end() < 0 && needed > 0 {
+            let silence = needed.min(-self.asrc.end() as usize);
+            self.asrc.push(&vec![0.; silence * num_channels]);
+            needed -= silence;
+        }
+        if needed > 0 {
+            let mut input = Vec::with_capacity(needed * num_channels);
+            if let Err(err) = self.reader.read(&mut input, needed * num_channels) {
+                println!("[ERR] Couldn't decode {}: {}", self.session.testfile, err);
+            }
+            self.asrc.push(&input);
+            if input.len() < needed * num_channels {
+                self.asrc.end_of_stream();
+            }
+        }
+        let out = self.asrc.process(frames, step);
+        self.asrc_frames += (out.len() / num_channels) as i64;
+        self.mark("Interpolation", start);
+
+        if out.is_empty() {
+            return out;
+        }
+        buf.extend(out);
+        return buf;
+    }
+
+    /// Fills the rest of `buf` from `position`, jumping to `jump` first,
+    /// and moves on by `step` file frames per frame.
+    fn fill_steered(
+        &mut self,
+        mut buf: Vec<f32>,
+        frames: usize,
+        jump: Option<f64>,
+        step: f64,
+        start: Instant,
+    ) -> Vec<f32> {
+        let num_channels = self.num_channels;
+        let sinc_overlap = self.sinc_overlap;
+        let frames = frames - buf.len() / num_channels;
+        if let Some(target) = jump {
+            self.position = target;
+        }
+        let first = (self.position.floor() as i64 - sinc_overlap as i64).clamp(
+            0,
+            self.reader
+                .frames()
+                .map_or(i64::MAX, |frames| frames as i64),
+        ) as u64;
+        let next_read = self
+            .reader
+            .position()
+            .saturating_sub(2 * sinc_overlap as u64 + 1);
+        if first != next_read {
+            self.state.seeks += 1;
+        }
+        self.reader.seek(first).unwrap_or_else(|err| {
+            panic!("[ERR] Couldn't seek in {}: {}", self.session.testfile, err)
+        });
+        self.mark("Seeking", start);
+
+        let mut input = Vec::with_capacity((frames + 2 * sinc_overlap + 1) * num_channels);
+        if let Err(err) = self
+            .reader
+            .read(&mut input, (frames + 2 * sinc_overlap + 1) * num_channels)
+        {
+            println!("[ERR] Couldn't decode {}: {}", self.session.testfile, err);
+        }
+        let out = if input.len() > (2 * sinc_overlap + 1) * num_channels {
+            let offset = self.position - (first as usize + sinc_overlap) as f64;
+            self.resampler.shift(&input, offset, num_channels)
+        } else {
+            // Too short to interpolate, drop the leading context frames
+            input[(sinc_overlap * num_channels).min(input.len())..].into()
+        };
+        self.position += (out.len() / num_channels) as f64 * step;
+        self.mark("Interpolation", start);
+
+        if out.is_empty() {
+            return out;
+        }
+        buf.extend(out);
+        return buf;
+    }
+
+    /// Ramps the gain up over the fade after a late join.
+    fn fade_in(&mut self, buf: &mut [f32]) {
+        let (done, frames) = match self.fade {
+            Some(fade) => fade,
+            None => return,
+        };
+        for (index, frame) in buf.chunks_mut(self.out_channels).enumerate() {
+            let gain = self
+                .config
+                .fade_curve
+                .gain((done + index) as f64 / frames as f64);
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+        let done = done + buf.len() / self.out_channels;
+        self.fade = if done < frames {
+            Some((done, frames))
+        } else {
+            None
+        };
+    }
+
+    /// Frames to write next, fewer than a period when a timeline command
+    /// takes effect before its end so the following period starts with it.
+    /// Playback going on in another segment is re-anchored on the next read.
+    fn plan(&mut self, block_time: f64) -> usize {
+        let period = self.sink.period_size() as usize;
+        // Commands within half a frame count as due
+        let time = block_time + self.state.sample_duration / 2.;
+        let timeline = self.timeline.lock().unwrap();
+        let segment = timeline.segment(time);
+        if segment != self.segment {
+            self.segment = segment;
+            self.reanchor = timeline.position(segment, time).is_some();
+        }
+        let cue = timeline.cue_after(time);
+        drop(timeline);
+        return match cue {
+            Some(at) => {
+                (((at - block_time) / self.state.sample_duration).round() as usize).clamp(1, period)
+            }
+            None => period,
+        };
+    }
+
+    /// Fades in at the start, out before and in after timeline commands
+    /// and around loop points, `buf` starting at `block_time`.
+    fn fade_schedule(&self, buf: &mut [f32], block_time: f64) {
+        let (start, cue) = (self.config.start_fade, self.config.cue_fade);
+        let (length, fade) = match self.loop_frames {
+            Some(length) if self.config.loop_fade > 0. => {
+                (length as f64, self.config.loop_fade * self.fs as f64)
+            }
+            _ => (0., 0.),
+        };
+        let timeline = self.timeline.lock().unwrap();
+        let half = self.state.sample_duration / 2.;
+        for (index, frame) in buf.chunks_mut(self.out_channels).enumerate() {
+            let time = block_time + index as f64 * self.state.sample_duration;
+            let mut x: f64 = 1.;
+            if start > 0. {
+                x = x.min(time.max(0.) / start);
+            }
+            if cue > 0. {
+                if let Some(at) = timeline.cue_after(time + half) {
+                    x = x.min((at - time) / cue);
+                }
+                if let Some(at) = timeline.cue_before(time + half) {
+                    x = x.min((time - at).max(0.) / cue);
+                }
+            }
+            if fade > 0. {
+                if let Some(position) = timeline.position_at(time + half) {
+                    let offset = (position * self.fs as f64).round() % length;
+                    x = x.min(offset.min(length - offset) / fade);
+                }
+            }
+            if x < 1. {
+                let gain = self.config.fade_curve.gain(x);
+                frame.iter_mut().for_each(|sample| *sample *= gain);
+            }
+        }
+    }
+
+    /// Applies the gain of the level or the mute and the fade-out when
+    /// stopping.
+    fn ramp_gain(&mut self, buf: &mut [f32]) {
+        let target = self.target_gain();
+        if target != self.gain.target() {
+            let ramp = self.level.as_ref().map_or(0., |level| level.get().1);
+            let frames = (ramp * self.fs as f64).round() as usize;
+            self.gain = Ramp::new(self.gain.value(), target, frames, self.config.fade_curve);
+        }
+        if self.gain.is_done() && self.gain.target() == 1. && self.stopping.is_none() {
+            return;
+        }
+        for frame in buf.chunks_mut(self.out_channels) {
+            let mut gain = self.gain.step();
+            if let Some(stopping) = self.stopping.as_mut() {
+                gain *= stopping.step();
+            }
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+
+    fn render(&mut self, buf: Vec<f32>, block_time: f64) -> Vec<f32> {
+        let renderer = match self.renderer.as_mut() {
+            Some(renderer) => renderer,
+            None => return buf,
+        };
+        if self.session.trajectory.is_some() {
+            let last = block_time + (buf.len() - 1) as f64 * self.sample_duration;
+            // The source follows the file position and stands still while paused
+            let position = self.timeline.lock().unwrap().position(self.segment, last);
+            if let Some(position) = position {
+                renderer.set_drivings(&drivings(&self.session, &self.speakers, position));
+            }
+        }
+        return renderer.process(&buf);
+    }
+
+    /// Waits for room in the sink and writes one period.
+    pub fn step(&mut self) -> Result<Step, SinkError> {
+        if self.stopping.is_some_and(|stopping| stopping.is_done()) {
+            return Ok(Step::Finished);
+        }
+        let start = Instant::now();
+        self.state.timings.clear();
+        self.state.frames_written += self.last_samples_pushed;
+        self.last_samples_pushed = 0;
+
+        let (stamps, delays) = self.spin()?;
+        if let Some(source) = self.time_source.as_ref() {
+            self.state.time_offset = source.offset();
+            self.state.time_error = source.error();
+            if let Some(servo) = self.servo.as_mut() {
+                servo.set_tolerance(self.state.time_error.unwrap_or(0.));
+            }
+            if let Some(offset) = self.state.time_offset {
+                self.startstamp = clock::shift(self.session.startstamp(), -offset);
+            }
+        }
+        self.first_delay = *delays.first().unwrap();
+        self.state.delay = *delays.last().unwrap();
+        self.state.spins = delays.len();
+        self.mark("Spinning", start);
+
+        self.estimate_error(&stamps, &delays);
+        self.mark("Error estimation", start);
+
+        self.estimate_sample_duration(&stamps, &delays);
+        self.mark("Sample duration estimation", start);
+
+        self.state.next_sample_time =
+            next_sample_time(&stamps, &delays, self.state.sample_duration);
+        self.nsts
+            .push_back((self.state.frames_written, self.state.next_sample_time));
+        let block_time = clock::diff(self.state.next_sample_time, self.startstamp);
+        self.mark("Next sample time estimation", start);
+
+        let frames = self.plan(block_time);
+        let mut buf = self.fill(start, frames);
+        if buf.is_empty() {
+            let pending = self
+                .timeline
+                .lock()
+                .unwrap()
+                .cue_after(block_time)
+                .is_some();
+            if !pending {
+                return Ok(Step::Finished);
+            }
+            // Over, but a command may still bring it back
+            buf = vec![0.; frames * self.num_channels];
+        }
+
+        let mut buf = self.render(buf, block_time);
+        if self.renderer.is_some() {
+            self.mark("Rendering", start);
+        }
+        self.fade_in(&mut buf);
+        self.fade_schedule(&mut buf, block_time);
+        self.ramp_gain(&mut buf);
+
+        return match self.sink.write(&buf) {
+            Ok(num) => {
+                assert_eq!(num, buf.len() / self.out_channels);
+                self.last_samples_pushed = num.try_into().unwrap();
+                Ok(Step::Playing)
+            }
+            Err(SinkError::Underrun) => {
+                self.state.underruns += 1;
+                self.sink.prepare()?;
+                Ok(Step::Underrun)
+            }
+            Err(err) => Err(err),
+        };
+    }
+
+    /// Time the sink could play from its buffer when the last period began.
+    pub fn time_budget(&self) -> Duration {
+        return Duration::from_secs_f64(self.first_delay as f64 * self.state.sample_duration);
+    }
+
+    /// Plays what is left in the sink.
+    pub fn finish(mut self) -> Result<S, SinkError> {
+        self.sink.drain()?;
+        return Ok(self.sink);
+    }
+}
@@ -0,0 +1,827 @@
+use super::*;
+use crate::decoder::encode::write_flac;
+use crate::resampler::Quality;
+use crate::servo::{ServoConfig, ServoState};
+use crate::sink::sim::{Recording, SimConfig, SimSink};
+use crate::timeline::{Action, Cue};
+use crate::wfs::Vec2;
+
+use std::sync::{Arc, Mutex};
+
+const FS: u32 = 48000;
+/// Length of one cycle of the test tone in samples
+const CYCLE: f64 = 64.;
+const AMPLITUDE: f64 = 0.5;
+
+/// Stereo file with a sine on the left and a cosine on the right channel,
+/// every frame tells its own position modulo `CYCLE`.
+fn testfile(seconds: u32, sample_format: hound::SampleFormat, bits: u16) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "piwfs-quadrature-{}-{:?}{}.wav",
+        seconds, sample_format, bits
+    ));
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: FS,
+        bits_per_sample: bits,
+        sample_format,
+    };
+    let scale = (1i64 << (bits - 1)) as f64;
+    let tmp = path.with_extension(format!("{:?}.tmp", std::thread::current().id()));
+    let mut writer = hound::WavWriter::create(&tmp, spec).unwrap();
+    for n in 0..seconds * FS {
+        let phase = 2. * std::f64::consts::PI * n as f64 / CYCLE;
+        for sample in &[AMPLITUDE * phase.sin(), AMPLITUDE * phase.cos()] {
+            match sample_format {
+                hound::SampleFormat::Float => writer.write_sample(*sample as f32),
+                hound::SampleFormat::Int => writer.write_sample((sample * scale).round() as i32),
+            }
+            .unwrap();
+        }
+    }
+    writer.finalize().unwrap();
+    std::fs::rename(&tmp, &path).unwrap();
+    return path.to_str().unwrap().to_string();
+}
+
+/// The same tone as 24 bit FLAC.
+fn flac_testfile(seconds: u32) -> String {
+    let path = std::env::temp_dir().join(format!("piwfs-quadrature-{}.flac", seconds));
+    let scale = (1 << 23) as f64;
+    let samples: Vec<i32> = (0..seconds * FS)
+        .flat_map(|n| {
+            let phase = 2. * std::f64::consts::PI * n as f64 / CYCLE;
+            vec![
+                (AMPLITUDE * scale * phase.sin()).round() as i32,
+                (AMPLITUDE * scale * phase.cos()).round() as i32,
+            ]
+        })
+        .collect();
+    let tmp = path.with_extension(format!("{:?}.tmp", std::thread::current().id()));
+    write_flac(&tmp, 2, FS, 24, &samples);
+    std::fs::rename(&tmp, &path).unwrap();
+    return path.to_str().unwrap().to_string();
+}
+
+const START: f64 = 0.5;
+
+fn session(config: &SimConfig, seconds: u32) -> Session {
+    let startat = config.origin + Duration::from_secs_f64(START);
+    return Session {
+        startat: startat.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64,
+        testfile: testfile(seconds, hound::SampleFormat::Int, 16),
+        quality: Quality::Medium,
+        desync_avg: 100,
+        estimation_avg: 100,
+        source: None,
+        trajectory: None,
+        reference: Default::default(),
+        predelay: 0.,
+        geometry: None,
+        looping: false,
+    };
+}
+
+fn player_config() -> PlayerConfig {
+    return PlayerConfig {
+        is_spinning: false,
+        ..PlayerConfig::default()
+    };
+}
+
+fn layout() -> Layout {
+    return Layout {
+        device: "hw:0".to_string(),
+        speakers: Vec::new(),
+        alias_freq: None,
+        channels: None,
+    };
+}
+
+fn player(
+    config: SimConfig,
+    player_config: PlayerConfig,
+    session: &Session,
+) -> SyncedPlayer<SimSink> {
+    return SyncedPlayer::new(player_config, session, &layout(), |_, channels, rate| {
+        Ok(SimSink::new(config, channels, rate))
+    })
+    .unwrap();
+}
+
+fn simulate(
+    config: SimConfig,
+    player_config: PlayerConfig,
+    session: &Session,
+) -> Arc<Mutex<Recording>> {
+    return simulate_player(player(config, player_config, session));
+}
+
+fn simulate_player(mut player: SyncedPlayer<SimSink>) -> Arc<Mutex<Recording>> {
+    let recording = player.sink().recording();
+    while player.step().unwrap() != Step::Finished {}
+    player.finish().unwrap();
+    return recording;
+}
+
+/// Difference in samples between the file position played `time` seconds
+/// after the origin and the one that should be played then.
+fn sync_error(recording: &Recording, time: f64) -> Option<f64> {
+    let frame = recording.frame_at(time)?;
+    let left = recording.sample(frame as usize, 0) as f64;
+    let right = recording.sample(frame as usize, 1) as f64;
+    if left.hypot(right) < AMPLITUDE / 2. {
+        return None;
+    }
+    let position = left.atan2(right) / (2. * std::f64::consts::PI) * CYCLE + frame.fract();
+    let error = position - (time - START) * FS as f64;
+    return Some(error - (error / CYCLE).round() * CYCLE);
+}
+
+fn config(ppm: f64, seed: u64) -> SimConfig {
+    let mut config = SimConfig::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+    config.ppm = ppm;
+    config.seed = seed;
+    return config;
+}
+
+/// Largest sync error between `from` seconds after the start and the end.
+fn max_error(recording: &Recording, from: f64, seconds: u32) -> f64 {
+    let mut max: f64 = 0.;
+    let mut time = START + from;
+    while time < START + seconds as f64 - 0.1 {
+        let error = sync_error(recording, time).expect("Nothing played");
+        max = max.max(error.abs());
+        time += 0.01;
+    }
+    return max;
+}
+
+#[test]
+fn test_converges() {
+    for ppm in &[-100., 0., 100.] {
+        let config = config(*ppm, 1);
+        let session = session(&config, 6);
+        let recording = simulate(config, player_config(), &session);
+        let error = max_error(&recording.lock().unwrap(), 1., 6);
+        assert!(error < 1., "{} ppm: {} samples off", ppm, error);
+    }
+}
+
+#[test]
+fn test_drifts_without_correction() {
+    let config = config(100., 1);
+    let session = session(&config, 6);
+    let player_config = PlayerConfig {
+        is_correction: false,
+        ..player_config()
+    };
+    let recording = simulate(config, player_config, &session);
+    let recording = recording.lock().unwrap();
+    let early = sync_error(&recording, START + 1.).unwrap();
+    let late = sync_error(&recording, START + 5.).unwrap();
+    // 100 ppm of 48 kHz over 4 seconds
+    assert!((late - early - 19.2).abs() < 0.5, "{} -> {}", early, late);
+}
+
+#[test]
+fn test_converges_with_noise() {
+    let mut config = config(60., 2);
+    config.drift = 2.;
+    config.jitter = 200e-6;
+    config.tstamp_noise = 20e-6;
+    config.hiccup_chance = 0.01;
+    config.hiccup = 0.01;
+    let session = session(&config, 8);
+    let recording = simulate(config, player_config(), &session);
+    let error = max_error(&recording.lock().unwrap(), 2., 8);
+    assert!(error < 2., "{} samples off", error);
+}
+
+#[test]
+fn test_slaves_in_sync() {
+    let handles: Vec<_> = [(-80., 3), (10., 4), (120., 5)]
+        .iter()
+        .map(|&(ppm, seed)| {
+            std::thread::spawn(move || {
+                let mut config = config(ppm, seed);
+                config.jitter = 100e-6;
+                config.tstamp_noise = 10e-6;
+                let session = session(&config, 6);
+                simulate(config, player_config(), &session)
+            })
+        })
+        .collect();
+    let recordings: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    let recordings: Vec<_> = recordings.iter().map(|r| r.lock().unwrap()).collect();
+    let mut time = START + 1.;
+    while time < START + 5.9 {
+        let errors: Vec<f64> = recordings
+            .iter()
+            .map(|recording| sync_error(recording, time).unwrap())
+            .collect();
+        let spread = errors.iter().cloned().fold(f64::MIN, f64::max)
+            - errors.iter().cloned().fold(f64::MAX, f64::min);
+        assert!(spread < 1.5, "At {} s: {:?}", time, errors);
+        time += 0.01;
+    }
+}
+
+#[test]
+fn test_converges_high_resolution() {
+    let formats = [
+        (hound::SampleFormat::Int, 24),
+        (hound::SampleFormat::Float, 32),
+    ];
+    for &(sample_format, bits) in formats.iter() {
+        let config = config(50., 6);
+        let session = Session {
+            testfile: testfile(4, sample_format, bits),
+            ..session(&config, 4)
+        };
+        let recording = simulate(config, player_config(), &session);
+        let error = max_error(&recording.lock().unwrap(), 1., 4);
+        assert!(
+            error < 1.,
+            "{:?} {}: {} samples off",
+            sample_format,
+            bits,
+            error
+        );
+    }
+}
+
+#[test]
+fn test_timing_estimates() {
+    let origin = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let stamps: Vec<_> = (0..4)
+        .map(|n| origin + Duration::from_micros(100 * n))
+        .collect();
+    let delays = [4096, 4091, 4086, 4081];
+    assert!((measure_sample_duration(&stamps, &delays, 1.) - 20e-6).abs() < 1e-12);
+    let backwards = [4096, 4101, 4096, 4091];
+    assert!((measure_sample_duration(&stamps, &backwards, 20e-6) - 20e-6).abs() < 1e-12);
+
+    let next = next_sample_time(&stamps, &delays, 20e-6);
+    let expected = origin + Duration::from_micros(150) + Duration::from_secs_f64(20e-6 * 4088.5);
+    assert!(clock::diff(next, expected).abs() < 1e-8);
+}
+
+#[test]
+fn test_state() {
+    let config = config(0., 1);
+    let session = session(&config, 1);
+    let mut player = player(config, player_config(), &session);
+    assert_eq!(player.channels(), 2);
+    assert_eq!(player.step().unwrap(), Step::Playing);
+    assert!(player.state().desync < -0.4 * FS as f64);
+    assert_eq!(player.state().diff, 0.);
+    while player.state().desync < 0. {
+        assert_eq!(player.step().unwrap(), Step::Playing);
+    }
+    let written = player.state().frames_written;
+    player.step().unwrap();
+    assert_eq!(player.state().frames_written, written + 1024);
+    assert_eq!(player.state().underruns, 0);
+    assert!(player
+        .state()
+        .timings
+        .iter()
+        .any(|(stage, _)| *stage == "Interpolation"));
+}
+
+#[test]
+fn test_converges_flac() {
+    let config = config(-70., 7);
+    let session = Session {
+        testfile: flac_testfile(4),
+        ..session(&config, 4)
+    };
+    let recording = simulate(config, player_config(), &session);
+    let error = max_error(&recording.lock().unwrap(), 1., 4);
+    assert!(error < 1., "{} samples off", error);
+}
+
+fn asrc_config() -> PlayerConfig {
+    return PlayerConfig {
+        is_asrc: true,
+        ..player_config()
+    };
+}
+
+#[test]
+fn test_converges_asrc() {
+    for &(ppm, seed) in &[(-100., 1), (0., 2), (100., 3)] {
+        let mut config = config(ppm, seed);
+        config.jitter = 100e-6;
+        config.tstamp_noise = 10e-6;
+        let session = session(&config, 6);
+        let mut player = player(config, asrc_config(), &session);
+        let recording = player.sink().recording();
+        while player.step().unwrap() != Step::Finished {}
+        assert_eq!(player.state().seeks, 0, "{} ppm", ppm);
+        // A faster card reads the file slower
+        assert!((player.state().ratio - 1. + ppm * 1e-6).abs() < 20e-6);
+        player.finish().unwrap();
+        let error = max_error(&recording.lock().unwrap(), 1.5, 6);
+        assert!(error < 1., "{} ppm: {} samples off", ppm, error);
+    }
+}
+
+#[test]
+fn test_asrc_is_continuous() {
+    // Largest change of the sync error between consecutive milliseconds
+    let roughness = |player_config| {
+        let config = config(100., 4);
+        let session = session(&config, 4);
+        let recording = simulate(config, player_config, &session);
+        let recording = recording.lock().unwrap();
+        let mut max: f64 = 0.;
+        let mut time = START + 1.;
+        let mut last = sync_error(&recording, time).unwrap();
+        while time < START + 3.9 {
+            time += 0.001;
+            let error = sync_error(&recording, time).unwrap();
+            max = max.max((error - last).abs());
+            last = error;
+        }
+        return max;
+    };
+    let seeking = roughness(player_config());
+    let asrc = roughness(asrc_config());
+    assert!(seeking > 0.08, "{} samples", seeking);
+    assert!(asrc < 0.03, "{} samples", asrc);
+}
+
+#[test]
+fn test_asrc_seeks_large_offsets() {
+    let config = config(0., 5);
+    let mut session = session(&config, 4);
+    // Joins a second late
+    session.startat -= 1_000_000_000;
+    let mut player = player(config, asrc_config(), &session);
+    let recording = player.sink().recording();
+    while player.step().unwrap() != Step::Finished {}
+    assert_eq!(player.state().seeks, 1);
+    player.finish().unwrap();
+    let recording = recording.lock().unwrap();
+    // A second is a whole number of tone cycles
+    let mut time = START + 0.2;
+    while time < START + 2.9 {
+        let error = sync_error(&recording, time).unwrap();
+        assert!(error.abs() < 1., "At {} s: {} samples off", time, error);
+        time += 0.01;
+    }
+}
+
+#[test]
+fn test_converges_servo() {
+    for &is_asrc in &[false, true] {
+        for &(ppm, seed) in &[(-100., 1), (80., 2)] {
+            let mut config = config(ppm, seed);
+            config.jitter = 100e-6;
+            config.tstamp_noise = 10e-6;
+            let session = session(&config, 8);
+            let player_config = PlayerConfig {
+                is_asrc,
+                servo: Some(ServoConfig::default()),
+                ..player_config()
+            };
+            let mut player = player(config, player_config, &session);
+            let recording = player.sink().recording();
+            while player.step().unwrap() != Step::Finished {}
+            let (state, _, drift) = player.state().servo.unwrap();
+            assert_eq!(state, ServoState::Locked);
+            // A faster card reads the file slower
+            assert!((drift + ppm).abs() < 10., "{} ppm: {} drift", ppm, drift);
+            player.finish().unwrap();
+            let error = max_error(&recording.lock().unwrap(), 3., 8);
+            assert!(
+                error < 1.5,
+                "{} ppm, ASRC {}: {} samples off",
+                ppm,
+                is_asrc,
+                error
+            );
+        }
+    }
+}
+
+#[test]
+fn test_servo_jumps_large_offsets() {
+    let config = config(30., 5);
+    let mut session = session(&config, 4);
+    session.startat -= 1_000_000_000;
+    let player_config = PlayerConfig {
+        is_asrc: true,
+        servo: Some(ServoConfig::default()),
+        ..player_config()
+    };
+    let recording = simulate(config, player_config, &session);
+    let error = max_error(&recording.lock().unwrap(), 1., 3);
+    assert!(error < 1., "{} samples off", error);
+}
+
+#[test]
+fn test_converges_kalman() {
+    for &is_asrc in &[false, true] {
+        for &(ppm, seed) in &[(-100., 1), (150., 2)] {
+            let mut config = config(ppm, seed);
+            config.jitter = 100e-6;
+            config.tstamp_noise = 10e-6;
+            let session = session(&config, 6);
+            let player_config = PlayerConfig {
+                is_asrc,
+                kalman: Some(KalmanConfig::default()),
+                ..player_config()
+            };
+            let mut player = player(config, player_config, &session);
+            let recording = player.sink().recording();
+            while player.step().unwrap() != Step::Finished {}
+            // Without spinning only the Kalman filter can tell the frame duration
+            let measured = 1. / (player.state().sample_duration * FS as f64) - 1.;
+            assert!(
+                (measured * 1e6 - ppm).abs() < 2.,
+                "{} ppm measured as {}",
+                ppm,
+                measured * 1e6
+            );
+            player.finish().unwrap();
+            let error = max_error(&recording.lock().unwrap(), 1., 6);
+            assert!(
+                error < 1.,
+                "{} ppm, ASRC {}: {} samples off",
+                ppm,
+                is_asrc,
+                error
+            );
+        }
+    }
+}
+
+struct FixedOffset(f64);
+
+impl TimeSource for FixedOffset {
+    fn offset(&self) -> Option<f64> {
+        return Some(self.0);
+    }
+
+    fn name(&self) -> String {
+        return "fixed".to_string();
+    }
+}
+
+#[test]
+fn test_follows_time_source() {
+    // Whole cycles and a quarter of one ahead of the local clock
+    let offset = (75. * CYCLE + 16.) / FS as f64;
+    let config = config(50., 1);
+    let session = session(&config, 6);
+    let mut player = player(config, player_config(), &session);
+    player.set_time_source(Arc::new(FixedOffset(offset)));
+    let recording = player.sink().recording();
+    while player.step().unwrap() != Step::Finished {}
+    assert_eq!(player.state().time_offset, Some(offset));
+    player.finish().unwrap();
+    let recording = recording.lock().unwrap();
+    // The session starts earlier on the local clock
+    assert!(sync_error(&recording, START - offset / 2.).is_some());
+    let mut time = START + 1.;
+    while time < START + 5. {
+        let error = sync_error(&recording, time).unwrap();
+        assert!((error - 16.).abs() < 1., "{} samples off", error);
+        time += 0.01;
+    }
+}
+
+#[test]
+fn test_mute_keeps_schedule() {
+    let config = config(50., 1);
+    let session = session(&config, 6);
+    let mut player = player(config, player_config(), &session);
+    let recording = player.sink().recording();
+    loop {
+        let time = clock::diff(player.state().next_sample_time, session.startstamp());
+        player.set_muted((2. ..3.).contains(&time));
+        if player.step().unwrap() == Step::Finished {
+            break;
+        }
+    }
+    player.finish().unwrap();
+    let recording = recording.lock().unwrap();
+    assert!(sync_error(&recording, START + 2.5).is_none());
+    // Ramped down and back up rather than cut
+    for &edge in &[2., 3.] {
+        let first = recording.frame_at(START + edge).unwrap() as usize;
+        let gains: Vec<f64> = (first..first + FS as usize / 10)
+            .map(|frame| {
+                let left = recording.sample(frame, 0) as f64;
+                let right = recording.sample(frame, 1) as f64;
+                left.hypot(right) / AMPLITUDE
+            })
+            .collect();
+        let jump = gains.windows(2).map(|pair| (pair[1] - pair[0]).abs());
+        assert!(jump.fold(0., f64::max) < 0.01);
+        assert!(gains.iter().any(|&gain| gain > 0.2 && gain < 0.8));
+    }
+    // Playing on where the session is by then
+    let mut time = START + 3.5;
+    while time < START + 5.5 {
+        let error = sync_error(&recording, time).unwrap();
+        assert!(error.abs() < 1., "{} samples off", error);
+        time += 0.01;
+    }
+}
+
+/// Offset jumping by `step` seconds after `calls` queries, with a claimed
+/// error of `error` seconds.
+struct SteppingOffset {
+    step: f64,
+    calls: usize,
+    error: Option<f64>,
+    called: Mutex<usize>,
+}
+
+impl TimeSource for SteppingOffset {
+    fn offset(&self) -> Option<f64> {
+        let mut called = self.called.lock().unwrap();
+        *called += 1;
+        return Some(if *called > self.calls { self.step } else { 0. });
+    }
+
+    fn error(&self) -> Option<f64> {
+        return self.error;
+    }
+
+    fn name(&self) -> String {
+        return "stepping".to_string();
+    }
+}
+
+#[test]
+fn test_time_source_error_widens_tolerance() {
+    // A 3 ms step of the offset is beyond the step threshold of the servo
+    let seeks = |error: Option<f64>| {
+        let config = config(0., 6);
+        let session = session(&config, 4);
+        let player_config = PlayerConfig {
+            is_asrc: true,
+            servo: Some(ServoConfig::default()),
+            ..player_config()
+        };
+        let mut player = player(config, player_config, &session);
+        player.set_time_source(Arc::new(SteppingOffset {
+            step: 3e-3,
+            calls: 200,
+            error,
+            called: Mutex::new(0),
+        }));
+        while player.step().unwrap() != Step::Finished {}
+        assert_eq!(player.state().time_error, error);
+        return player.state().seeks;
+    };
+    assert_eq!(seeks(None), 1);
+    // Within the claimed error the step is slewed
+    assert_eq!(seeks(Some(5e-3)), 0);
+}
+
+#[test]
+fn test_plays_mapped_channels() {
+    let config = config(0., 7);
+    let session = session(&config, 3);
+    let layout = Layout {
+        channels: Some("1,2,1:3*0.5,2:3*0.5".parse().unwrap()),
+        ..layout()
+    };
+    let mut player = SyncedPlayer::new(player_config(), &session, &layout, |_, channels, rate| {
+        Ok(SimSink::new(config, channels, rate))
+    })
+    .unwrap();
+    assert_eq!(player.channels(), 3);
+    let recording = player.sink().recording();
+    while player.step().unwrap() != Step::Finished {}
+    player.finish().unwrap();
+    let recording = recording.lock().unwrap();
+    assert_eq!(recording.channels, 3);
+    let error = max_error(&recording, 1., 3);
+    assert!(error < 1., "{} samples off", error);
+    let frame = recording.frame_at(START + 1.).unwrap() as usize;
+    let mixed = (recording.sample(frame, 0) + recording.sample(frame, 1)) / 2.;
+    assert!((recording.sample(frame, 2) - mixed).abs() < 1e-6);
+}
+
+#[test]
+fn test_loops_seamlessly() {
+    let config = config(100., 8);
+    // A whole number of cycles, so the tone goes on across the loop point
+    let session = Session {
+        looping: true,
+        ..session(&config, 1)
+    };
+    let mut player = player(config, player_config(), &session);
+    let recording = player.sink().recording();
+    while recording.lock().unwrap().frame_at(START + 4.).is_none() {
+        assert_ne!(player.step().unwrap(), Step::Finished);
+    }
+    player.finish().unwrap();
+    let error = max_error(&recording.lock().unwrap(), 1., 4);
+    assert!(error < 1., "{} samples off", error);
+}
+
+#[test]
+fn test_joins_late() {
+    let config = config(100., 9);
+    // A whole number of cycles late, so the tone is where it would be
+    // without the delay
+    let session = Session {
+        startat: session(&config, 6).startat - 2_000_000_000,
+        ..session(&config, 6)
+    };
+    let mut player = player(config, player_config(), &session);
+    let recording = player.sink().recording();
+    player.step().unwrap();
+    assert_eq!(player.state().seeks, 1);
+    while player.step().unwrap() != Step::Finished {}
+    player.finish().unwrap();
+    let recording = recording.lock().unwrap();
+    // Faded in from silence, synchronized right after
+    assert_eq!(recording.sample(0, 1), 0.);
+    let error = max_error(&recording, -0.4, 3);
+    assert!(error < 1., "{} samples off", error);
+}
+
+/// Timeline of `session` with `cues` at seconds after its start.
+fn timeline(session: &Session, cues: &[(f64, Action)]) -> Arc<Mutex<Timeline>> {
+    let mut timeline = Timeline::new(session.startat);
+    for &(seconds, action) in cues {
+        let at = session.startat + (seconds * 1e9) as u64;
+        timeline.add(Cue { at, action }).unwrap();
+    }
+    return Arc::new(Mutex::new(timeline));
+}
+
+#[test]
+fn test_pause_and_resume() {
+    let config = config(100., 10);
+    let session = session(&config, 5);
+    let mut player = player(config, player_config(), &session);
+    // Paused for a whole number of cycles, so the tone goes on in phase
+    player.set_timeline(timeline(
+        &session,
+        &[(1., Action::Pause), (2., Action::Resume)],
+    ));
+    let recording = simulate_player(player);
+    let recording = recording.lock().unwrap();
+    let silent = recording.frame_at(START + 1.5).unwrap() as usize;
+    assert_eq!(recording.sample(silent, 0), 0.);
+    // The file goes on after the pause and ends a second later
+    let error = max_error(&recording, 2.1, 6);
+    assert!(error < 1., "{} samples off", error);
+    assert!(max_error(&recording, 0.5, 1) < 1.);
+}
+
+#[test]
+fn test_seek() {
+    for player_config in &[player_config(), asrc_config()] {
+        let config = config(-100., 11);
+        let session = session(&config, 5);
+        let mut player = player(config, *player_config, &session);
+        // A quarter cycle ahead of where it would be without the seek
+        let position = 3. + CYCLE / 4. / FS as f64;
+        player.set_timeline(timeline(&session, &[(1., Action::Seek(position))]));
+        let recording = simulate_player(player);
+        let recording = recording.lock().unwrap();
+        let mut time = START + 1.1;
+        while time < START + 2.9 {
+            let error = sync_error(&recording, time).unwrap() - CYCLE / 4.;
+            assert!(error.abs() < 1., "{} samples off at {} s", error, time);
+            time += 0.01;
+        }
+        // Faded out right before the seek
+        let before = recording.frame_at(START + 1. - 1. / FS as f64).unwrap() as usize;
+        assert!(recording.sample(before, 0).abs() < 0.01);
+    }
+}
+
+/// Gain of the tone played `time` seconds after the origin.
+fn gain_at(recording: &Recording, time: f64) -> f64 {
+    let frame = recording.frame_at(time).unwrap() as usize;
+    let left = recording.sample(frame, 0) as f64;
+    let right = recording.sample(frame, 1) as f64;
+    return left.hypot(right) / AMPLITUDE;
+}
+
+#[test]
+fn test_fades_in_and_out() {
+    let config = config(50., 12);
+    let session = session(&config, 6);
+    let player_config = PlayerConfig {
+        start_fade: 0.1,
+        fade_curve: Curve::RaisedCosine,
+        ..player_config()
+    };
+    let mut player = player(config, player_config, &session);
+    let recording = player.sink().recording();
+    while recording.lock().unwrap().frame_at(START + 2.).is_none() {
+        player.step().unwrap();
+    }
+    player.fade_out();
+    while player.step().unwrap() != Step::Finished {}
+    player.finish().unwrap();
+    let recording = recording.lock().unwrap();
+    assert!(gain_at(&recording, START + 0.001) < 0.01);
+    assert!((gain_at(&recording, START + 0.05) - 0.5).abs() < 0.01);
+    assert!((gain_at(&recording, START + 0.2) - 1.).abs() < 1e-3);
+    // Stopped within the fade and what is queued, silent at the very end
+    let frames = recording.samples.len() / recording.channels;
+    assert!(frames < ((START + 2.5) * FS as f64) as usize);
+    let end = &recording.samples[recording.samples.len() - recording.channels..];
+    assert!(end.iter().all(|&sample| sample == 0.));
+}
+
+#[test]
+fn test_level_ramps() {
+    let config = config(-50., 13);
+    let session = session(&config, 4);
+    let mut player = player(config, player_config(), &session);
+    let level = Arc::new(Level::new(-3.));
+    player.set_level(Arc::clone(&level));
+    let recording = player.sink().recording();
+    while recording.lock().unwrap().frame_at(START + 1.).is_none() {
+        player.step().unwrap();
+    }
+    level.set(-20., 0.5);
+    while player.step().unwrap() != Step::Finished {}
+    player.finish().unwrap();
+    let recording = recording.lock().unwrap();
+    assert!((gain_at(&recording, START + 0.5) - 0.708).abs() < 1e-3);
+    let mut last = gain_at(&recording, START + 1.);
+    let mut time = START + 1.01;
+    while time < START + 2. {
+        let gain = gain_at(&recording, time);
+        assert!(gain <= last + 1e-4, "Gain rises at {} s", time);
+        assert!(last - gain < 0.02, "Gain jumps at {} s", time);
+        last = gain;
+        time += 0.01;
+    }
+    assert!((gain_at(&recording, START + 2.) - 0.1).abs() < 1e-3);
+    // Synchronized while attenuated
+    let error = max_error(&recording, 0.5, 1);
+    assert!(error < 1., "{} samples off", error);
+}
+
+#[test]
+fn test_fades_loop_point() {
+    let config = config(0., 14);
+    let session = Session {
+        looping: true,
+        ..session(&config, 1)
+    };
+    let player_config = PlayerConfig {
+        loop_fade: 0.01,
+        ..player_config()
+    };
+    let mut player = player(config, player_config, &session);
+    let recording = player.sink().recording();
+    while recording.lock().unwrap().frame_at(START + 3.).is_none() {
+        player.step().unwrap();
+    }
+    player.finish().unwrap();
+    let recording = recording.lock().unwrap();
+    assert!(gain_at(&recording, START + 2.) < 0.01);
+    assert!((gain_at(&recording, START + 2.005) - 0.5).abs() < 0.01);
+    assert!((gain_at(&recording, START + 1.995) - 0.5).abs() < 0.01);
+    assert!((gain_at(&recording, START + 2.5) - 1.).abs() < 1e-3);
+}
+
+#[test]
+fn test_trajectory_follows_timeline() {
+    let config = config(0., 15);
+    // Moving away from the loudspeaker, quieter the further
+    let session = Session {
+        trajectory: Some("0:0,-1;4:0,-3".parse().unwrap()),
+        reference: Vec2::new(0., 2.),
+        ..session(&config, 4)
+    };
+    let layout = Layout {
+        speakers: vec![Some("0,0,90".parse().unwrap())],
+        channels: Some("1".parse().unwrap()),
+        ..layout()
+    };
+    let mut player = SyncedPlayer::new(player_config(), &session, &layout, |_, channels, rate| {
+        Ok(SimSink::new(config, channels, rate))
+    })
+    .unwrap();
+    player.set_timeline(timeline(&session, &[(2., Action::Seek(0.5))]));
+    let recording = simulate_player(player);
+    let recording = recording.lock().unwrap();
+    let peak = |time: f64| {
+        let first = recording.frame_at(START + time).unwrap() as usize;
+        return (first..first + CYCLE as usize)
+            .map(|frame| recording.sample(frame, 0).abs())
+            .fold(0., f32::max);
+    };
+    // Back where it was a second into the file
+    assert!((peak(2.5) - peak(1.)).abs() < 0.01 * peak(1.));
+    assert!(peak(1.9) < 0.9 * peak(1.));
+}
@@ -0,0 +1,221 @@
+//! Control protocol spoken between the master and the slaves.
+//!
+//! A message is a header line `PIWFS/<version> <COMMAND>` followed by any
+//! number of `<key>: <value>` lines and terminated by an empty line. Values are
+//! escaped so they never span lines: `\\` encodes a backslash and `\n` a
+//! newline. A peer answers every request with exactly one message, either
+//! `ACK` or `NAK` with a `reason` field.
+//!
+//! The same messages are accepted over TCP (any number of requests per
+//! connection) and UDP (one request per datagram, the reply is sent back to
+//! the source address). Slaves started with `--listen` understand:
+//!
+//! | Command   | Fields                               | Reply       |
+//! |-----------|--------------------------------------|-------------|
+//! | `SESSION` | `startat` and all fields of `LOAD`   | `ACK`/`NAK` |
+//! | `LOAD`    | `testfile` and the session options   | `ACK`/`NAK` |
+//! | `ARM`     | `startat`                            | `ACK`/`NAK` |
+//! | `STOP`    |                                      | `ACK`       |
+//! | `PAUSE`   | `at`                                 | `ACK`/`NAK` |
+//! | `RESUME`  | `at`                                 | `ACK`/`NAK` |
+//! | `SEEK`    | `at`, `position`                     | `ACK`/`NAK` |
+//! | `GAIN`    | `gain`, `ramp`                       | `ACK`/`NAK` |
+//! | `STATUS`  |                                      | `STATUS`    |
+//!
+//! `SESSION` is a `LOAD` immediately followed by an `ARM`. `startat` is given
+//! in nanoseconds since the UNIX epoch. The session options `quality`,
+//! `desync-avg`, `estimation-avg`, `source`, `trajectory`, `reference` and
+//! `predelay` are optional and written like the corresponding command line
+//! options, except for `predelay` which is given in seconds. The optional
+//! `geometry` carries the contents of the array geometry file, `loop` is
+//! `true` to play the file over and over. `PAUSE`, `RESUME` and `SEEK` act
+//! on the session being played at `at`, given like `startat`, which may not
+//! be before the session start or an earlier command. `position` is the
+//! point of the file to go on from in seconds. `GAIN` sets the output level
+//! of the slave to `gain` dB, reached along a ramp of the optional `ramp`
+//! seconds, immediately or whenever the next session starts. A `STATUS`
+//! reply carries `state` (one of `idle`, `loaded`, `armed`, `playing`,
+//! `paused`), `gain` and, when a file is loaded, `testfile` and `startat`.
+
+#[cfg(test)]
+mod tests;
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+pub const VERSION: u32 = 1;
+pub const DEFAULT_PORT: u16 = 7457;
+/// Pause after a socket failed for another reason than a timeout, so one
+/// that keeps failing doesn't spin
+pub const BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub command: String,
+    fields: Vec<(String, String)>,
+}
+
+fn escape(value: &str) -> String {
+    return value.replace('\\', "\\\\").replace('\n', "\\n");
+}
+
+fn unescape(value: &str) -> io::Result<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            out.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('n') => out.push('\n'),
+            _ => return Err(invalid_data("Invalid escape sequence")),
+        }
+    }
+    return Ok(out);
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    return io::Error::new(ErrorKind::InvalidData, msg.to_string());
+}
+
+impl Message {
+    pub fn new(command: &str) -> Self {
+        return Message {
+            command: command.to_string(),
+            fields: Vec::new(),
+        };
+    }
+
+    pub fn nak(reason: &str) -> Self {
+        return Message::new("NAK").with("reason", reason);
+    }
+
+    pub fn with<T: ToString>(mut self, key: &str, value: T) -> Self {
+        self.fields.push((key.to_string(), value.to_string()));
+        return self;
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        return self
+            .fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str());
+    }
+
+    /// Parses an optional field, a present but malformed field is an error.
+    pub fn parse<T: FromStr>(&self, key: &str) -> Result<Option<T>, String> {
+        return match self.get(key) {
+            Some(value) => value
+                .parse::<T>()
+                .map(Some)
+                .map_err(|_| format!("Malformed {}: {}", key, value)),
+            None => Ok(None),
+        };
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut out = format!("PIWFS/{} {}\n", VERSION, self.command);
+        for (key, value) in &self.fields {
+            out += &format!("{}: {}\n", key, escape(value));
+        }
+        out.push('\n');
+        writer.write_all(out.as_bytes())?;
+        return writer.flush();
+    }
+
+    /// Reads a single message, returns `None` if the stream ended cleanly
+    /// before a header line.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Option<Message>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end_matches(&['\r', '\n'][..]);
+        let (version, command) = header
+            .split_once(' ')
+            .ok_or_else(|| invalid_data("Malformed header"))?;
+        let version = version
+            .strip_prefix("PIWFS/")
+            .and_then(|v| v.parse::<u32>().ok())
+            .ok_or_else(|| invalid_data("Not a PiWFS message"))?;
+        if version != VERSION {
+            return Err(invalid_data(&format!(
+                "Unsupported protocol version {} (expected {})",
+                version, VERSION
+            )));
+        }
+        let mut msg = Message::new(command);
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let field = line.trim_end_matches(&['\r', '\n'][..]);
+            if field.is_empty() {
+                break;
+            }
+            let (key, value) = field
+                .split_once(": ")
+                .ok_or_else(|| invalid_data("Malformed field"))?;
+            msg.fields.push((key.to_string(), unescape(value)?));
+        }
+        return Ok(Some(msg));
+    }
+}
+
+/// Resolves `host` or `host:port`, using `DEFAULT_PORT` when none is given.
+pub fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    let mut addrs = match addr.to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => (addr, DEFAULT_PORT).to_socket_addrs()?,
+    };
+    return addrs
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "Address did not resolve"));
+}
+
+/// Takes `err` of `what`, which is printed the first time `warned` is
+/// clear, then pauses for `BACKOFF`. Timeouts return right away.
+pub fn back_off(what: &str, err: &io::Error, warned: &mut bool) {
+    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut {
+        return;
+    }
+    if !*warned {
+        println!("[WRN] {} failed: {}", what, err);
+        *warned = true;
+    }
+    thread::sleep(BACKOFF);
+}
+
+/// Receives a datagram on `socket` and its sender, `None` after backing off
+/// from an error like `back_off`.
+pub fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    what: &str,
+    warned: &mut bool,
+) -> Option<(usize, SocketAddr)> {
+    return match socket.recv_from(buf) {
+        Ok(res) => Some(res),
+        Err(err) => {
+            back_off(what, &err, warned);
+            None
+        }
+    };
+}
+
+/// Sends a request over a fresh TCP connection and waits for the reply.
+pub fn request(addr: &SocketAddr, msg: &Message, timeout: Duration) -> io::Result<Message> {
+    let mut stream = TcpStream::connect_timeout(addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    msg.write_to(&mut stream)?;
+    let mut reader = BufReader::new(stream);
+    return Message::read_from(&mut reader)?.ok_or_else(|| ErrorKind::UnexpectedEof.into());
+}
@@ -0,0 +1,81 @@
+use super::*;
+use std::io::Cursor;
+use std::net::TcpListener;
+
+#[test]
+fn test_roundtrip() {
+    let msg = Message::new("SESSION")
+        .with("startat", 1_600_000_000_000_000_000u64)
+        .with("testfile", "/mnt/share/my file.wav")
+        .with("weird", "back\\slash\nnew: line");
+    let mut buf = Vec::new();
+    msg.write_to(&mut buf).unwrap();
+    assert_eq!(buf.iter().filter(|&&b| b == b'\n').count(), 5);
+    let mut cursor = Cursor::new(buf);
+    let read = Message::read_from(&mut cursor).unwrap().unwrap();
+    assert_eq!(read, msg);
+    assert_eq!(read.get("weird"), Some("back\\slash\nnew: line"));
+    assert_eq!(read.get("missing"), None);
+    assert!(Message::read_from(&mut cursor).unwrap().is_none());
+}
+#[test]
+fn test_reject_version() {
+    let mut cursor = Cursor::new(b"PIWFS/999 ACK\n\n".to_vec());
+    assert!(Message::read_from(&mut cursor).is_err());
+    let mut cursor = Cursor::new(b"HTTP/1.1 GET\n\n".to_vec());
+    assert!(Message::read_from(&mut cursor).is_err());
+    let mut cursor = Cursor::new(b"PIWFS/1 ACK\nno separator\n\n".to_vec());
+    assert!(Message::read_from(&mut cursor).is_err());
+}
+#[test]
+fn test_request() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let msg = Message::read_from(&mut reader).unwrap().unwrap();
+        let mut stream = stream;
+        Message::new("ACK")
+            .with("echo", msg.command)
+            .write_to(&mut stream)
+            .unwrap();
+    });
+    let reply = request(&addr, &Message::new("PING"), Duration::from_secs(1)).unwrap();
+    server.join().unwrap();
+    assert_eq!(reply.command, "ACK");
+    assert_eq!(reply.get("echo"), Some("PING"));
+}
+#[test]
+fn test_resolve() {
+    assert_eq!(
+        resolve("127.0.0.1").unwrap(),
+        SocketAddr::from(([127, 0, 0, 1], DEFAULT_PORT))
+    );
+    assert_eq!(
+        resolve("127.0.0.1:1234").unwrap(),
+        SocketAddr::from(([127, 0, 0, 1], 1234))
+    );
+}
+
+#[test]
+fn test_recv_from() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .unwrap();
+    let mut buf = [0; 16];
+    let mut warned = false;
+    assert_eq!(recv_from(&socket, &mut buf, "Test", &mut warned), None);
+    assert!(!warned, "Timeouts are not failures");
+    socket
+        .send_to(b"ping", socket.local_addr().unwrap())
+        .unwrap();
+    let received = recv_from(&socket, &mut buf, "Test", &mut warned);
+    assert_eq!(received, Some((4, socket.local_addr().unwrap())));
+
+    let start = std::time::Instant::now();
+    back_off("Test", &ErrorKind::ConnectionRefused.into(), &mut warned);
+    assert!(warned);
+    assert!(start.elapsed() >= BACKOFF);
+}
@@ -0,0 +1,341 @@
+//! Precision Time Protocol (IEEE 1588-2008) over UDP/IPv4. `slave` is an
+//! ordinary clock estimating the offset of the local clock from a
+//! grandmaster with software timestamps, it doesn't adjust the system clock
+//! but acts as a `TimeSource` for the player, so `ptp4l` isn't required.
+//! `grandmaster` is the master clock of `piwfs master --ptp`, serving the
+//! system clock to `slave` as well as to `ptp4l`.
+//!
+//! Only the messages of the end-to-end delay mechanism are supported. Event
+//! messages (Sync, Delay_Req) go to port 319, general ones (Follow_Up,
+//! Delay_Resp, Announce) to port 320 of the multicast group 224.0.1.129.
+//! `management` speaks the management messages `ptp4l` answers on its Unix
+//! domain socket, for slaves whose system clock is disciplined by linuxptp.
+
+#[cfg(test)]
+mod tests;
+
+pub mod grandmaster;
+pub mod management;
+pub mod slave;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ArgMatches;
+
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 129);
+pub const EVENT_PORT: u16 = 319;
+pub const GENERAL_PORT: u16 = 320;
+
+const HEADER_LEN: usize = 34;
+const TIMESTAMP_LEN: usize = 10;
+
+/// Flags of the header
+pub const TWO_STEP: u16 = 0x0200;
+pub const UNICAST: u16 = 0x0400;
+pub const UTC_OFFSET_VALID: u16 = 0x0004;
+pub const PTP_TIMESCALE: u16 = 0x0008;
+
+/// Parses the `--ptp-domain` option shared by master and slave.
+pub fn domain_from_args(args: &ArgMatches) -> u8 {
+    return args.value_of("ptp-domain").map_or(0, |domain| {
+        domain
+            .parse()
+            .expect("[ERR] Couldn't parse PTP domain as a number")
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PortIdentity {
+    pub clock: [u8; 8],
+    pub port: u16,
+}
+
+impl PortIdentity {
+    /// Identity for this process, PTP derives it from the MAC address but
+    /// a hash of the host, process and time is unique enough here.
+    pub fn generate() -> PortIdentity {
+        let mut hasher = DefaultHasher::new();
+        std::process::id().hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+        let mut buf = [0u8; 256];
+        if let Ok(name) = nix::unistd::gethostname(&mut buf) {
+            name.hash(&mut hasher);
+        }
+        return PortIdentity {
+            clock: hasher.finish().to_be_bytes(),
+            port: 1,
+        };
+    }
+
+    fn read(buf: &[u8]) -> PortIdentity {
+        let mut clock = [0; 8];
+        clock.copy_from_slice(&buf[..8]);
+        return PortIdentity {
+            clock,
+            port: u16::from_be_bytes([buf[8], buf[9]]),
+        };
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.clock);
+        buf.extend_from_slice(&self.port.to_be_bytes());
+    }
+}
+
+impl std::fmt::Display for PortIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let c = self.clock;
+        return write!(
+            f,
+            "{:02x}{:02x}{:02x}.{:02x}{:02x}.{:02x}{:02x}{:02x}-{}",
+            c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7], self.port
+        );
+    }
+}
+
+/// Seconds (48 bits on the wire) and nanoseconds since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Timestamp {
+    pub seconds: u64,
+    pub nanoseconds: u32,
+}
+
+impl Timestamp {
+    pub fn from_system(time: SystemTime) -> Timestamp {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        return Timestamp {
+            seconds: since.as_secs(),
+            nanoseconds: since.subsec_nanos(),
+        };
+    }
+
+    pub fn to_system(&self) -> SystemTime {
+        return UNIX_EPOCH + Duration::new(self.seconds, self.nanoseconds);
+    }
+
+    fn read(buf: &[u8]) -> Timestamp {
+        let mut seconds = [0; 8];
+        seconds[2..].copy_from_slice(&buf[..6]);
+        return Timestamp {
+            seconds: u64::from_be_bytes(seconds),
+            nanoseconds: u32::from_be_bytes([buf[6], buf[7], buf[8], buf[9]]),
+        };
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.seconds.to_be_bytes()[2..]);
+        buf.extend_from_slice(&self.nanoseconds.to_be_bytes());
+    }
+}
+
+/// Dataset of the grandmaster carried by Announce messages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Announce {
+    pub origin: Timestamp,
+    /// TAI minus UTC in seconds
+    pub utc_offset: i16,
+    pub priority1: u8,
+    pub class: u8,
+    pub accuracy: u8,
+    pub variance: u16,
+    pub priority2: u8,
+    pub grandmaster: [u8; 8],
+    pub steps_removed: u16,
+    pub time_source: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Body {
+    Sync(Timestamp),
+    DelayReq(Timestamp),
+    /// Precise origin timestamp of the Sync with the same sequence id
+    FollowUp(Timestamp),
+    DelayResp {
+        /// When the master received the Delay_Req
+        receive: Timestamp,
+        requesting: PortIdentity,
+    },
+    Announce(Announce),
+    Management(management::Management),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Message {
+    pub domain: u8,
+    pub flags: u16,
+    /// Nanoseconds multiplied by 2^16
+    pub correction: i64,
+    pub source: PortIdentity,
+    pub sequence: u16,
+    /// Log2 of the interval between messages of this kind in seconds
+    pub log_interval: i8,
+    pub body: Body,
+}
+
+impl Message {
+    pub fn new(domain: u8, source: PortIdentity, sequence: u16, body: Body) -> Message {
+        return Message {
+            domain,
+            flags: 0,
+            correction: 0,
+            source,
+            sequence,
+            log_interval: 0x7f,
+            body,
+        };
+    }
+
+    /// Correction field in seconds
+    pub fn correction_secs(&self) -> f64 {
+        return self.correction as f64 / 65536. * 1e-9;
+    }
+
+    fn message_type(&self) -> u8 {
+        return match self.body {
+            Body::Sync(_) => 0x0,
+            Body::DelayReq(_) => 0x1,
+            Body::FollowUp(_) => 0x8,
+            Body::DelayResp { .. } => 0x9,
+            Body::Announce(_) => 0xb,
+            Body::Management(_) => 0xd,
+        };
+    }
+
+    fn control(&self) -> u8 {
+        return match self.body {
+            Body::Sync(_) => 0,
+            Body::DelayReq(_) => 1,
+            Body::FollowUp(_) => 2,
+            Body::DelayResp { .. } => 3,
+            Body::Announce(_) => 5,
+            Body::Management(_) => 4,
+        };
+    }
+
+    /// Event messages are timestamped and go to the event port.
+    pub fn is_event(&self) -> bool {
+        return self.message_type() < 0x8;
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(64);
+        buf.push(self.message_type());
+        buf.push(2);
+        buf.extend_from_slice(&[0, 0]);
+        buf.push(self.domain);
+        buf.push(0);
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        buf.extend_from_slice(&self.correction.to_be_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        self.source.write(&mut buf);
+        buf.extend_from_slice(&self.sequence.to_be_bytes());
+        buf.push(self.control());
+        buf.push(self.log_interval as u8);
+        match &self.body {
+            Body::Sync(ts) | Body::DelayReq(ts) | Body::FollowUp(ts) => ts.write(&mut buf),
+            Body::DelayResp {
+                receive,
+                requesting,
+            } => {
+                receive.write(&mut buf);
+                requesting.write(&mut buf);
+            }
+            Body::Announce(announce) => {
+                announce.origin.write(&mut buf);
+                buf.extend_from_slice(&announce.utc_offset.to_be_bytes());
+                buf.push(0);
+                buf.push(announce.priority1);
+                buf.push(announce.class);
+                buf.push(announce.accuracy);
+                buf.extend_from_slice(&announce.variance.to_be_bytes());
+                buf.push(announce.priority2);
+                buf.extend_from_slice(&announce.grandmaster);
+                buf.extend_from_slice(&announce.steps_removed.to_be_bytes());
+                buf.push(announce.time_source);
+            }
+            Body::Management(management) => management.write(&mut buf),
+        }
+        let len = buf.len() as u16;
+        buf[2..4].copy_from_slice(&len.to_be_bytes());
+        return buf;
+    }
+
+    /// `None` for messages of a type that isn't supported.
+    pub fn decode(buf: &[u8]) -> Result<Option<Message>, String> {
+        if buf.len() < HEADER_LEN {
+            return Err(format!("Message of {} bytes is too short", buf.len()));
+        }
+        if buf[1] & 0x0f != 2 {
+            return Err(format!("Unsupported PTP version {}", buf[1] & 0x0f));
+        }
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if len > buf.len() || len < HEADER_LEN {
+            return Err(format!("Bad length {} of {} bytes", len, buf.len()));
+        }
+        let body = &buf[HEADER_LEN..len];
+        let need = |size: usize| {
+            if body.len() < size {
+                Err(format!("Message type {:#x} too short", buf[0] & 0x0f))
+            } else {
+                Ok(())
+            }
+        };
+        let body = match buf[0] & 0x0f {
+            0x0 => {
+                need(TIMESTAMP_LEN)?;
+                Body::Sync(Timestamp::read(body))
+            }
+            0x1 => {
+                need(TIMESTAMP_LEN)?;
+                Body::DelayReq(Timestamp::read(body))
+            }
+            0x8 => {
+                need(TIMESTAMP_LEN)?;
+                Body::FollowUp(Timestamp::read(body))
+            }
+            0x9 => {
+                need(TIMESTAMP_LEN + 10)?;
+                Body::DelayResp {
+                    receive: Timestamp::read(body),
+                    requesting: PortIdentity::read(&body[TIMESTAMP_LEN..]),
+                }
+            }
+            0xb => {
+                need(TIMESTAMP_LEN + 20)?;
+                let mut grandmaster = [0; 8];
+                grandmaster.copy_from_slice(&body[19..27]);
+                Body::Announce(Announce {
+                    origin: Timestamp::read(body),
+                    utc_offset: i16::from_be_bytes([body[10], body[11]]),
+                    priority1: body[13],
+                    class: body[14],
+                    accuracy: body[15],
+                    variance: u16::from_be_bytes([body[16], body[17]]),
+                    priority2: body[18],
+                    grandmaster,
+                    steps_removed: u16::from_be_bytes([body[27], body[28]]),
+                    time_source: body[29],
+                })
+            }
+            0xd => Body::Management(
+                management::Management::read(body)
+                    .map_err(|err| format!("Management message: {}", err))?,
+            ),
+            _ => return Ok(None),
+        };
+        let mut correction = [0; 8];
+        correction.copy_from_slice(&buf[8..16]);
+        return Ok(Some(Message {
+            domain: buf[4],
+            flags: u16::from_be_bytes([buf[6], buf[7]]),
+            correction: i64::from_be_bytes(correction),
+            source: PortIdentity::read(&buf[20..30]),
+            sequence: u16::from_be_bytes([buf[30], buf[31]]),
+            log_interval: buf[33] as i8,
+            body,
+        }));
+    }
+}
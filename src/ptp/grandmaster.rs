@@ -0,0 +1,326 @@
+//! Master clock serving the system clock. It announces itself, sends a
+//! two-step Sync every 2^`log_sync_interval` seconds and answers every
+//! Delay_Req. There is no best master clock algorithm, whoever runs it is
+//! the grandmaster, the Announce messages carry the default dataset of a
+//! free running ordinary clock for `ptp4l` slaves. The timescale is
+//! arbitrary (UTC of the system clock), so slaves apply no UTC offset.
+//!
+//! Delay_Req messages tell which slaves follow the master, every client is
+//! listed with its request rate and the spread of its slave to master
+//! delays.
+
+use super::slave::receive;
+use super::{Announce, Body, Message, PortIdentity, Timestamp, TWO_STEP};
+use super::{EVENT_PORT, GENERAL_PORT, GROUP};
+use crate::clock;
+
+use indicator::{Indicator, Median, Variance};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the delay thread looks at the stop flag
+const POLL: Duration = Duration::from_millis(100);
+/// Delay_Req messages the statistics of a client run over
+const CLIENT_WINDOW: usize = 32;
+/// TAI minus UTC announced, not valid as the timescale is arbitrary
+const UTC_OFFSET: i16 = 37;
+/// Time source of the announce dataset, an internal oscillator
+const INTERNAL_OSCILLATOR: u8 = 0xa0;
+
+/// Duration of a PTP log interval
+pub fn interval(log: i8) -> Duration {
+    return Duration::from_secs_f64(2f64.powi(log as i32));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GrandmasterConfig {
+    pub domain: u8,
+    /// Port Sync is sent from and Delay_Req received on, 0 for any
+    pub event_port: u16,
+    /// Where event and general messages are sent
+    pub event_dest: SocketAddrV4,
+    pub general_dest: SocketAddrV4,
+    /// Log2 of the intervals between Sync and Announce messages in seconds
+    pub log_sync_interval: i8,
+    pub log_announce_interval: i8,
+    /// Log2 of the interval slaves should keep between Delay_Req messages,
+    /// only advertised in Delay_Resp
+    pub log_delay_req_interval: i8,
+    pub priority1: u8,
+    pub priority2: u8,
+}
+
+impl Default for GrandmasterConfig {
+    fn default() -> GrandmasterConfig {
+        return GrandmasterConfig {
+            domain: 0,
+            event_port: EVENT_PORT,
+            event_dest: SocketAddrV4::new(GROUP, EVENT_PORT),
+            general_dest: SocketAddrV4::new(GROUP, GENERAL_PORT),
+            log_sync_interval: 0,
+            log_announce_interval: 1,
+            log_delay_req_interval: 0,
+            priority1: 128,
+            priority2: 128,
+        };
+    }
+}
+
+/// Delay_Req traffic of one slave.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientStats {
+    /// Address the last request came from
+    pub addr: SocketAddr,
+    pub requests: usize,
+    /// Mean interval between requests in seconds
+    pub interval: Option<f64>,
+    /// Median and standard deviation of the arrival minus the origin
+    /// timestamp of the requests in seconds. That is the slave to master
+    /// delay for a slave following the master, plus the offset of its clock
+    /// otherwise, and `None` for slaves leaving the origin empty like ptp4l.
+    pub delay: Option<(f64, f64)>,
+}
+
+struct Client {
+    addr: SocketAddr,
+    requests: usize,
+    first: Instant,
+    last: Instant,
+    median: Median<f64>,
+    variance: Variance<f64>,
+}
+
+/// Statistics of every slave sending Delay_Req messages.
+pub struct Clients {
+    clients: HashMap<PortIdentity, Client>,
+}
+
+impl Clients {
+    pub fn new() -> Clients {
+        return Clients {
+            clients: HashMap::new(),
+        };
+    }
+
+    /// Records a request of `id` from `addr` with `origin` that arrived at
+    /// `arrival`, `now` on the monotonic clock.
+    pub fn record(
+        &mut self,
+        id: PortIdentity,
+        addr: SocketAddr,
+        origin: Timestamp,
+        arrival: SystemTime,
+        now: Instant,
+    ) {
+        let client = self.clients.entry(id).or_insert_with(|| {
+            println!("[INF] PTP slave {} ({})", id, addr);
+            Client {
+                addr,
+                requests: 0,
+                first: now,
+                last: now,
+                median: Median::new(CLIENT_WINDOW).unwrap(),
+                variance: Variance::new(CLIENT_WINDOW).unwrap(),
+            }
+        });
+        client.addr = addr;
+        client.requests += 1;
+        client.last = now;
+        if origin != Timestamp::default() {
+            let delay = clock::diff(arrival, origin.to_system());
+            client.median.next(delay);
+            client.variance.next(delay);
+        }
+    }
+
+    /// Statistics of every client ordered by identity
+    pub fn stats(&self) -> Vec<(PortIdentity, ClientStats)> {
+        let mut stats: Vec<_> = self
+            .clients
+            .iter()
+            .map(|(id, client)| {
+                let interval = if client.requests > 1 {
+                    Some((client.last - client.first).as_secs_f64() / (client.requests - 1) as f64)
+                } else {
+                    None
+                };
+                let delay = client
+                    .median
+                    .value()
+                    .map(|median| (median, client.variance.value().unwrap_or(0.).sqrt()));
+                (
+                    *id,
+                    ClientStats {
+                        addr: client.addr,
+                        requests: client.requests,
+                        interval,
+                        delay,
+                    },
+                )
+            })
+            .collect();
+        stats.sort_by_key(|(id, _)| id.clock);
+        return stats;
+    }
+}
+
+impl Default for Clients {
+    fn default() -> Clients {
+        return Clients::new();
+    }
+}
+
+pub struct Grandmaster {
+    identity: PortIdentity,
+    clients: Arc<Mutex<Clients>>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Grandmaster {
+    pub fn start(config: GrandmasterConfig) -> io::Result<Grandmaster> {
+        let event = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.event_port))?;
+        let general = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        for socket in &[&event, &general] {
+            socket.set_multicast_ttl_v4(1)?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+        event.set_read_timeout(Some(POLL))?;
+        let identity = PortIdentity::generate();
+        let clients = Arc::new(Mutex::new(Clients::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let sync_event = event.try_clone()?;
+        let sync_general = general.try_clone()?;
+        let sync_stop = Arc::clone(&stop);
+        let sync_thread = thread::spawn(move || {
+            let sync_interval = interval(config.log_sync_interval);
+            let announce_interval = interval(config.log_announce_interval);
+            let mut next_announce = Instant::now();
+            let mut sync_sequence: u16 = 0;
+            let mut announce_sequence: u16 = 0;
+            let mut warned = false;
+            while !sync_stop.load(Ordering::Relaxed) {
+                let mut sent = Ok(0);
+                if Instant::now() >= next_announce {
+                    let announce = announce(&config, identity, announce_sequence);
+                    sent = sync_general.send_to(&announce.encode(), config.general_dest);
+                    announce_sequence = announce_sequence.wrapping_add(1);
+                    next_announce += announce_interval;
+                }
+                let origin = Timestamp::from_system(SystemTime::now());
+                let mut sync =
+                    Message::new(config.domain, identity, sync_sequence, Body::Sync(origin));
+                sync.flags = TWO_STEP;
+                sync.log_interval = config.log_sync_interval;
+                let mut follow_up = Message::new(
+                    config.domain,
+                    identity,
+                    sync_sequence,
+                    Body::FollowUp(origin),
+                );
+                follow_up.log_interval = config.log_sync_interval;
+                let sent = sent
+                    .and_then(|_| sync_event.send_to(&sync.encode(), config.event_dest))
+                    .and_then(|_| sync_general.send_to(&follow_up.encode(), config.general_dest));
+                if let Err(err) = sent {
+                    if !warned {
+                        println!("[WRN] Couldn't send PTP sync: {}", err);
+                        warned = true;
+                    }
+                }
+                sync_sequence = sync_sequence.wrapping_add(1);
+                thread::sleep(sync_interval);
+            }
+        });
+
+        let delay_clients = Arc::clone(&clients);
+        let delay_stop = Arc::clone(&stop);
+        let delay_thread = thread::spawn(move || {
+            receive(&event, &delay_stop, |msg, peer, time| {
+                if msg.domain != config.domain {
+                    return;
+                }
+                if let Body::DelayReq(origin) = msg.body {
+                    let mut response = Message::new(
+                        config.domain,
+                        identity,
+                        msg.sequence,
+                        Body::DelayResp {
+                            receive: Timestamp::from_system(time),
+                            requesting: msg.source,
+                        },
+                    );
+                    response.log_interval = config.log_delay_req_interval;
+                    if let Err(err) = general.send_to(&response.encode(), config.general_dest) {
+                        println!(
+                            "[WRN] Couldn't answer PTP delay request of {}: {}",
+                            peer, err
+                        );
+                    }
+                    delay_clients.lock().unwrap().record(
+                        msg.source,
+                        peer,
+                        origin,
+                        time,
+                        Instant::now(),
+                    );
+                }
+            });
+        });
+        return Ok(Grandmaster {
+            identity,
+            clients,
+            stop,
+            threads: vec![sync_thread, delay_thread],
+        });
+    }
+
+    pub fn identity(&self) -> PortIdentity {
+        return self.identity;
+    }
+
+    /// Statistics of the slaves that sent Delay_Req messages so far
+    pub fn clients(&self) -> Vec<(PortIdentity, ClientStats)> {
+        return self.clients.lock().unwrap().stats();
+    }
+}
+
+impl Drop for Grandmaster {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            thread.join().ok();
+        }
+    }
+}
+
+fn announce(config: &GrandmasterConfig, identity: PortIdentity, sequence: u16) -> Message {
+    let mut msg = Message::new(
+        config.domain,
+        identity,
+        sequence,
+        Body::Announce(Announce {
+            origin: Timestamp::from_system(SystemTime::now()),
+            utc_offset: UTC_OFFSET,
+            priority1: config.priority1,
+            // Default class of a clock that can't be a slave-only clock,
+            // with unknown accuracy and variance
+            class: 248,
+            accuracy: 0xfe,
+            variance: 0xffff,
+            priority2: config.priority2,
+            grandmaster: identity.clock,
+            steps_removed: 0,
+            time_source: INTERNAL_OSCILLATOR,
+        }),
+    );
+    msg.log_interval = config.log_announce_interval;
+    return msg;
+}
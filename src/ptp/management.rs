@@ -0,0 +1,500 @@
+//! Management messages, the way `pmc` talks to `ptp4l` over its Unix domain
+//! socket. Only the TIME_STATUS_NP and PORT_DATA_SET datasets are decoded,
+//! enough to tell whether the system clock follows a grandmaster. `Monitor`
+//! polls them in the background.
+
+use super::{Body, Message, PortIdentity};
+
+use std::fmt;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Socket `ptp4l` listens on by default
+pub const UDS_PATH: &str = "/var/run/ptp4l";
+
+pub const GET: u8 = 0;
+pub const RESPONSE: u8 = 2;
+
+pub const TIME_STATUS_NP: u16 = 0xc000;
+pub const PORT_DATA_SET: u16 = 0x2004;
+
+const MANAGEMENT: u16 = 0x0001;
+const MANAGEMENT_ERROR_STATUS: u16 = 0x0002;
+const TIME_STATUS_LEN: usize = 50;
+const PORT_DATA_SET_LEN: usize = 26;
+
+/// Length of the management fields following the header
+pub(crate) const FIELDS_LEN: usize = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    Initializing,
+    Faulty,
+    Disabled,
+    Listening,
+    PreMaster,
+    Master,
+    Passive,
+    Uncalibrated,
+    Slave,
+    Unknown(u8),
+}
+
+impl From<u8> for PortState {
+    fn from(state: u8) -> PortState {
+        return match state {
+            1 => PortState::Initializing,
+            2 => PortState::Faulty,
+            3 => PortState::Disabled,
+            4 => PortState::Listening,
+            5 => PortState::PreMaster,
+            6 => PortState::Master,
+            7 => PortState::Passive,
+            8 => PortState::Uncalibrated,
+            9 => PortState::Slave,
+            other => PortState::Unknown(other),
+        };
+    }
+}
+
+impl From<PortState> for u8 {
+    fn from(state: PortState) -> u8 {
+        return match state {
+            PortState::Initializing => 1,
+            PortState::Faulty => 2,
+            PortState::Disabled => 3,
+            PortState::Listening => 4,
+            PortState::PreMaster => 5,
+            PortState::Master => 6,
+            PortState::Passive => 7,
+            PortState::Uncalibrated => 8,
+            PortState::Slave => 9,
+            PortState::Unknown(other) => other,
+        };
+    }
+}
+
+impl fmt::Display for PortState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            PortState::Initializing => write!(f, "INITIALIZING"),
+            PortState::Faulty => write!(f, "FAULTY"),
+            PortState::Disabled => write!(f, "DISABLED"),
+            PortState::Listening => write!(f, "LISTENING"),
+            PortState::PreMaster => write!(f, "PRE_MASTER"),
+            PortState::Master => write!(f, "MASTER"),
+            PortState::Passive => write!(f, "PASSIVE"),
+            PortState::Uncalibrated => write!(f, "UNCALIBRATED"),
+            PortState::Slave => write!(f, "SLAVE"),
+            PortState::Unknown(state) => write!(f, "STATE {}", state),
+        };
+    }
+}
+
+/// The linuxptp specific TIME_STATUS_NP dataset, fields `pmc` prints but
+/// piwfs doesn't use are left out and written as zeros.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeStatus {
+    /// Offset of the clock from the grandmaster in nanoseconds
+    pub master_offset: i64,
+    /// Time the last Sync was received, nanoseconds
+    pub ingress_time: i64,
+    pub gm_present: bool,
+    pub gm_identity: [u8; 8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortDataSet {
+    pub port: PortIdentity,
+    pub state: PortState,
+    pub log_min_delay_req_interval: i8,
+    /// Nanoseconds multiplied by 2^16
+    pub peer_mean_path_delay: i64,
+    pub log_announce_interval: i8,
+    pub announce_receipt_timeout: u8,
+    pub log_sync_interval: i8,
+    pub delay_mechanism: u8,
+    pub log_min_pdelay_req_interval: i8,
+    pub version: u8,
+}
+
+/// Management TLV, requests carry no data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tlv {
+    /// Request of the dataset with the given id
+    Empty(u16),
+    TimeStatus(TimeStatus),
+    PortDataSet(PortDataSet),
+    /// Error status of a request for the dataset with the given id
+    Error {
+        id: u16,
+        error: u16,
+    },
+    /// Dataset that isn't decoded
+    Other(u16),
+}
+
+impl Tlv {
+    /// Id of the dataset
+    pub fn id(&self) -> u16 {
+        return match self {
+            Tlv::Empty(id) | Tlv::Other(id) | Tlv::Error { id, .. } => *id,
+            Tlv::TimeStatus(_) => TIME_STATUS_NP,
+            Tlv::PortDataSet(_) => PORT_DATA_SET,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Management {
+    /// Port the message is addressed to, all ones for every port
+    pub target: PortIdentity,
+    /// `GET`, `RESPONSE`, ...
+    pub action: u8,
+    pub tlv: Tlv,
+}
+
+impl Management {
+    pub(crate) fn write(&self, buf: &mut Vec<u8>) {
+        self.target.write(buf);
+        // Starting boundary hops and boundary hops
+        buf.extend_from_slice(&[0, 0]);
+        buf.push(self.action);
+        buf.push(0);
+        let mut data = Vec::new();
+        let tlv_type = match self.tlv {
+            Tlv::Empty(_) | Tlv::Other(_) => MANAGEMENT,
+            Tlv::TimeStatus(status) => {
+                data.extend_from_slice(&status.master_offset.to_be_bytes());
+                data.extend_from_slice(&status.ingress_time.to_be_bytes());
+                // Rate offset, phase change and time base indicator
+                data.extend_from_slice(&[0; 22]);
+                data.extend_from_slice(&(status.gm_present as i32).to_be_bytes());
+                data.extend_from_slice(&status.gm_identity);
+                MANAGEMENT
+            }
+            Tlv::PortDataSet(set) => {
+                set.port.write(&mut data);
+                data.push(set.state.into());
+                data.push(set.log_min_delay_req_interval as u8);
+                data.extend_from_slice(&set.peer_mean_path_delay.to_be_bytes());
+                data.push(set.log_announce_interval as u8);
+                data.push(set.announce_receipt_timeout);
+                data.push(set.log_sync_interval as u8);
+                data.push(set.delay_mechanism);
+                data.push(set.log_min_pdelay_req_interval as u8);
+                data.push(set.version);
+                MANAGEMENT
+            }
+            Tlv::Error { error, .. } => {
+                buf.extend_from_slice(&MANAGEMENT_ERROR_STATUS.to_be_bytes());
+                buf.extend_from_slice(&8u16.to_be_bytes());
+                buf.extend_from_slice(&error.to_be_bytes());
+                buf.extend_from_slice(&self.tlv.id().to_be_bytes());
+                buf.extend_from_slice(&[0; 4]);
+                return;
+            }
+        };
+        buf.extend_from_slice(&tlv_type.to_be_bytes());
+        buf.extend_from_slice(&(2 + data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.tlv.id().to_be_bytes());
+        buf.extend(data);
+    }
+
+    pub(crate) fn read(buf: &[u8]) -> Result<Management, String> {
+        if buf.len() < FIELDS_LEN + 6 {
+            return Err("Management message too short".to_string());
+        }
+        let target = PortIdentity::read(buf);
+        let action = buf[12] & 0x0f;
+        let tlv = &buf[FIELDS_LEN..];
+        let tlv_type = u16::from_be_bytes([tlv[0], tlv[1]]);
+        let len = u16::from_be_bytes([tlv[2], tlv[3]]) as usize;
+        if tlv.len() < 4 + len || len < 2 {
+            return Err(format!("Bad TLV length {}", len));
+        }
+        let value = &tlv[4..4 + len];
+        let tlv = match tlv_type {
+            MANAGEMENT_ERROR_STATUS if len >= 4 => Tlv::Error {
+                error: u16::from_be_bytes([value[0], value[1]]),
+                id: u16::from_be_bytes([value[2], value[3]]),
+            },
+            MANAGEMENT => {
+                let id = u16::from_be_bytes([value[0], value[1]]);
+                let data = &value[2..];
+                match id {
+                    _ if data.is_empty() => Tlv::Empty(id),
+                    TIME_STATUS_NP if data.len() >= TIME_STATUS_LEN => {
+                        let i64_at = |at: usize| {
+                            let mut bytes = [0; 8];
+                            bytes.copy_from_slice(&data[at..at + 8]);
+                            i64::from_be_bytes(bytes)
+                        };
+                        let mut gm_identity = [0; 8];
+                        gm_identity.copy_from_slice(&data[42..50]);
+                        Tlv::TimeStatus(TimeStatus {
+                            master_offset: i64_at(0),
+                            ingress_time: i64_at(8),
+                            gm_present: data[38..42] != [0; 4],
+                            gm_identity,
+                        })
+                    }
+                    PORT_DATA_SET if data.len() >= PORT_DATA_SET_LEN => {
+                        let mut delay = [0; 8];
+                        delay.copy_from_slice(&data[12..20]);
+                        Tlv::PortDataSet(PortDataSet {
+                            port: PortIdentity::read(data),
+                            state: data[10].into(),
+                            log_min_delay_req_interval: data[11] as i8,
+                            peer_mean_path_delay: i64::from_be_bytes(delay),
+                            log_announce_interval: data[20] as i8,
+                            announce_receipt_timeout: data[21],
+                            log_sync_interval: data[22] as i8,
+                            delay_mechanism: data[23],
+                            log_min_pdelay_req_interval: data[24] as i8,
+                            version: data[25],
+                        })
+                    }
+                    TIME_STATUS_NP | PORT_DATA_SET => {
+                        return Err(format!("Dataset {:#06x} too short", id));
+                    }
+                    _ => Tlv::Other(id),
+                }
+            }
+            other => return Err(format!("Unexpected TLV type {:#06x}", other)),
+        };
+        return Ok(Management {
+            target,
+            action,
+            tlv,
+        });
+    }
+}
+
+/// Distinguishes the sockets of several clients in one process
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Management client like `pmc -u`, bound to a socket of its own that the
+/// answers are sent to.
+pub struct Pmc {
+    socket: UnixDatagram,
+    path: PathBuf,
+    domain: u8,
+    identity: PortIdentity,
+    sequence: u16,
+    timeout: Duration,
+}
+
+impl Pmc {
+    pub fn connect(server: &Path, domain: u8) -> io::Result<Pmc> {
+        let path = std::env::temp_dir().join(format!(
+            "piwfs-pmc.{}.{}",
+            std::process::id(),
+            CLIENTS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path)?;
+        let pmc = Pmc {
+            socket,
+            path,
+            domain,
+            identity: PortIdentity::generate(),
+            sequence: 0,
+            timeout: Duration::from_secs(1),
+        };
+        pmc.socket.connect(server)?;
+        return Ok(pmc);
+    }
+
+    /// Asks for the dataset `id` and waits for the answer.
+    pub fn get(&mut self, id: u16) -> io::Result<Tlv> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let request = Message::new(
+            self.domain,
+            self.identity,
+            self.sequence,
+            Body::Management(Management {
+                target: PortIdentity {
+                    clock: [0xff; 8],
+                    port: 0xffff,
+                },
+                action: GET,
+                tlv: Tlv::Empty(id),
+            }),
+        );
+        self.socket.send(&request.encode())?;
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0; 1500];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::new(0, 0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("No answer for dataset {:#06x}", id),
+                ));
+            }
+            self.socket.set_read_timeout(Some(left))?;
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            };
+            let msg = match Message::decode(&buf[..len]) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => continue,
+                Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+            };
+            match msg.body {
+                Body::Management(management)
+                    if msg.sequence == self.sequence && management.tlv.id() == id =>
+                {
+                    return match management.tlv {
+                        Tlv::Error { error, .. } => Err(io::Error::other(format!(
+                            "Error {:#06x} for dataset {:#06x}",
+                            error, id
+                        ))),
+                        tlv => Ok(tlv),
+                    };
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    pub fn time_status(&mut self) -> io::Result<TimeStatus> {
+        return match self.get(TIME_STATUS_NP)? {
+            Tlv::TimeStatus(status) => Ok(status),
+            other => Err(unexpected(other)),
+        };
+    }
+
+    pub fn port_data_set(&mut self) -> io::Result<PortDataSet> {
+        return match self.get(PORT_DATA_SET)? {
+            Tlv::PortDataSet(set) => Ok(set),
+            other => Err(unexpected(other)),
+        };
+    }
+}
+
+fn unexpected(tlv: Tlv) -> io::Error {
+    return io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unexpected answer {:?}", tlv),
+    );
+}
+
+impl Drop for Pmc {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// What the last poll of `ptp4l` returned.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Ptp4lStatus {
+    /// Offset of the clock from the grandmaster in seconds
+    pub offset: Option<f64>,
+    pub state: Option<PortState>,
+    pub gm_present: bool,
+    /// Why the last poll failed
+    pub error: Option<String>,
+}
+
+impl Ptp4lStatus {
+    /// Why the clock can't be trusted, `None` while the port is a slave of
+    /// a grandmaster less than `max_offset` seconds off.
+    pub fn problem(&self, max_offset: f64) -> Option<String> {
+        if let Some(err) = &self.error {
+            return Some(format!("ptp4l doesn't answer: {}", err));
+        }
+        match self.state {
+            Some(PortState::Slave) => (),
+            Some(state) => return Some(format!("ptp4l port is {}", state)),
+            None => return Some("ptp4l port state unknown".to_string()),
+        }
+        if !self.gm_present {
+            return Some("ptp4l has no grandmaster".to_string());
+        }
+        return match self.offset {
+            Some(offset) if offset.abs() > max_offset => Some(format!(
+                "ptp4l offset {:+.0} us exceeds {:.0} us",
+                offset * 1e6,
+                max_offset * 1e6
+            )),
+            Some(_) => None,
+            None => Some("ptp4l offset unknown".to_string()),
+        };
+    }
+}
+
+/// Polls `ptp4l` in the background.
+pub struct Monitor {
+    status: Arc<Mutex<Ptp4lStatus>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Monitor {
+    /// Polls the `ptp4l` listening on `server` every `interval`, the first
+    /// poll is done before returning.
+    pub fn start(server: &Path, domain: u8, interval: Duration) -> io::Result<Monitor> {
+        let mut pmc = Pmc::connect(server, domain)?;
+        let status = Arc::new(Mutex::new(poll(&mut pmc)));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_status = Arc::clone(&status);
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let mut last = Instant::now();
+            while !thread_stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(10).min(interval));
+                if last.elapsed() < interval {
+                    continue;
+                }
+                last = Instant::now();
+                let polled = poll(&mut pmc);
+                *thread_status.lock().unwrap() = polled;
+            }
+        });
+        return Ok(Monitor {
+            status,
+            stop,
+            thread: Some(thread),
+        });
+    }
+
+    pub fn status(&self) -> Ptp4lStatus {
+        return self.status.lock().unwrap().clone();
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn poll(pmc: &mut Pmc) -> Ptp4lStatus {
+    let polled = pmc
+        .time_status()
+        .and_then(|time| Ok((time, pmc.port_data_set()?)));
+    return match polled {
+        Ok((time, port)) => Ptp4lStatus {
+            offset: Some(time.master_offset as f64 / 1e9),
+            state: Some(port.state),
+            gm_present: time.gm_present,
+            error: None,
+        },
+        Err(err) => Ptp4lStatus {
+            error: Some(err.to_string()),
+            ..Ptp4lStatus::default()
+        },
+    };
+}
@@ -0,0 +1,370 @@
+//! Ordinary clock in the slave state. The offset of the master is tracked
+//! from Sync (and Follow_Up) messages with a Kalman filter, the path delay
+//! from Delay_Req/Delay_Resp exchanges with a running median. Timestamps
+//! are taken by the receiving thread right after a message arrives, so the
+//! scheduling latency of both ends shows up as noise the filters average.
+
+use super::{Body, Message, PortIdentity, Timestamp, EVENT_PORT, GENERAL_PORT, GROUP};
+use super::{PTP_TIMESCALE, TWO_STEP, UTC_OFFSET_VALID};
+use crate::clock::{self, TimeSource};
+use crate::protocol;
+
+use indicator::{Indicator, Kalman, Median};
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// Time without a Sync after which another master is accepted
+const MASTER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the receiving threads look at the stop flag
+const POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlaveConfig {
+    pub domain: u8,
+    /// Ports event and general messages are received on, 0 for any
+    pub event_port: u16,
+    pub general_port: u16,
+    /// Multicast group to join, `None` to receive unicast only
+    pub group: Option<Ipv4Addr>,
+    pub iface: Ipv4Addr,
+    /// Shortest time between two delay requests
+    pub delay_interval: Duration,
+    /// Path delay measurements the median runs over
+    pub delay_window: usize,
+    /// Density of the random walk of the clock rate, in microseconds
+    /// squared per second cubed
+    pub process_noise: f64,
+    /// Variance of a single offset measurement, in microseconds squared
+    pub measurement_noise: f64,
+}
+
+impl Default for SlaveConfig {
+    fn default() -> SlaveConfig {
+        return SlaveConfig {
+            domain: 0,
+            event_port: EVENT_PORT,
+            general_port: GENERAL_PORT,
+            group: Some(GROUP),
+            iface: Ipv4Addr::UNSPECIFIED,
+            delay_interval: Duration::from_secs(1),
+            delay_window: 9,
+            process_noise: 1e-2,
+            measurement_noise: 2500.,
+        };
+    }
+}
+
+/// What the slave knows about its master.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    pub master: Option<PortIdentity>,
+    /// Network time minus local time in seconds
+    pub offset: Option<f64>,
+    /// Mean path delay in seconds
+    pub delay: Option<f64>,
+    /// Complete Sync measurements from the current master
+    pub syncs: usize,
+}
+
+/// Offset estimation from the timestamps of the current master, without
+/// any networking.
+pub struct Tracker {
+    config: SlaveConfig,
+    identity: PortIdentity,
+    master: Option<PortIdentity>,
+    /// Subtracted from master timestamps on the PTP timescale, seconds
+    utc_offset: f64,
+    /// Local time of the last complete Sync measurement
+    last_sync: Option<SystemTime>,
+    /// Sequence, reception time and correction of the Sync waiting for
+    /// its Follow_Up
+    pending_sync: Option<(u16, SystemTime, f64)>,
+    /// Sequence and send time of the Delay_Req waiting for its response
+    pending_delay: Option<(u16, SystemTime)>,
+    last_request: Option<SystemTime>,
+    sequence: u16,
+    /// Master to slave difference in microseconds over seconds since `base`
+    master_to_slave: Kalman<f64>,
+    delay: Median<f64>,
+    base: SystemTime,
+    syncs: usize,
+}
+
+impl Tracker {
+    pub fn new(config: SlaveConfig, identity: PortIdentity, base: SystemTime) -> Tracker {
+        return Tracker {
+            config,
+            identity,
+            master: None,
+            utc_offset: 0.,
+            last_sync: None,
+            pending_sync: None,
+            pending_delay: None,
+            last_request: None,
+            sequence: 0,
+            master_to_slave: Kalman::with_noise(config.process_noise, config.measurement_noise),
+            delay: Median::new(config.delay_window).unwrap(),
+            base,
+            syncs: 0,
+        };
+    }
+
+    pub fn identity(&self) -> PortIdentity {
+        return self.identity;
+    }
+
+    /// Follows the master sending `source` from now on.
+    fn select(&mut self, source: PortIdentity) {
+        println!("[INF] PTP master {}", source);
+        *self = Tracker::new(self.config, self.identity, self.base);
+        self.master = Some(source);
+    }
+
+    fn is_master(&self, source: PortIdentity) -> bool {
+        return self.master == Some(source);
+    }
+
+    /// Master time in seconds since `base`
+    fn master_time(&self, ts: Timestamp) -> f64 {
+        return clock::diff(ts.to_system(), self.base) - self.utc_offset;
+    }
+
+    /// Predicted master to slave difference at `time`, in seconds
+    fn master_to_slave_at(&self, time: SystemTime) -> Option<f64> {
+        let (a, b) = self.master_to_slave.value()?;
+        return Some((a + b * clock::diff(time, self.base)) * 1e-6);
+    }
+
+    fn measure(&mut self, received: SystemTime, origin: Timestamp, correction: f64) {
+        let local = clock::diff(received, self.base);
+        let master_to_slave = local - self.master_time(origin) - correction;
+        self.master_to_slave.next((local, master_to_slave * 1e6));
+        self.last_sync = Some(received);
+        self.syncs += 1;
+    }
+
+    /// Takes a message received at `time`. Returns true for a Sync of the
+    /// master, after which a delay request may be due.
+    pub fn receive(&mut self, msg: &Message, time: SystemTime) -> bool {
+        if msg.domain != self.config.domain {
+            return false;
+        }
+        match msg.body {
+            Body::Sync(origin) => {
+                if !self.is_master(msg.source) {
+                    let expired = self
+                        .last_sync
+                        .is_none_or(|last| clock::diff(time, last) > MASTER_TIMEOUT.as_secs_f64());
+                    if !expired {
+                        return false;
+                    }
+                    self.select(msg.source);
+                }
+                if msg.flags & TWO_STEP != 0 {
+                    self.pending_sync = Some((msg.sequence, time, msg.correction_secs()));
+                } else {
+                    self.measure(time, origin, msg.correction_secs());
+                }
+                return true;
+            }
+            Body::FollowUp(origin) if self.is_master(msg.source) => {
+                if let Some((sequence, received, correction)) = self.pending_sync {
+                    if sequence == msg.sequence {
+                        self.pending_sync = None;
+                        self.measure(received, origin, correction + msg.correction_secs());
+                    }
+                }
+            }
+            Body::DelayResp {
+                receive,
+                requesting,
+            } if self.is_master(msg.source) && requesting == self.identity => {
+                if let Some((sequence, sent)) = self.pending_delay {
+                    if sequence == msg.sequence {
+                        self.pending_delay = None;
+                        if let Some(master_to_slave) = self.master_to_slave_at(sent) {
+                            let slave_to_master = self.master_time(receive)
+                                - clock::diff(sent, self.base)
+                                - msg.correction_secs();
+                            self.delay.next((master_to_slave + slave_to_master) / 2.);
+                        }
+                    }
+                }
+            }
+            Body::Announce(announce) if self.is_master(msg.source) => {
+                let timescale = PTP_TIMESCALE | UTC_OFFSET_VALID;
+                self.utc_offset = if msg.flags & timescale == timescale {
+                    announce.utc_offset as f64
+                } else {
+                    0.
+                };
+            }
+            _ => (),
+        }
+        return false;
+    }
+
+    /// Delay_Req to send right away when one is due at `time`, which is
+    /// taken as its send time.
+    pub fn delay_request(&mut self, time: SystemTime) -> Option<Message> {
+        self.master?;
+        if let Some(last) = self.last_request {
+            if clock::diff(time, last) < self.config.delay_interval.as_secs_f64() {
+                return None;
+            }
+        }
+        self.last_request = Some(time);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.pending_delay = Some((self.sequence, time));
+        return Some(Message::new(
+            self.config.domain,
+            self.identity,
+            self.sequence,
+            Body::DelayReq(Timestamp::from_system(time)),
+        ));
+    }
+
+    /// Network time minus local time at `time` in seconds
+    pub fn offset(&self, time: SystemTime) -> Option<f64> {
+        let delay = self.delay.value()?;
+        return Some(delay - self.master_to_slave_at(time)?);
+    }
+
+    pub fn status(&self, time: SystemTime) -> Status {
+        return Status {
+            master: self.master,
+            offset: self.offset(time),
+            delay: self.delay.value(),
+            syncs: self.syncs,
+        };
+    }
+}
+
+/// Tracker fed by threads receiving on the PTP ports.
+pub struct PtpSlave {
+    tracker: Arc<Mutex<Tracker>>,
+    event_addr: SocketAddr,
+    general_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl PtpSlave {
+    pub fn start(config: SlaveConfig) -> io::Result<PtpSlave> {
+        let bind = |port: u16| -> io::Result<UdpSocket> {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+            if let Some(group) = config.group {
+                socket.join_multicast_v4(&group, &config.iface)?;
+            }
+            socket.set_read_timeout(Some(POLL))?;
+            return Ok(socket);
+        };
+        let event = bind(config.event_port)?;
+        let general = bind(config.general_port)?;
+        let event_addr = event.local_addr()?;
+        let general_addr = general.local_addr()?;
+        let tracker = Arc::new(Mutex::new(Tracker::new(
+            config,
+            PortIdentity::generate(),
+            SystemTime::now(),
+        )));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let event_tracker = Arc::clone(&tracker);
+        let event_stop = Arc::clone(&stop);
+        let event_thread = thread::spawn(move || {
+            receive(&event, &event_stop, |msg, peer, time| {
+                let request = {
+                    let mut tracker = event_tracker.lock().unwrap();
+                    if !tracker.receive(&msg, time) {
+                        return;
+                    }
+                    tracker.delay_request(SystemTime::now())
+                };
+                if let Some(request) = request {
+                    if let Err(err) = event.send_to(&request.encode(), peer) {
+                        println!("[WRN] Couldn't send PTP delay request: {}", err);
+                    }
+                }
+            });
+        });
+        let general_tracker = Arc::clone(&tracker);
+        let general_stop = Arc::clone(&stop);
+        let general_thread = thread::spawn(move || {
+            receive(&general, &general_stop, |msg, _, time| {
+                general_tracker.lock().unwrap().receive(&msg, time);
+            });
+        });
+        return Ok(PtpSlave {
+            tracker,
+            event_addr,
+            general_addr,
+            stop,
+            threads: vec![event_thread, general_thread],
+        });
+    }
+
+    pub fn identity(&self) -> PortIdentity {
+        return self.tracker.lock().unwrap().identity();
+    }
+
+    /// Address event messages are received on
+    pub fn event_addr(&self) -> SocketAddr {
+        return self.event_addr;
+    }
+
+    /// Address general messages are received on
+    pub fn general_addr(&self) -> SocketAddr {
+        return self.general_addr;
+    }
+
+    pub fn status(&self) -> Status {
+        return self.tracker.lock().unwrap().status(SystemTime::now());
+    }
+}
+
+impl TimeSource for PtpSlave {
+    fn offset(&self) -> Option<f64> {
+        return self.tracker.lock().unwrap().offset(SystemTime::now());
+    }
+
+    fn name(&self) -> String {
+        return "PTP".to_string();
+    }
+}
+
+impl Drop for PtpSlave {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Calls `handle` with every message received on `socket`, its sender and
+/// the time it arrived, until `stop` is set.
+pub(crate) fn receive<F>(socket: &UdpSocket, stop: &AtomicBool, mut handle: F)
+where
+    F: FnMut(Message, SocketAddr, SystemTime),
+{
+    let mut buf = [0; 1500];
+    let mut warned = false;
+    while !stop.load(Ordering::Relaxed) {
+        let (len, peer) = match protocol::recv_from(socket, &mut buf, "PTP receive", &mut warned) {
+            Some(res) => res,
+            None => continue,
+        };
+        let time = SystemTime::now();
+        match Message::decode(&buf[..len]) {
+            Ok(Some(msg)) => handle(msg, peer, time),
+            Ok(None) => (),
+            Err(err) => println!("[WRN] Bad PTP message from {}: {}", peer, err),
+        }
+    }
+}
@@ -0,0 +1,516 @@
+use super::grandmaster::{self, Clients, Grandmaster, GrandmasterConfig};
+use super::management::*;
+use super::slave::{PtpSlave, SlaveConfig, Tracker};
+use super::*;
+use crate::clock;
+
+use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+fn identity(byte: u8) -> PortIdentity {
+    return PortIdentity {
+        clock: [byte; 8],
+        port: 1,
+    };
+}
+
+#[test]
+fn test_message_roundtrip() {
+    let ts = Timestamp {
+        seconds: 0x1234_5678_9abc,
+        nanoseconds: 999_999_999,
+    };
+    let bodies = [
+        Body::Sync(ts),
+        Body::DelayReq(ts),
+        Body::FollowUp(ts),
+        Body::DelayResp {
+            receive: ts,
+            requesting: identity(7),
+        },
+        Body::Announce(Announce {
+            origin: ts,
+            utc_offset: 37,
+            priority1: 128,
+            class: 248,
+            accuracy: 0xfe,
+            variance: 0xffff,
+            priority2: 127,
+            grandmaster: [3; 8],
+            steps_removed: 1,
+            time_source: 0xa0,
+        }),
+    ];
+    for (sequence, body) in bodies.iter().enumerate() {
+        let mut msg = Message::new(4, identity(1), sequence as u16, *body);
+        msg.flags = TWO_STEP | UTC_OFFSET_VALID;
+        msg.correction = -(5 << 16);
+        msg.log_interval = -3;
+        let buf = msg.encode();
+        assert_eq!(buf[1], 2);
+        assert_eq!(u16::from_be_bytes([buf[2], buf[3]]) as usize, buf.len());
+        assert_eq!(Message::decode(&buf), Ok(Some(msg)));
+        assert!(Message::decode(&buf[..buf.len() - 1]).is_err());
+    }
+    assert_eq!(
+        Timestamp::from_system(ts.to_system()),
+        ts,
+        "{:?}",
+        ts.to_system()
+    );
+    // Signaling messages are skipped
+    let mut signaling = Message::new(0, identity(1), 0, Body::Sync(ts)).encode();
+    signaling[0] = 0xc;
+    assert_eq!(Message::decode(&signaling), Ok(None));
+    assert!(Message::decode(&signaling[..20]).is_err());
+}
+
+fn announce(flags: u16) -> Message {
+    let mut msg = Message::new(
+        0,
+        identity(1),
+        0,
+        Body::Announce(Announce {
+            origin: Timestamp::default(),
+            utc_offset: 37,
+            priority1: 128,
+            class: 248,
+            accuracy: 0xfe,
+            variance: 0xffff,
+            priority2: 128,
+            grandmaster: identity(1).clock,
+            steps_removed: 0,
+            time_source: 0xa0,
+        }),
+    );
+    msg.flags = flags;
+    return msg;
+}
+
+/// Feeds a tracker the exchanges with a master whose clock runs `ppm`
+/// fast and `offset` seconds ahead, over a path of `delay` seconds. Every
+/// Sync is followed by an Announce with `flags`. Returns the tracker and
+/// the local time of the end.
+fn exchange(ppm: f64, offset: f64, delay: f64, flags: u16) -> (Tracker, SystemTime) {
+    let base = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let config = SlaveConfig {
+        delay_interval: Duration::from_millis(500),
+        ..SlaveConfig::default()
+    };
+    let mut tracker = Tracker::new(config, identity(2), base);
+    let master_clock =
+        |local: f64| Timestamp::from_system(clock::shift(base, local * (1. + ppm * 1e-6) + offset));
+    let mut local = 0.;
+    let mut sequence: u16 = 0;
+    while local < 30. {
+        // The Sync leaves the master `delay` before it is received
+        let origin = master_clock(local - delay);
+        let mut sync = Message::new(0, identity(1), sequence, Body::Sync(Timestamp::default()));
+        sync.flags = TWO_STEP;
+        let received = clock::shift(base, local);
+        if tracker.receive(&sync, received) {
+            tracker.receive(&announce(flags), received);
+            let follow_up = Message::new(0, identity(1), sequence, Body::FollowUp(origin));
+            tracker.receive(&follow_up, clock::shift(base, local + 1e-3));
+            if let Some(request) = tracker.delay_request(received) {
+                let response = Message::new(
+                    0,
+                    identity(1),
+                    request.sequence,
+                    Body::DelayResp {
+                        receive: master_clock(local + delay),
+                        requesting: request.source,
+                    },
+                );
+                tracker.receive(&response, clock::shift(base, local + 2e-3));
+            }
+        }
+        sequence = sequence.wrapping_add(1);
+        local += 0.125;
+    }
+    return (tracker, clock::shift(base, local));
+}
+
+#[test]
+fn test_tracker_offset() {
+    for &(ppm, offset) in &[(0., 0.), (20., 0.25), (-80., -3.5)] {
+        let delay = 150e-6;
+        let (tracker, end) = exchange(ppm, offset, delay, 0);
+        let status = tracker.status(end);
+        assert_eq!(status.master, Some(identity(1)));
+        let measured = status.delay.unwrap();
+        assert!((measured - delay).abs() < 1e-7, "{} s delay", measured);
+        // Network time minus local time keeps drifting with the master
+        let expected = offset + 30. * ppm * 1e-6;
+        let error = status.offset.unwrap() - expected;
+        assert!(
+            error.abs() < 1e-7,
+            "{} ppm {} s: {} s off",
+            ppm,
+            offset,
+            error
+        );
+    }
+}
+
+#[test]
+fn test_tracker_master_and_timescale() {
+    let (mut tracker, end) = exchange(0., 40., 100e-6, PTP_TIMESCALE);
+    assert!((tracker.offset(end).unwrap() - 40.).abs() < 1e-7);
+
+    // Another master is ignored while the current one is alive
+    let mut sync = Message::new(0, identity(9), 0, Body::Sync(Timestamp::default()));
+    assert!(!tracker.receive(&sync, end));
+    assert_eq!(tracker.status(end).master, Some(identity(1)));
+    let later = end + Duration::from_secs(10);
+    sync.domain = 1;
+    assert!(!tracker.receive(&sync, later));
+    sync.domain = 0;
+    assert!(tracker.receive(&sync, later));
+    let status = tracker.status(later);
+    assert_eq!(status.master, Some(identity(9)));
+    // The one-step Sync counts, the delay is measured anew
+    assert_eq!((status.offset, status.syncs), (None, 1));
+
+    // A master on the PTP timescale counts TAI, the slave wants UTC
+    let (tracker, end) = exchange(0., 40., 100e-6, PTP_TIMESCALE | UTC_OFFSET_VALID);
+    assert!((tracker.offset(end).unwrap() - 3.).abs() < 1e-7);
+}
+
+#[test]
+fn test_loopback() {
+    let slave = PtpSlave::start(SlaveConfig {
+        event_port: 0,
+        general_port: 0,
+        group: None,
+        delay_interval: Duration::from_millis(50),
+        ..SlaveConfig::default()
+    })
+    .unwrap();
+    let port = |addr: SocketAddr| SocketAddrV4::new(Ipv4Addr::LOCALHOST, addr.port());
+    let grandmaster = Grandmaster::start(GrandmasterConfig {
+        event_port: 0,
+        event_dest: port(slave.event_addr()),
+        general_dest: port(slave.general_addr()),
+        log_sync_interval: -6,
+        ..GrandmasterConfig::default()
+    })
+    .unwrap();
+    let start = Instant::now();
+    while slave.status().delay.is_none() || slave.status().syncs < 50 {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "{:?}",
+            slave.status()
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let status = slave.status();
+    assert_eq!(status.master, Some(grandmaster.identity()));
+    // Both ends share the clock
+    let offset = crate::clock::TimeSource::offset(&slave).unwrap();
+    assert!(offset.abs() < 1e-3, "{} s off", offset);
+    assert!(status.delay.unwrap().abs() < 1e-3, "{:?}", status);
+
+    let clients = grandmaster.clients();
+    assert_eq!(clients.len(), 1);
+    let (id, stats) = &clients[0];
+    assert_eq!(*id, slave.identity());
+    assert!(stats.requests > 1, "{:?}", stats);
+    let (delay, deviation) = stats.delay.unwrap();
+    assert!(delay.abs() < 1e-3 && deviation < 1e-3, "{:?}", stats);
+    // Requests follow Sync messages at most every 50 ms
+    assert!(stats.interval.unwrap() >= 0.05, "{:?}", stats);
+}
+
+#[test]
+fn test_grandmaster_messages() {
+    let listen = || {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, socket.local_addr().unwrap().port());
+        (socket, addr)
+    };
+    let (event, event_dest) = listen();
+    let (general, general_dest) = listen();
+    let config = GrandmasterConfig {
+        domain: 3,
+        event_port: 0,
+        event_dest,
+        general_dest,
+        log_sync_interval: -5,
+        log_announce_interval: -3,
+        log_delay_req_interval: -2,
+        ..GrandmasterConfig::default()
+    };
+    let grandmaster = Grandmaster::start(config).unwrap();
+    let next = |socket: &UdpSocket| {
+        let mut buf = [0; 1500];
+        let (len, peer) = socket.recv_from(&mut buf).unwrap();
+        (Message::decode(&buf[..len]).unwrap().unwrap(), peer)
+    };
+
+    let (mut announces, mut follow_ups) = (0, 0);
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(500) {
+        let (msg, _) = next(&general);
+        assert_eq!((msg.domain, msg.source), (3, grandmaster.identity()));
+        match msg.body {
+            Body::Announce(announce) => {
+                assert_eq!(msg.log_interval, -3);
+                assert_eq!(announce.grandmaster, grandmaster.identity().clock);
+                assert_eq!(msg.flags & (PTP_TIMESCALE | UTC_OFFSET_VALID), 0);
+                announces += 1;
+            }
+            Body::FollowUp(_) => {
+                assert_eq!(msg.log_interval, -5);
+                follow_ups += 1;
+            }
+            _ => panic!("Unexpected {:?}", msg),
+        }
+    }
+    // 1/8 s between announces and 1/32 s between syncs
+    assert!((3..=5).contains(&announces), "{} announces", announces);
+    assert!((12..=17).contains(&follow_ups), "{} follow ups", follow_ups);
+
+    let (sync, master) = next(&event);
+    assert_eq!((sync.flags & TWO_STEP, sync.log_interval), (TWO_STEP, -5));
+    let request = Message::new(
+        3,
+        identity(5),
+        77,
+        Body::DelayReq(Timestamp::from_system(SystemTime::now())),
+    );
+    event.send_to(&request.encode(), master).unwrap();
+    loop {
+        let (msg, _) = next(&general);
+        if let Body::DelayResp { requesting, .. } = msg.body {
+            assert_eq!((requesting, msg.sequence), (identity(5), 77));
+            assert_eq!(msg.log_interval, -2);
+            break;
+        }
+    }
+    let clients = grandmaster.clients();
+    assert_eq!(clients.len(), 1);
+    assert_eq!(clients[0].0, identity(5));
+    assert_eq!(clients[0].1.requests, 1);
+    assert_eq!(grandmaster::interval(-2), Duration::from_millis(250));
+}
+
+#[test]
+fn test_client_stats() {
+    let mut clients = Clients::new();
+    let addr: SocketAddr = "10.0.0.2:319".parse().unwrap();
+    let start = Instant::now();
+    let arrival = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    for n in 0..10 {
+        let delay = if n % 2 == 0 { 100e-6 } else { 300e-6 };
+        let origin = Timestamp::from_system(clock::shift(arrival, -delay));
+        let now = start + Duration::from_millis(250 * n);
+        clients.record(identity(2), addr, origin, arrival, now);
+        // ptp4l leaves the origin of its requests empty
+        clients.record(identity(1), addr, Timestamp::default(), arrival, now);
+    }
+    let stats = clients.stats();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].0, identity(1));
+    assert_eq!(stats[0].1.delay, None);
+    let stats = &stats[1].1;
+    assert_eq!(stats.requests, 10);
+    assert!((stats.interval.unwrap() - 0.25).abs() < 1e-9);
+    let (median, deviation) = stats.delay.unwrap();
+    assert!((median - 200e-6).abs() < 1e-9, "{}", median);
+    assert!((deviation - 105.4e-6).abs() < 0.1e-6, "{}", deviation);
+}
+
+fn time_status(master_offset: i64) -> TimeStatus {
+    return TimeStatus {
+        master_offset,
+        ingress_time: 1_600_000_000_123_456_789,
+        gm_present: true,
+        gm_identity: [3; 8],
+    };
+}
+
+fn port_data_set(state: PortState) -> PortDataSet {
+    return PortDataSet {
+        port: identity(2),
+        state,
+        log_min_delay_req_interval: 0,
+        peer_mean_path_delay: 0,
+        log_announce_interval: 1,
+        announce_receipt_timeout: 3,
+        log_sync_interval: 0,
+        delay_mechanism: 1,
+        log_min_pdelay_req_interval: 0,
+        version: 2,
+    };
+}
+
+#[test]
+fn test_management_roundtrip() {
+    let tlvs = [
+        Tlv::Empty(TIME_STATUS_NP),
+        Tlv::TimeStatus(time_status(-1234)),
+        Tlv::PortDataSet(port_data_set(PortState::Slave)),
+        Tlv::Error {
+            id: PORT_DATA_SET,
+            error: 0x0002,
+        },
+        Tlv::Empty(0x2000),
+    ];
+    for tlv in tlvs.iter() {
+        let msg = Message::new(
+            0,
+            identity(1),
+            9,
+            Body::Management(Management {
+                target: identity(2),
+                action: RESPONSE,
+                tlv: *tlv,
+            }),
+        );
+        let buf = msg.encode();
+        assert_eq!((buf[0] & 0x0f, buf[32]), (0xd, 4));
+        assert_eq!(Message::decode(&buf), Ok(Some(msg)));
+        assert!(Message::decode(&buf[..buf.len() - 1]).is_err());
+    }
+    assert_eq!(PortState::from(9), PortState::Slave);
+    assert_eq!(u8::from(PortState::Listening), 4);
+    assert_eq!(PortState::PreMaster.to_string(), "PRE_MASTER");
+}
+
+/// Answers GET requests on a Unix domain socket like `ptp4l`, with the
+/// datasets the test puts into `datasets`.
+struct FakePtp4l {
+    path: PathBuf,
+    datasets: Arc<Mutex<(TimeStatus, PortDataSet)>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakePtp4l {
+    fn start(name: &str) -> FakePtp4l {
+        let path = std::env::temp_dir().join(format!("piwfs-{}.{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let datasets = Arc::new(Mutex::new((
+            time_status(250),
+            port_data_set(PortState::Slave),
+        )));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_datasets = Arc::clone(&datasets);
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            while !thread_stop.load(Ordering::Relaxed) {
+                let (len, peer) = match socket.recv_from(&mut buf) {
+                    Ok(res) => res,
+                    Err(_) => continue,
+                };
+                let request = Message::decode(&buf[..len]).unwrap().unwrap();
+                let id = match request.body {
+                    Body::Management(management) => management.tlv.id(),
+                    _ => panic!("Unexpected {:?}", request),
+                };
+                let (time, port) = *thread_datasets.lock().unwrap();
+                let tlv = match id {
+                    TIME_STATUS_NP => Tlv::TimeStatus(time),
+                    PORT_DATA_SET => Tlv::PortDataSet(port),
+                    _ => Tlv::Error { id, error: 0x0002 },
+                };
+                let response = Message::new(
+                    request.domain,
+                    identity(2),
+                    request.sequence,
+                    Body::Management(Management {
+                        target: request.source,
+                        action: RESPONSE,
+                        tlv,
+                    }),
+                );
+                let peer = peer.as_pathname().unwrap().to_path_buf();
+                socket.send_to(&response.encode(), peer).unwrap();
+            }
+        });
+        return FakePtp4l {
+            path,
+            datasets,
+            stop,
+            thread: Some(thread),
+        };
+    }
+}
+
+impl Drop for FakePtp4l {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.take().unwrap().join().ok();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn test_pmc() {
+    let ptp4l = FakePtp4l::start("pmc");
+    let mut pmc = Pmc::connect(&ptp4l.path, 0).unwrap();
+    assert_eq!(pmc.time_status().unwrap(), time_status(250));
+    assert_eq!(
+        pmc.port_data_set().unwrap(),
+        port_data_set(PortState::Slave)
+    );
+    assert!(pmc.get(0x2000).is_err());
+}
+
+#[test]
+fn test_monitor() {
+    let ptp4l = FakePtp4l::start("monitor");
+    let wait_for = |monitor: &Monitor, state: PortState, offset: f64| {
+        let start = Instant::now();
+        while monitor.status().state != Some(state) || monitor.status().offset != Some(offset) {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "{:?}",
+                monitor.status()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    let monitor = Monitor::start(&ptp4l.path, 0, Duration::from_millis(20)).unwrap();
+    // The first poll is done right away
+    let status = monitor.status();
+    assert_eq!(status.state, Some(PortState::Slave));
+    assert_eq!(status.offset, Some(250e-9));
+    assert_eq!(status.problem(1e-6), None);
+
+    ptp4l.datasets.lock().unwrap().0 = time_status(-5000);
+    wait_for(&monitor, PortState::Slave, -5e-6);
+    assert!(monitor.status().problem(1e-6).unwrap().contains("-5 us"));
+    assert_eq!(monitor.status().problem(1e-5), None);
+
+    ptp4l.datasets.lock().unwrap().1 = port_data_set(PortState::Listening);
+    wait_for(&monitor, PortState::Listening, -5e-6);
+    assert!(monitor
+        .status()
+        .problem(1e-5)
+        .unwrap()
+        .contains("LISTENING"));
+
+    drop(ptp4l);
+    let start = Instant::now();
+    while monitor.status().error.is_none() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(monitor.status().problem(1.).is_some());
+}
@@ -0,0 +1,352 @@
+//! Fractional delay filters for the desync correction. A windowed sinc is
+//! tabulated for a number of fractional offsets (the polyphase table), the
+//! taps for any offset are interpolated between the two nearest phases, so
+//! no `sin()` is evaluated while playing. Presets trade the number of taps
+//! against passband flatness and stopband attenuation. `VariableRate`
+//! reads a stream at a position advancing by a variable step per frame,
+//! which follows a drifting clock without jumps.
+
+#[cfg(test)]
+mod tests;
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+/// Fractional offsets tabulated between two samples
+const PHASES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    /// Kaiser window with the given beta
+    Kaiser(f64),
+    Blackman,
+}
+
+impl Window {
+    /// Value at `x` in [-1, 1]
+    pub fn at(&self, x: f64) -> f64 {
+        return match self {
+            Window::Kaiser(beta) => {
+                bessel_i0(beta * (1. - x * x).max(0.).sqrt()) / bessel_i0(*beta)
+            }
+            Window::Blackman => 0.42 + 0.5 * (PI * x).cos() + 0.08 * (2. * PI * x).cos(),
+        };
+    }
+}
+
+/// Modified Bessel function of the first kind of order zero.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.;
+    let mut term = 1.;
+    let mut k = 1.;
+    while term > sum * 1e-12 {
+        term *= (x / (2. * k)) * (x / (2. * k));
+        sum += term;
+        k += 1.;
+    }
+    return sum;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    Fast,
+    Medium,
+    High,
+    Best,
+}
+
+/// Filter parameters of a preset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Design {
+    /// Taps on each side of the interpolated position
+    pub half_width: usize,
+    pub window: Window,
+    /// Cutoff frequency relative to the sample rate
+    pub cutoff: f64,
+}
+
+impl Quality {
+    pub fn design(&self) -> Design {
+        let (half_width, beta, cutoff) = match self {
+            Quality::Fast => (4, 4., 0.29),
+            Quality::Medium => (8, 4., 0.37),
+            Quality::High => (16, 4., 0.4175),
+            Quality::Best => (32, 7., 0.444),
+        };
+        return Design {
+            half_width,
+            window: Window::Kaiser(beta),
+            cutoff,
+        };
+    }
+}
+
+impl Default for Quality {
+    fn default() -> Quality {
+        return Quality::Medium;
+    }
+}
+
+impl FromStr for Quality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Quality, String> {
+        return match s {
+            "fast" => Ok(Quality::Fast),
+            "medium" => Ok(Quality::Medium),
+            "high" => Ok(Quality::High),
+            "best" => Ok(Quality::Best),
+            _ => Err(format!(
+                "Unknown quality {}, expected fast, medium, high or best",
+                s
+            )),
+        };
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            f,
+            "{}",
+            match self {
+                Quality::Fast => "fast",
+                Quality::Medium => "medium",
+                Quality::High => "high",
+                Quality::Best => "best",
+            }
+        );
+    }
+}
+
+pub struct FractionalDelay {
+    half_width: usize,
+    /// `PHASES + 1` rows of `2 * half_width` taps, row `p` reads the signal
+    /// `p / PHASES` samples after tap `half_width - 1`
+    table: Vec<f32>,
+}
+
+impl FractionalDelay {
+    pub fn new(design: Design) -> FractionalDelay {
+        let half_width = design.half_width;
+        let mut table = Vec::with_capacity((PHASES + 1) * 2 * half_width);
+        for phase in 0..=PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            let row: Vec<f64> = (0..2 * half_width)
+                .map(|tap| {
+                    let t = tap as f64 - (half_width - 1) as f64 - frac;
+                    let x = 2. * design.cutoff * t;
+                    let sinc = if x == 0. {
+                        1.
+                    } else {
+                        (PI * x).sin() / (PI * x)
+                    };
+                    sinc * design.window.at(t / half_width as f64)
+                })
+                .collect();
+            // Unity gain at DC for every offset
+            let sum: f64 = row.iter().sum();
+            table.extend(row.iter().map(|tap| (tap / sum) as f32));
+        }
+        return FractionalDelay { half_width, table };
+    }
+
+    pub fn half_width(&self) -> usize {
+        return self.half_width;
+    }
+
+    /// Phase below `frac` and the weight of the one above it
+    fn phase(&self, frac: f64) -> (usize, f32) {
+        let position = frac.clamp(0., 1.) * PHASES as f64;
+        let phase = (position as usize).min(PHASES - 1);
+        return (phase, (position - phase as f64) as f32);
+    }
+
+    /// Taps reading the signal `frac` samples, in [0, 1], after tap
+    /// `half_width - 1`.
+    pub fn taps(&self, frac: f64) -> Vec<f32> {
+        let mut taps = vec![0.; 2 * self.half_width];
+        self.taps_into(frac, &mut taps);
+        return taps;
+    }
+
+    /// Writes the `2 * half_width` taps for `frac` into `taps`, so
+    /// callers needing new ones for every frame don't allocate them.
+    pub fn taps_into(&self, frac: f64, taps: &mut [f32]) {
+        let len = 2 * self.half_width;
+        let (phase, weight) = self.phase(frac);
+        let (below, above) = self.table[phase * len..(phase + 2) * len].split_at(len);
+        for ((tap, a), b) in taps.iter_mut().zip(below).zip(above) {
+            *tap = a + weight * (b - a);
+        }
+    }
+
+    /// The `2 * half_width` samples of `window` read `frac` samples, in
+    /// [0, 1], after sample `half_width - 1`, the same as applying `taps`
+    /// without allocating them.
+    pub fn read(&self, window: &[f32], frac: f64) -> f32 {
+        let len = 2 * self.half_width;
+        let (phase, weight) = self.phase(frac);
+        let (below, above) = self.table[phase * len..(phase + 2) * len].split_at(len);
+        let (mut low, mut high) = (0., 0.);
+        for ((sample, a), b) in window.iter().zip(below).zip(above) {
+            low += a * sample;
+            high += b * sample;
+        }
+        return low + weight * (high - low);
+    }
+
+    /// Interleaved `buf` read `offset` samples after frame `n + half_width`
+    /// for every output frame `n`. The output is `2 * half_width + 1` frames
+    /// shorter, taps reaching outside of `buf` count as silence.
+    pub fn shift(&self, buf: &[f32], offset: f64, channels: usize) -> Vec<f32> {
+        let frames = buf.len() / channels;
+        let out_frames = frames.saturating_sub(2 * self.half_width + 1);
+        let taps = self.taps(offset - offset.floor());
+        // Input frame under the first tap for output frame 0
+        let first = offset.floor() as i64 + 1;
+        let mut out = vec![0.; out_frames * channels];
+        for frame in 0..out_frames {
+            for (tap, coeff) in taps.iter().enumerate() {
+                let input = first + (frame + tap) as i64;
+                if input < 0 || input >= frames as i64 {
+                    continue;
+                }
+                let input = input as usize * channels;
+                for channel in 0..channels {
+                    out[frame * channels + channel] += coeff * buf[input + channel];
+                }
+            }
+        }
+        return out;
+    }
+}
+
+/// Asynchronous sample rate converter. Every output frame reads the input
+/// stream at a fractional position with the taps of a `FractionalDelay`,
+/// the position advances by `step` stream frames per output frame.
+pub struct VariableRate {
+    filter: FractionalDelay,
+    channels: usize,
+    /// Interleaved input, starting at stream frame `first`
+    input: VecDeque<f32>,
+    first: i64,
+    /// Taps of the output frame being read, reused between frames
+    taps: Vec<f32>,
+    /// Stream position read by the next output frame
+    position: f64,
+    ended: bool,
+}
+
+impl VariableRate {
+    /// Converter reading stream frame 0 next, the input has to continue
+    /// from `end()`.
+    pub fn new(filter: FractionalDelay, channels: usize) -> VariableRate {
+        let first = 1 - filter.half_width() as i64;
+        let taps = vec![0.; 2 * filter.half_width()];
+        return VariableRate {
+            filter,
+            channels,
+            input: VecDeque::new(),
+            first,
+            taps,
+            position: 0.,
+            ended: false,
+        };
+    }
+
+    pub fn half_width(&self) -> usize {
+        return self.filter.half_width();
+    }
+
+    pub fn position(&self) -> f64 {
+        return self.position;
+    }
+
+    /// Stream frame following the buffered input
+    pub fn end(&self) -> i64 {
+        return self.first + (self.input.len() / self.channels) as i64;
+    }
+
+    /// First stream frame under the taps at `position`
+    fn first_tap(&self, position: f64) -> i64 {
+        return position.floor() as i64 + 1 - self.half_width() as i64;
+    }
+
+    /// Moves the read position to `position`. Returns false when the input
+    /// buffered so far is of no use there and the stream has to continue
+    /// from the new `end()`.
+    pub fn seek(&mut self, position: f64) -> bool {
+        let first = self.first_tap(position);
+        self.position = position;
+        if first >= self.first && first <= self.end() && !self.ended {
+            self.input
+                .drain(..(first - self.first) as usize * self.channels);
+            self.first = first;
+            return true;
+        }
+        self.input.clear();
+        self.first = first;
+        self.ended = false;
+        return false;
+    }
+
+    /// Stream frames to push before `frames` output frames can be read at
+    /// `step`.
+    pub fn needed(&self, frames: usize, step: f64) -> usize {
+        if self.ended || frames == 0 {
+            return 0;
+        }
+        let last = self.position + (frames - 1) as f64 * step;
+        let end = last.floor() as i64 + self.half_width() as i64 + 1;
+        return (end - self.end()).max(0) as usize;
+    }
+
+    /// Appends interleaved frames continuing the stream at `end()`.
+    pub fn push(&mut self, samples: &[f32]) {
+        self.input.extend(samples.iter());
+    }
+
+    /// Marks the stream as over, the last frames are read against silence.
+    pub fn end_of_stream(&mut self) {
+        if !self.ended {
+            let silence = self.half_width() * self.channels;
+            self.input.resize(self.input.len() + silence, 0.);
+            self.ended = true;
+        }
+    }
+
+    /// Reads up to `frames` output frames, fewer when the input runs out.
+    pub fn process(&mut self, frames: usize, step: f64) -> Vec<f32> {
+        let channels = self.channels;
+        let len = 2 * self.half_width();
+        let mut out = Vec::with_capacity(frames * channels);
+        for _ in 0..frames {
+            let first = self.first_tap(self.position);
+            if first + len as i64 > self.end() {
+                break;
+            }
+            self.filter
+                .taps_into(self.position - self.position.floor(), &mut self.taps);
+            let offset = out.len();
+            out.resize(offset + channels, 0.);
+            for (tap, coeff) in self.taps.iter().enumerate() {
+                let input = first + tap as i64 - self.first;
+                if input < 0 {
+                    continue;
+                }
+                let input = input as usize * channels;
+                for channel in 0..channels {
+                    out[offset + channel] += coeff * self.input[input + channel];
+                }
+            }
+            self.position += step;
+        }
+        let first = self.first_tap(self.position).clamp(self.first, self.end());
+        self.input.drain(..(first - self.first) as usize * channels);
+        self.first = first;
+        return out;
+    }
+}
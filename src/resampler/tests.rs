@@ -0,0 +1,191 @@
+use super::*;
+
+const QUALITIES: [Quality; 4] = [Quality::Fast, Quality::Medium, Quality::High, Quality::Best];
+
+/// Magnitude response of `taps` at `freq` relative to the sample rate.
+fn response(taps: &[f32], freq: f64) -> f64 {
+    let (mut re, mut im) = (0., 0.);
+    for (k, tap) in taps.iter().enumerate() {
+        let phase = 2. * PI * freq * k as f64;
+        re += *tap as f64 * phase.cos();
+        im -= *tap as f64 * phase.sin();
+    }
+    return re.hypot(im);
+}
+
+/// Largest deviation from unity gain below `pass` and smallest
+/// attenuation above `stop`, in dB, over fractional offsets.
+fn measure(filter: &FractionalDelay, pass: f64, stop: f64) -> (f64, f64) {
+    let mut ripple: f64 = 0.;
+    let mut leak: f64 = 0.;
+    for offset in 0..=32 {
+        let taps = filter.taps(offset as f64 / 32.);
+        for step in 0..=200 {
+            let freq = pass * step as f64 / 200.;
+            ripple = ripple.max(20. * response(&taps, freq).log10().abs());
+            let freq = stop + (0.5 - stop) * step as f64 / 200.;
+            leak = leak.max(response(&taps, freq));
+        }
+    }
+    return (ripple, -20. * leak.log10());
+}
+
+#[test]
+fn test_presets() {
+    // Passband edge, ripple, stopband edge, attenuation
+    let specs = [
+        (0.15, 0.25, 0.45, 35.),
+        (0.3, 0.25, 0.45, 40.),
+        (0.38, 0.2, 0.46, 42.),
+        (0.42, 0.25, 0.48, 65.),
+    ];
+    for (quality, &(pass, max_ripple, stop, min_attenuation)) in QUALITIES.iter().zip(specs.iter())
+    {
+        let filter = FractionalDelay::new(quality.design());
+        let (ripple, attenuation) = measure(&filter, pass, stop);
+        assert!(ripple < max_ripple, "{}: {} dB ripple", quality, ripple);
+        assert!(
+            attenuation > min_attenuation,
+            "{}: {} dB attenuation",
+            quality,
+            attenuation
+        );
+    }
+}
+
+#[test]
+fn test_windows() {
+    assert!((Window::Blackman.at(0.) - 1.).abs() < 1e-12);
+    assert!(Window::Blackman.at(1.).abs() < 1e-12);
+    assert!((Window::Kaiser(8.).at(0.) - 1.).abs() < 1e-12);
+    assert!((Window::Kaiser(8.).at(-1.) - 1. / bessel_i0(8.)).abs() < 1e-12);
+    assert!((bessel_i0(1.) - 1.266_065_877_752_008_4).abs() < 1e-12);
+
+    // A wider window main lobe buys more attenuation with the same taps
+    let design = |window| Design {
+        half_width: 16,
+        window,
+        cutoff: 0.35,
+    };
+    let (_, kaiser) = measure(&FractionalDelay::new(design(Window::Kaiser(4.))), 0.2, 0.45);
+    let (_, blackman) = measure(&FractionalDelay::new(design(Window::Blackman)), 0.2, 0.45);
+    assert!(blackman > kaiser && kaiser > 40., "{} {}", blackman, kaiser);
+}
+
+#[test]
+fn test_taps() {
+    let filter = FractionalDelay::new(Quality::High.design());
+    for offset in 0..=10 {
+        let taps = filter.taps(offset as f64 / 10.);
+        assert_eq!(taps.len(), 32);
+        let sum: f32 = taps.iter().sum();
+        assert!((sum - 1.).abs() < 1e-5);
+    }
+    assert_eq!(filter.taps(-0.5), filter.taps(0.));
+    assert_eq!(filter.taps(1.5), filter.taps(1.));
+
+    let window: Vec<f32> = (0..32).map(|n| (n as f32 * 0.3).sin()).collect();
+    for &frac in &[0., 0.123, 0.5, 0.999, 1.] {
+        let applied: f32 = filter
+            .taps(frac)
+            .iter()
+            .zip(&window)
+            .map(|(tap, sample)| tap * sample)
+            .sum();
+        assert!((filter.read(&window, frac) - applied).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn test_shift() {
+    let freq = 0.05;
+    let signal = |t: f64| (2. * PI * freq * t).sin() as f32;
+    let buf: Vec<f32> = (0..400)
+        .flat_map(|n| vec![signal(n as f64), -signal(n as f64)])
+        .collect();
+    for quality in QUALITIES.iter() {
+        let filter = FractionalDelay::new(quality.design());
+        let half_width = filter.half_width();
+        for &offset in &[0., 0.25, 0.5, 0.999, 3.4, -2.7] {
+            let out = filter.shift(&buf, offset, 2);
+            assert_eq!(out.len(), buf.len() - 2 * (2 * half_width + 1));
+            // Away from the edges where taps reach outside of the buffer
+            for frame in 10..out.len() / 2 - 10 {
+                let expected = signal(frame as f64 + half_width as f64 + offset);
+                assert!(
+                    (out[2 * frame] - expected).abs() < 0.03,
+                    "{} at {}: {} != {}",
+                    quality,
+                    offset,
+                    out[2 * frame],
+                    expected
+                );
+                assert_eq!(out[2 * frame], -out[2 * frame + 1]);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_quality() {
+    for quality in QUALITIES.iter() {
+        assert_eq!(quality.to_string().parse::<Quality>(), Ok(*quality));
+    }
+    assert_eq!(Quality::default(), Quality::Medium);
+    assert!("2".parse::<Quality>().is_err());
+}
+
+#[test]
+fn test_variable_rate() {
+    let freq = 0.05;
+    let signal = |t: f64| (2. * PI * freq * t).sin() as f32;
+    let stream = |from: i64, frames: usize| -> Vec<f32> {
+        (from..from + frames as i64)
+            .flat_map(|n| {
+                let sample = if n < 0 { 0. } else { signal(n as f64) };
+                vec![sample, -sample]
+            })
+            .collect()
+    };
+    let mut converter = VariableRate::new(FractionalDelay::new(Quality::Medium.design()), 2);
+    assert_eq!(converter.end(), -7);
+    let mut expected = 0.;
+    // Steps change every block, the position stays continuous
+    for (block, &step) in [1., 1.0005, 0.9993, 1.002, 1.].iter().enumerate() {
+        let needed = converter.needed(256, step);
+        let samples = stream(converter.end(), needed);
+        converter.push(&samples);
+        let out = converter.process(256, step);
+        assert_eq!(out.len(), 2 * 256);
+        for frame in 0..256 {
+            if block > 0 || frame > 10 {
+                assert!(
+                    (out[2 * frame] - signal(expected)).abs() < 0.03,
+                    "{} at {}",
+                    out[2 * frame],
+                    expected
+                );
+            }
+            assert_eq!(out[2 * frame], -out[2 * frame + 1]);
+            expected += step;
+        }
+        assert!((converter.position() - expected).abs() < 1e-9);
+    }
+
+    // Short seeks keep the buffered input
+    let end = converter.end();
+    assert!(converter.seek(expected + 2.5));
+    assert_eq!(converter.end(), end);
+    assert!(!converter.seek(expected + 1000.));
+    assert_eq!(converter.end(), (expected + 1000.).floor() as i64 - 7);
+
+    // Without more input only what the taps cover is read
+    let samples = stream(converter.end(), 100);
+    converter.push(&samples);
+    let out = converter.process(256, 1.);
+    assert_eq!(out.len(), 2 * 85);
+    converter.end_of_stream();
+    assert_eq!(converter.needed(256, 1.), 0);
+    assert_eq!(converter.process(256, 1.).len(), 2 * 8);
+    assert!(converter.process(256, 1.).is_empty());
+}
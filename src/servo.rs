@@ -0,0 +1,118 @@
+//! Clock servo steering the file position towards the schedule, modeled on
+//! the PI servo of linuxptp. Every period it is given the offset of the
+//! schedule from the position it steers and answers with a frequency
+//! correction in ppm, or with a jump when the offset is too large to slew.
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServoConfig {
+    /// Proportional gain, ppm of correction per microsecond of offset
+    pub kp: f64,
+    /// Integral gain, ppm of drift per microsecond of offset and second
+    pub ki: f64,
+    /// Offset beyond which the servo jumps instead of slewing, in seconds
+    pub step_threshold: f64,
+    /// Largest frequency correction, in ppm
+    pub max_slew: f64,
+    /// Largest drift the integral term settles on, in ppm
+    pub integral_limit: f64,
+}
+
+impl Default for ServoConfig {
+    fn default() -> ServoConfig {
+        return ServoConfig {
+            kp: 4.,
+            ki: 4.,
+            step_threshold: 0.002,
+            max_slew: 500.,
+            integral_limit: 300.,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServoState {
+    /// No offset seen yet
+    Unlocked,
+    /// The last offset was corrected by a jump
+    Jump,
+    /// Following the schedule by slewing
+    Locked,
+}
+
+pub struct PiServo {
+    config: ServoConfig,
+    /// Frequency correction accumulated by the integral term, in ppm
+    drift: f64,
+    /// Correction of the last sample, in ppm
+    ppm: f64,
+    /// Time of the last sample, in seconds
+    last: Option<f64>,
+    state: ServoState,
+    /// Offsets slewed even beyond the step threshold, in seconds
+    tolerance: f64,
+}
+
+impl PiServo {
+    pub fn new(config: ServoConfig) -> PiServo {
+        return PiServo {
+            config,
+            drift: 0.,
+            ppm: 0.,
+            last: None,
+            state: ServoState::Unlocked,
+            tolerance: 0.,
+        };
+    }
+
+    pub fn state(&self) -> ServoState {
+        return self.state;
+    }
+
+    pub fn drift(&self) -> f64 {
+        return self.drift;
+    }
+
+    /// Frequency correction of the last sample in ppm
+    pub fn ppm(&self) -> f64 {
+        return self.ppm;
+    }
+
+    /// Raises the step threshold to `seconds`, the error of the time the
+    /// offsets are measured against. Jumping for less would only follow
+    /// its noise.
+    pub fn set_tolerance(&mut self, seconds: f64) {
+        self.tolerance = seconds;
+    }
+
+    /// Takes the offset of the schedule from the steered position at `time`,
+    /// both in seconds. Positive offsets need the position to speed up.
+    /// Returns the frequency correction to apply until the next sample, the
+    /// caller has to jump by `offset` first when the state becomes `Jump`.
+    pub fn sample(&mut self, offset: f64, time: f64) -> f64 {
+        let interval = self.last.map_or(0., |last| (time - last).max(0.));
+        self.last = Some(time);
+        if offset.abs() > self.config.step_threshold.max(self.tolerance) {
+            self.state = ServoState::Jump;
+            self.ppm = self.drift;
+            return self.ppm;
+        }
+        self.state = ServoState::Locked;
+        let ki_term = 1e6 * self.config.ki * offset * interval;
+        let ppm = 1e6 * self.config.kp * offset + self.drift + ki_term;
+        let max_slew = self.config.max_slew;
+        self.ppm = if ppm > max_slew {
+            max_slew
+        } else if ppm < -max_slew {
+            -max_slew
+        } else {
+            // Only integrate while the output is not saturated
+            let limit = self.config.integral_limit;
+            self.drift = (self.drift + ki_term).clamp(-limit, limit);
+            ppm
+        };
+        return self.ppm;
+    }
+}
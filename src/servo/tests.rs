@@ -0,0 +1,79 @@
+use super::*;
+
+/// Runs the servo against a clock `ppm` off for `seconds`, sampling every
+/// `interval` seconds. Returns the servo and the final offset.
+fn simulate(config: ServoConfig, ppm: f64, offset: f64, seconds: f64) -> (PiServo, f64) {
+    let interval = 0.02;
+    let mut servo = PiServo::new(config);
+    let mut offset = offset;
+    let mut time = 0.;
+    while time < seconds {
+        let correction = servo.sample(offset, time);
+        if servo.state() == ServoState::Jump {
+            offset = 0.;
+        }
+        offset += (ppm - correction) * 1e-6 * interval;
+        time += interval;
+    }
+    return (servo, offset);
+}
+
+#[test]
+fn test_converges() {
+    for &ppm in &[-120., -3., 0., 45., 250.] {
+        let (servo, offset) = simulate(ServoConfig::default(), ppm, 100e-6, 20.);
+        assert_eq!(servo.state(), ServoState::Locked);
+        assert!(offset.abs() < 0.1e-6, "{} ppm: {} s off", ppm, offset);
+        assert!(
+            (servo.drift() - ppm).abs() < 0.1,
+            "{} ppm: {}",
+            ppm,
+            servo.drift()
+        );
+    }
+}
+
+#[test]
+fn test_step_threshold() {
+    let mut servo = PiServo::new(ServoConfig::default());
+    assert_eq!(servo.state(), ServoState::Unlocked);
+    servo.sample(0.01, 0.);
+    assert_eq!(servo.state(), ServoState::Jump);
+    assert_eq!(servo.ppm(), 0.);
+    servo.sample(0.001, 0.02);
+    assert_eq!(servo.state(), ServoState::Locked);
+    servo.sample(-0.003, 0.04);
+    assert_eq!(servo.state(), ServoState::Jump);
+}
+
+#[test]
+fn test_max_slew() {
+    let mut servo = PiServo::new(ServoConfig::default());
+    assert_eq!(servo.sample(0.0015, 0.), 500.);
+    assert_eq!(servo.sample(-0.0015, 0.02), -500.);
+    // Saturated samples leave the integral alone
+    assert_eq!(servo.drift(), 0.);
+    let config = ServoConfig {
+        max_slew: 50.,
+        ..ServoConfig::default()
+    };
+    let (servo, _) = simulate(config, 80., 0., 10.);
+    assert_eq!(servo.ppm(), 50.);
+    assert!(servo.drift() <= 50.);
+}
+
+#[test]
+fn test_integral_limit() {
+    let config = ServoConfig {
+        integral_limit: 20.,
+        ..ServoConfig::default()
+    };
+    let (servo, offset) = simulate(config, 60., 0., 20.);
+    assert_eq!(servo.drift(), 20.);
+    // The proportional term makes up for the rest with a standing offset
+    assert!(
+        (offset - 40e-6 / config.kp).abs() < 0.5e-6,
+        "{} s off",
+        offset
+    );
+}
@@ -0,0 +1,172 @@
+use crate::geometry::Geometry;
+use crate::protocol::Message;
+use crate::resampler::Quality;
+use crate::wfs::{Source, Trajectory, Vec2};
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ArgMatches;
+
+/// Everything a slave needs to know to take part in a playback session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    /// Start of playback in nanoseconds since the UNIX epoch
+    pub startat: u64,
+    pub testfile: String,
+    pub quality: Quality,
+    pub desync_avg: usize,
+    pub estimation_avg: usize,
+    /// Virtual source to render, the file is played as is when there is none
+    pub source: Option<Source>,
+    /// Path of a moving point source, used instead of `source`
+    pub trajectory: Option<Trajectory>,
+    /// Point at which the rendered amplitude is correct
+    pub reference: Vec2,
+    /// Rendering delay common to all loudspeakers in seconds
+    pub predelay: f64,
+    /// Contents of the array geometry file
+    pub geometry: Option<String>,
+    /// Plays the file or playlist over and over instead of stopping at its end
+    pub looping: bool,
+}
+
+impl Session {
+    pub fn from_args(args: &ArgMatches) -> Session {
+        let startat = match args.value_of("startat") {
+            Some(startat) => startat
+                .parse::<u64>()
+                .expect("[ERR] Couldn't parse startat as a unsigned integer number"),
+            None => {
+                let delay = args
+                    .value_of("delay")
+                    .unwrap_or("10")
+                    .parse::<f64>()
+                    .expect("[ERR] Couldn't parse delay as a number");
+                (SystemTime::now() + Duration::from_secs_f64(delay))
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64
+            }
+        };
+        return Session {
+            startat,
+            testfile: args.value_of("testfile").unwrap().to_string(),
+            quality: args
+                .value_of("quality")
+                .map_or(Quality::default(), |quality| {
+                    quality
+                        .parse()
+                        .unwrap_or_else(|err| panic!("[ERR] Couldn't parse quality: {}", err))
+                }),
+            desync_avg: args
+                .value_of("desync-avg")
+                .unwrap_or("1000")
+                .parse::<usize>()
+                .expect("[ERR] Couldn't parse average as an unsigned integer"),
+            estimation_avg: args
+                .value_of("estimation-avg")
+                .unwrap_or("1000")
+                .parse::<usize>()
+                .expect("[ERR] Couldn't parse average as an unsigned integer"),
+            source: args.value_of("source").map(|source| {
+                source
+                    .parse()
+                    .unwrap_or_else(|err| panic!("[ERR] Couldn't parse source: {}", err))
+            }),
+            trajectory: args.value_of("trajectory").map(|trajectory| {
+                trajectory
+                    .parse()
+                    .unwrap_or_else(|err| panic!("[ERR] Couldn't parse trajectory: {}", err))
+            }),
+            reference: args
+                .value_of("reference")
+                .unwrap_or("0,0")
+                .parse()
+                .unwrap_or_else(|err| panic!("[ERR] Couldn't parse reference: {}", err)),
+            predelay: args
+                .value_of("predelay")
+                .unwrap_or("50")
+                .parse::<f64>()
+                .expect("[ERR] Couldn't parse predelay as a number")
+                / 1000.,
+            geometry: args.value_of("geometry").map(|path| {
+                let text = std::fs::read_to_string(path)
+                    .unwrap_or_else(|err| panic!("[ERR] Couldn't read {}: {}", path, err));
+                let geometry = Geometry::parse(&text)
+                    .unwrap_or_else(|err| panic!("[ERR] Invalid geometry {}: {}", path, err));
+                for warning in geometry.warnings() {
+                    println!("[WRN] {}: {}", path, warning);
+                }
+                text
+            }),
+            looping: args.is_present("loop"),
+        };
+    }
+
+    pub fn startstamp(&self) -> SystemTime {
+        return UNIX_EPOCH + Duration::from_nanos(self.startat);
+    }
+
+    /// Virtual source to render at `time` seconds after the start.
+    pub fn source_at(&self, time: f64) -> Option<Source> {
+        return match &self.trajectory {
+            Some(trajectory) => Some(Source::Point(trajectory.position(time))),
+            None => self.source,
+        };
+    }
+
+    pub fn to_message(&self) -> Message {
+        let msg = Message::new("SESSION")
+            .with("startat", self.startat)
+            .with("testfile", &self.testfile)
+            .with("quality", self.quality)
+            .with("desync-avg", self.desync_avg)
+            .with("estimation-avg", self.estimation_avg)
+            .with("reference", self.reference)
+            .with("predelay", self.predelay);
+        let msg = match self.source {
+            Some(source) => msg.with("source", source),
+            None => msg,
+        };
+        let msg = match &self.trajectory {
+            Some(trajectory) => msg.with("trajectory", trajectory),
+            None => msg,
+        };
+        let msg = if self.looping {
+            msg.with("loop", true)
+        } else {
+            msg
+        };
+        return match &self.geometry {
+            Some(geometry) => msg.with("geometry", geometry),
+            None => msg,
+        };
+    }
+
+    /// Builds a session from a `SESSION` or `LOAD` message. A missing
+    /// `startat` is left as zero, the session is then not armed yet.
+    pub fn from_message(msg: &Message) -> Result<Session, String> {
+        return Ok(Session {
+            startat: msg.parse("startat")?.unwrap_or(0),
+            testfile: msg
+                .get("testfile")
+                .ok_or_else(|| "Missing testfile".to_string())?
+                .to_string(),
+            quality: msg.parse("quality")?.unwrap_or_default(),
+            desync_avg: msg.parse("desync-avg")?.unwrap_or(1000),
+            estimation_avg: msg.parse("estimation-avg")?.unwrap_or(1000),
+            source: msg.parse("source")?,
+            trajectory: msg.parse("trajectory")?,
+            reference: msg.parse("reference")?.unwrap_or_default(),
+            predelay: msg.parse("predelay")?.unwrap_or(0.05),
+            geometry: match msg.get("geometry") {
+                Some(text) => {
+                    Geometry::parse(text).map_err(|err| format!("Invalid geometry: {}", err))?;
+                    Some(text.to_string())
+                }
+                None => None,
+            },
+            looping: msg.parse("loop")?.unwrap_or(false),
+        });
+    }
+}
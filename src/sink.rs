@@ -0,0 +1,77 @@
+//! Audio outputs the synchronization loop can drive. A sink consumes
+//! interleaved frames and reports, for a point in time, how many frames are
+//! still queued before the one written next is heard. Samples are floats
+//! with full scale at ±1, each sink converts them to what its device takes.
+
+mod alsa;
+#[cfg(test)]
+pub mod sim;
+
+#[cfg(test)]
+mod tests;
+
+pub use self::alsa::AlsaSink;
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkState {
+    /// Ready, waiting for the start threshold to be reached
+    Prepared,
+    Running,
+    /// Ran out of frames, has to be prepared again
+    Underrun,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    /// Time at which `delay` was measured
+    pub stamp: SystemTime,
+    /// Frames queued before the next written frame is played
+    pub delay: i64,
+    pub state: SinkState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkError {
+    Underrun,
+    Device(String),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            SinkError::Underrun => write!(f, "Buffer underrun"),
+            SinkError::Device(err) => write!(f, "{}", err),
+        };
+    }
+}
+
+/// Rounds `sample` to a signed integer of `bits` bits, clipping at full
+/// scale.
+pub fn quantize(sample: f32, bits: u32) -> i32 {
+    let scale = (1i64 << (bits - 1)) as f64;
+    return (sample as f64 * scale).round().max(-scale).min(scale - 1.) as i32;
+}
+
+pub trait AudioSink {
+    fn period_size(&self) -> i64;
+    fn buffer_size(&self) -> i64;
+    /// Playback starts once this many frames are queued.
+    fn set_start_threshold(&mut self, frames: i64) -> Result<(), SinkError>;
+    fn status(&mut self) -> Result<Status, SinkError>;
+    fn state(&self) -> SinkState;
+    /// Queues interleaved samples, returns the number of frames written.
+    fn write(&mut self, buf: &[f32]) -> Result<usize, SinkError>;
+    /// Recovers from an underrun.
+    fn prepare(&mut self) -> Result<(), SinkError>;
+    /// Blocks until all queued frames are played.
+    fn drain(&mut self) -> Result<(), SinkError>;
+    /// Waits between two status queries, sinks with their own notion of
+    /// time may override it.
+    fn sleep(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
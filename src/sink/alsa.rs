@@ -0,0 +1,187 @@
+use super::{quantize, AudioSink, SinkError, SinkState, Status};
+use crate::clock::{Timebase, TstampClock, MAX_SLEW};
+
+use alsa::pcm::{Access, Format, HwParams, State, TstampType, PCM};
+use alsa::{Direction, ValueOr};
+
+use std::convert::TryInto;
+use std::time::Duration;
+
+impl From<alsa::Error> for SinkError {
+    fn from(err: alsa::Error) -> SinkError {
+        if err.errno() == Some(nix::errno::Errno::EPIPE) {
+            return SinkError::Underrun;
+        }
+        return SinkError::Device(err.to_string());
+    }
+}
+
+/// Formats tried when opening a device, most precise first. Plugin devices
+/// take floats and convert them themselves.
+const FORMATS: [Format; 5] = [
+    Format::FloatLE,
+    Format::S32LE,
+    Format::S24LE,
+    Format::S243LE,
+    Format::S16LE,
+];
+
+/// Interleaved playback on an ALSA device in the most precise format it
+/// accepts. Statuses are timestamped with `clock` and mapped to the system
+/// clock.
+pub struct AlsaSink {
+    pcm: PCM,
+    format: Format,
+    timebase: Timebase,
+    period_size: i64,
+    buffer_size: i64,
+}
+
+impl AlsaSink {
+    pub fn open(
+        device: &str,
+        channels: u32,
+        rate: u32,
+        clock: TstampClock,
+    ) -> Result<AlsaSink, SinkError> {
+        let pcm = PCM::new(device, Direction::Playback, false)?;
+        {
+            let hwp = HwParams::any(&pcm)?;
+            hwp.set_channels(channels)?;
+            hwp.set_rate(rate, ValueOr::Nearest)?;
+            let format = FORMATS
+                .iter()
+                .copied()
+                .find(|format| hwp.test_format(*format).is_ok())
+                .ok_or_else(|| SinkError::Device("No supported sample format".to_string()))?;
+            hwp.set_format(format)?;
+            hwp.set_access(Access::RWInterleaved)?;
+            pcm.hw_params(&hwp)?;
+        }
+        let (format, period_size, buffer_size) = {
+            let hwp = pcm.hw_params_current()?;
+            (
+                hwp.get_format()?,
+                hwp.get_period_size()?,
+                hwp.get_buffer_size()?,
+            )
+        };
+        {
+            let swp = pcm.sw_params_current()?;
+            swp.set_tstamp_mode(true)?;
+            swp.set_tstamp_type(match clock {
+                TstampClock::Realtime => TstampType::Gettimeofday,
+                TstampClock::Monotonic => TstampType::Monotonic,
+                TstampClock::MonotonicRaw => TstampType::MonotonicRaw,
+            })?;
+            pcm.sw_params(&swp)?;
+        }
+        return Ok(AlsaSink {
+            pcm,
+            format,
+            timebase: Timebase::new(clock, MAX_SLEW),
+            period_size,
+            buffer_size,
+        });
+    }
+
+    pub fn format(&self) -> Format {
+        return self.format;
+    }
+
+    /// Queries the maximum channel count and sample rate of the device.
+    pub fn probe(device: &str) -> Result<(u32, u32), SinkError> {
+        let pcm = PCM::new(device, Direction::Playback, false)?;
+        let hwp = HwParams::any(&pcm)?;
+        return Ok((hwp.get_channels_max()?, hwp.get_rate_max()?));
+    }
+}
+
+fn sink_state(state: State) -> SinkState {
+    return match state {
+        State::Prepared => SinkState::Prepared,
+        State::Running => SinkState::Running,
+        State::XRun => SinkState::Underrun,
+        _ => SinkState::Other,
+    };
+}
+
+impl AudioSink for AlsaSink {
+    fn period_size(&self) -> i64 {
+        return self.period_size;
+    }
+
+    fn buffer_size(&self) -> i64 {
+        return self.buffer_size;
+    }
+
+    fn set_start_threshold(&mut self, frames: i64) -> Result<(), SinkError> {
+        let swp = self.pcm.sw_params_current()?;
+        swp.set_start_threshold(frames)?;
+        self.pcm.sw_params(&swp)?;
+        return Ok(());
+    }
+
+    fn status(&mut self) -> Result<Status, SinkError> {
+        let status = self.pcm.status()?;
+        let stamp = status.get_htstamp();
+        self.timebase.measure();
+        return Ok(Status {
+            stamp: self.timebase.to_system(Duration::new(
+                stamp.tv_sec.try_into().unwrap(),
+                stamp.tv_nsec.try_into().unwrap(),
+            )),
+            delay: status.get_delay(),
+            state: sink_state(status.get_state()),
+        });
+    }
+
+    fn state(&self) -> SinkState {
+        return sink_state(self.pcm.state());
+    }
+
+    fn write(&mut self, buf: &[f32]) -> Result<usize, SinkError> {
+        return Ok(match self.format {
+            Format::FloatLE => {
+                let buf: Vec<f32> = buf.iter().map(|&sample| sample.clamp(-1., 1.)).collect();
+                self.pcm.io_f32()?.writei(&buf)?
+            }
+            Format::S32LE => {
+                let buf: Vec<i32> = buf.iter().map(|&sample| quantize(sample, 32)).collect();
+                self.pcm.io_i32()?.writei(&buf)?
+            }
+            Format::S24LE => {
+                let bytes: Vec<u8> = buf
+                    .iter()
+                    .flat_map(|&sample| quantize(sample, 24).to_le_bytes())
+                    .collect();
+                self.pcm.io_bytes().writei(&bytes)?
+            }
+            Format::S243LE => {
+                let bytes: Vec<u8> = buf
+                    .iter()
+                    .flat_map(|&sample| {
+                        let bytes = quantize(sample, 24).to_le_bytes();
+                        [bytes[0], bytes[1], bytes[2]]
+                    })
+                    .collect();
+                self.pcm.io_bytes().writei(&bytes)?
+            }
+            _ => {
+                let buf: Vec<i16> = buf
+                    .iter()
+                    .map(|&sample| quantize(sample, 16) as i16)
+                    .collect();
+                self.pcm.io_i16()?.writei(&buf)?
+            }
+        });
+    }
+
+    fn prepare(&mut self) -> Result<(), SinkError> {
+        return Ok(self.pcm.prepare()?);
+    }
+
+    fn drain(&mut self) -> Result<(), SinkError> {
+        return Ok(self.pcm.drain()?);
+    }
+}
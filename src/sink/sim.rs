@@ -0,0 +1,302 @@
+//! Sound card simulated on a virtual clock. Time only moves when the player
+//! queries the status, sleeps or blocks in `write`, so a whole playback runs
+//! in a fraction of real time and, for a given seed, always the same way.
+//! Sinks created with the same `origin` live on one true timeline and their
+//! recordings can be compared to measure how well they are in sync.
+
+use super::{AudioSink, SinkError, SinkState, Status};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Small xorshift generator, good enough for noise and reproducible.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        return Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1);
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        return self.0.wrapping_mul(0x2545_F491_4F6C_DD1D);
+    }
+
+    /// Uniform in [0, 1)
+    pub fn uniform(&mut self) -> f64 {
+        return (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+    }
+
+    /// Standard normal distribution
+    pub fn gaussian(&mut self) -> f64 {
+        let u = 1. - self.uniform();
+        let v = self.uniform();
+        return (-2. * u.ln()).sqrt() * (2. * std::f64::consts::PI * v).cos();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// True time at which the simulation starts
+    pub origin: SystemTime,
+    /// Offset of the true sample rate from the nominal one
+    pub ppm: f64,
+    /// Change of the offset in ppm per second
+    pub drift: f64,
+    /// Standard deviation of the scheduling latency after every wait, seconds
+    pub jitter: f64,
+    /// Standard deviation of the error of status timestamps, seconds
+    pub tstamp_noise: f64,
+    /// Probability that a write is followed by a hiccup
+    pub hiccup_chance: f64,
+    /// Time the player stalls for on a hiccup, seconds
+    pub hiccup: f64,
+    /// Time a status query takes, seconds
+    pub status_cost: f64,
+    pub period_size: i64,
+    pub buffer_size: i64,
+    pub seed: u64,
+}
+
+impl SimConfig {
+    pub fn new(origin: SystemTime) -> SimConfig {
+        return SimConfig {
+            origin,
+            ppm: 0.,
+            drift: 0.,
+            jitter: 0.,
+            tstamp_noise: 0.,
+            hiccup_chance: 0.,
+            hiccup: 0.,
+            status_cost: 5e-6,
+            period_size: 1024,
+            buffer_size: 8192,
+            seed: 1,
+        };
+    }
+
+    /// Frames the card consumes between the origin and `time` seconds
+    /// after it, if it ran the whole time.
+    fn phase(&self, rate: f64, time: f64) -> f64 {
+        return rate * (time + 1e-6 * (self.ppm * time + self.drift * time * time / 2.));
+    }
+
+    fn time_of_phase(&self, rate: f64, phase: f64) -> f64 {
+        let mut time = phase / rate;
+        for _ in 0..3 {
+            let speed = rate * (1. + 1e-6 * (self.ppm + self.drift * time));
+            time += (phase - self.phase(rate, time)) / speed;
+        }
+        return time;
+    }
+}
+
+/// Everything a simulated card played.
+pub struct Recording {
+    config: SimConfig,
+    rate: f64,
+    pub channels: usize,
+    /// Interleaved samples in the order they were played
+    pub samples: Vec<f32>,
+    /// Start time (seconds after the origin) and first frame of every run
+    /// between underruns
+    pub runs: Vec<(f64, usize)>,
+}
+
+impl Recording {
+    /// Fractional index of the frame being played `time` seconds after the
+    /// origin, `None` when the card is silent.
+    pub fn frame_at(&self, time: f64) -> Option<f64> {
+        let run = self.runs.iter().rposition(|(start, _)| *start <= time)?;
+        let (start, first) = self.runs[run];
+        let end = self
+            .runs
+            .get(run + 1)
+            .map_or(self.samples.len() / self.channels, |(_, first)| *first);
+        let frame =
+            first as f64 + self.config.phase(self.rate, time) - self.config.phase(self.rate, start);
+        return if frame < end as f64 {
+            Some(frame)
+        } else {
+            None
+        };
+    }
+
+    pub fn sample(&self, frame: usize, channel: usize) -> f32 {
+        return self.samples[frame * self.channels + channel];
+    }
+}
+
+pub struct SimSink {
+    config: SimConfig,
+    rate: f64,
+    channels: usize,
+    rng: Rng,
+    /// Seconds since the origin
+    now: f64,
+    state: SinkState,
+    start_threshold: i64,
+    written: i64,
+    /// Frames played before the current run
+    consumed: i64,
+    run_start: f64,
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl SimSink {
+    pub fn new(config: SimConfig, channels: u32, rate: u32) -> SimSink {
+        let recording = Recording {
+            config: config.clone(),
+            rate: rate as f64,
+            channels: channels as usize,
+            samples: Vec::new(),
+            runs: Vec::new(),
+        };
+        return SimSink {
+            rng: Rng::new(config.seed),
+            rate: rate as f64,
+            channels: channels as usize,
+            now: 0.,
+            state: SinkState::Prepared,
+            start_threshold: 1,
+            written: 0,
+            consumed: 0,
+            run_start: 0.,
+            recording: Arc::new(Mutex::new(recording)),
+            config,
+        };
+    }
+
+    /// Shared handle to what the card plays, stays valid after the sink is
+    /// dropped.
+    pub fn recording(&self) -> Arc<Mutex<Recording>> {
+        return Arc::clone(&self.recording);
+    }
+
+    fn played(&self) -> i64 {
+        if self.state != SinkState::Running {
+            return self.consumed;
+        }
+        let frames =
+            self.config.phase(self.rate, self.now) - self.config.phase(self.rate, self.run_start);
+        return (self.consumed + frames as i64).min(self.written);
+    }
+
+    fn update(&mut self) {
+        if self.state == SinkState::Running && self.played() >= self.written {
+            self.consumed = self.written;
+            self.state = SinkState::Underrun;
+        }
+    }
+
+    /// Advances the clock until `frames` more frames are played.
+    fn wait_frames(&mut self, frames: i64) {
+        let phase = self.config.phase(self.rate, self.run_start)
+            + (self.played() + frames - self.consumed) as f64;
+        self.now = self.now.max(self.config.time_of_phase(self.rate, phase));
+    }
+
+    fn wait(&mut self, seconds: f64) {
+        self.now += seconds + (self.config.jitter * self.rng.gaussian()).abs();
+    }
+
+    fn start(&mut self) {
+        self.state = SinkState::Running;
+        self.run_start = self.now;
+        self.recording
+            .lock()
+            .unwrap()
+            .runs
+            .push((self.now, self.consumed as usize));
+    }
+}
+
+impl AudioSink for SimSink {
+    fn period_size(&self) -> i64 {
+        return self.config.period_size;
+    }
+
+    fn buffer_size(&self) -> i64 {
+        return self.config.buffer_size;
+    }
+
+    fn set_start_threshold(&mut self, frames: i64) -> Result<(), SinkError> {
+        self.start_threshold = frames.clamp(1, self.config.buffer_size);
+        return Ok(());
+    }
+
+    fn status(&mut self) -> Result<Status, SinkError> {
+        self.now += self.config.status_cost;
+        self.update();
+        let time = self.now + self.config.tstamp_noise * self.rng.gaussian();
+        return Ok(Status {
+            stamp: if time >= 0. {
+                self.config.origin + Duration::from_secs_f64(time)
+            } else {
+                self.config.origin - Duration::from_secs_f64(-time)
+            },
+            delay: self.written - self.played(),
+            state: self.state,
+        });
+    }
+
+    fn state(&self) -> SinkState {
+        if self.state == SinkState::Running && self.played() >= self.written {
+            return SinkState::Underrun;
+        }
+        return self.state;
+    }
+
+    fn write(&mut self, buf: &[f32]) -> Result<usize, SinkError> {
+        self.update();
+        if self.state == SinkState::Underrun {
+            return Err(SinkError::Underrun);
+        }
+        let frames = (buf.len() / self.channels) as i64;
+        let queued = self.written - self.played();
+        if self.state == SinkState::Running && queued + frames > self.config.buffer_size {
+            self.wait_frames(queued + frames - self.config.buffer_size);
+            self.wait(0.);
+        }
+        self.recording
+            .lock()
+            .unwrap()
+            .samples
+            .extend_from_slice(buf);
+        self.written += frames;
+        if self.state == SinkState::Prepared && self.written - self.consumed >= self.start_threshold
+        {
+            self.start();
+        }
+        if self.rng.uniform() < self.config.hiccup_chance {
+            self.now += self.config.hiccup;
+        }
+        return Ok(frames as usize);
+    }
+
+    fn prepare(&mut self) -> Result<(), SinkError> {
+        self.update();
+        self.consumed = self.played();
+        self.state = SinkState::Prepared;
+        return Ok(());
+    }
+
+    fn drain(&mut self) -> Result<(), SinkError> {
+        if self.state == SinkState::Prepared && self.written > self.consumed {
+            self.start();
+        }
+        if self.state == SinkState::Running {
+            let left = self.written - self.played();
+            self.wait_frames(left);
+            self.update();
+        }
+        self.state = SinkState::Prepared;
+        return Ok(());
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        self.wait(duration.as_secs_f64());
+    }
+}
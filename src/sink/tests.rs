@@ -0,0 +1,113 @@
+use super::sim::{SimConfig, SimSink};
+use super::*;
+
+use std::time::UNIX_EPOCH;
+
+const RATE: u32 = 48000;
+
+fn config(ppm: f64) -> SimConfig {
+    let mut config = SimConfig::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+    config.ppm = ppm;
+    return config;
+}
+
+fn seconds(stamp: SystemTime) -> f64 {
+    return stamp.duration_since(UNIX_EPOCH).unwrap().as_secs_f64() - 1_000_000.;
+}
+
+#[test]
+fn test_sim_rate() {
+    for ppm in &[-100., 0., 250.] {
+        let mut sink = SimSink::new(config(*ppm), 2, RATE);
+        sink.set_start_threshold(1024).unwrap();
+        let period = vec![0.; 2 * 1024];
+        let mut first = None;
+        for _ in 0..500 {
+            sink.write(&period).unwrap();
+            let status = sink.status().unwrap();
+            assert_eq!(status.state, SinkState::Running);
+            assert!(status.delay <= sink.buffer_size());
+            first.get_or_insert((status.stamp, status.delay));
+        }
+        let (first_stamp, first_delay) = first.unwrap();
+        let last = sink.status().unwrap();
+        let frames = 499. * 1024. - (last.delay - first_delay) as f64;
+        let elapsed = seconds(last.stamp) - seconds(first_stamp);
+        let measured = (frames / elapsed / RATE as f64 - 1.) * 1e6;
+        assert!(
+            (measured - ppm).abs() < 2.,
+            "{} ppm measured as {}",
+            ppm,
+            measured
+        );
+    }
+}
+
+#[test]
+fn test_sim_underrun() {
+    let mut sink = SimSink::new(config(0.), 1, RATE);
+    let recording = sink.recording();
+    sink.set_start_threshold(1000).unwrap();
+    sink.write(&[1.; 500]).unwrap();
+    assert_eq!(sink.state(), SinkState::Prepared);
+    sink.sleep(Duration::from_secs(1));
+    assert_eq!(sink.status().unwrap().delay, 500);
+    sink.write(&[2.; 500]).unwrap();
+    assert_eq!(sink.state(), SinkState::Running);
+    sink.sleep(Duration::from_millis(100));
+    let status = sink.status().unwrap();
+    assert_eq!(status.state, SinkState::Underrun);
+    assert_eq!(status.delay, 0);
+    assert_eq!(sink.write(&[3.; 500]), Err(SinkError::Underrun));
+    sink.prepare().unwrap();
+    sink.write(&[4.; 1000]).unwrap();
+    sink.drain().unwrap();
+
+    let recording = recording.lock().unwrap();
+    assert_eq!(recording.runs.len(), 2);
+    assert_eq!(recording.runs[1].1, 1000);
+    let start = recording.runs[0].0;
+    assert_eq!(recording.frame_at(start - 0.001), None);
+    let frame = recording.frame_at(start + 0.01).unwrap();
+    assert!((frame - 480.).abs() < 1e-6);
+    assert_eq!(recording.sample(frame as usize, 0), 1.);
+    assert_eq!(recording.frame_at(start + 0.05), None);
+    let frame = recording.frame_at(recording.runs[1].0).unwrap();
+    assert_eq!(recording.sample(frame as usize, 0), 4.);
+}
+
+#[test]
+fn test_sim_noise_is_reproducible() {
+    let run = |seed| {
+        let mut config = config(20.);
+        config.drift = 1.;
+        config.jitter = 1e-4;
+        config.tstamp_noise = 1e-5;
+        config.hiccup_chance = 0.1;
+        config.hiccup = 0.005;
+        config.seed = seed;
+        let mut sink = SimSink::new(config, 1, RATE);
+        sink.set_start_threshold(2048).unwrap();
+        return (0..100)
+            .map(|_| {
+                sink.write(&[0.; 1024]).unwrap_or(0);
+                sink.sleep(Duration::from_millis(1));
+                sink.status().unwrap()
+            })
+            .collect::<Vec<_>>();
+    };
+    assert_eq!(run(7), run(7));
+    assert_ne!(run(7), run(8));
+}
+
+#[test]
+fn test_quantize() {
+    assert_eq!(quantize(0., 16), 0);
+    assert_eq!(quantize(0.5, 16), 16384);
+    assert_eq!(quantize(-1., 16), -32768);
+    assert_eq!(quantize(1., 16), 32767);
+    assert_eq!(quantize(-2., 24), -8_388_608);
+    assert_eq!(quantize(0.25, 24), 2_097_152);
+    assert_eq!(quantize(1.5, 32), i32::MAX);
+    assert_eq!(quantize(-1., 32), i32::MIN);
+}
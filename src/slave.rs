@@ -1,381 +1,467 @@
-use alsa::pcm::{Access, Format, HwParams, State, TstampType, PCM};
-use alsa::{Direction, ValueOr};
-use hound;
-
-use indicator::{Average, Indicator, LinearRegression, Median, Variance};
-
+use piwfs::clock::{LockPolicy, TimeSource, TstampClock};
+use piwfs::daemon::Daemon;
+use piwfs::decoder::ChannelMap;
+use piwfs::discovery::{self, Announcement};
+use piwfs::fade::{Curve, Level};
+use piwfs::geometry::{Geometry, Layout};
+use piwfs::ntp;
+use piwfs::player::{KalmanConfig, PlayerConfig, Step, SyncedPlayer};
+use piwfs::protocol;
+use piwfs::ptp;
+use piwfs::ptp::management::{self, Monitor, Ptp4lStatus};
+use piwfs::ptp::slave::{PtpSlave, SlaveConfig};
+use piwfs::servo::ServoConfig;
+use piwfs::session::Session;
+use piwfs::sink::{AlsaSink, AudioSink, SinkError};
+use piwfs::timeline::Timeline;
+use piwfs::wfs::Loudspeaker;
+
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use std::collections::VecDeque;
-use std::convert::TryInto;
-use std::f32::consts::PI;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::panic::panic_any;
+use std::time::{Duration, SystemTime};
 
 use clap::ArgMatches;
 
-fn sinc_move_inter(buf: &Vec<i16>, ratio: f32, size: usize, num_channels: usize) -> Vec<i16> {
-    let out_size = buf.len() - (2 * size + 1) * num_channels;
-    let mut out = vec![0; out_size];
-    for channel in 0..num_channels {
-        for out_it in (channel..out_size).step_by(num_channels) {
-            let mut interp = 0.;
-            for in_it in
-                ((channel + out_it)..(out_it + (2 * size + 1) * num_channels)).step_by(num_channels)
-            {
-                let cur_r = PI
-                    * (ratio + (out_it / num_channels + size) as f32
-                        - (in_it / num_channels) as f32);
-                interp += (buf[in_it] as f32) * cur_r.sin() / cur_r;
-            }
-            out[out_it] = (std::i16::MIN as f32).max((std::i16::MAX as f32).min(interp)) as i16;
-        }
-    }
-    return out;
+/// Value of the option `name` as a number, `default` when not given.
+fn number(args: &ArgMatches, name: &str, default: f64) -> f64 {
+    return args.value_of(name).map_or(default, |value| {
+        value
+            .parse::<f64>()
+            .unwrap_or_else(|_| panic!("[ERR] Couldn't parse {} as a number", name))
+    });
 }
 
-fn next_rdr_sample<T: std::io::Read>(reader: &mut hound::WavReader<T>) -> u32 {
-    return (reader.len() - reader.samples::<i16>().len() as u32) / reader.spec().channels as u32;
+/// PI servo gains and limits, defaults for the ones not given.
+fn servo_config(args: &ArgMatches) -> ServoConfig {
+    let default = ServoConfig::default();
+    return ServoConfig {
+        kp: number(args, "servo-kp", default.kp),
+        ki: number(args, "servo-ki", default.ki),
+        step_threshold: number(args, "step-threshold", default.step_threshold),
+        max_slew: number(args, "max-slew", default.max_slew),
+        integral_limit: number(args, "integral-limit", default.integral_limit),
+    };
 }
 
-fn duration_diff_secs_f64(lhs: SystemTime, rhs: SystemTime) -> f64 {
-    return if lhs > rhs {
-        lhs.duration_since(rhs).unwrap().as_secs_f64()
-    } else {
-        -rhs.duration_since(lhs).unwrap().as_secs_f64()
+/// Kalman filter noise, defaults for the values not given.
+fn kalman_config(args: &ArgMatches) -> KalmanConfig {
+    let default = KalmanConfig::default();
+    return KalmanConfig {
+        process: number(args, "process-noise", default.process),
+        measurement: number(args, "measurement-noise", default.measurement),
     };
 }
 
-pub fn main(args: &ArgMatches) {
-    let pcm = PCM::new(
-        &args.value_of("device").unwrap_or("hw:0"),
-        Direction::Playback,
-        false,
-    )
-    .unwrap();
-    let mut reader = hound::WavReader::open(args.value_of("testfile").unwrap()).unwrap();
-    let is_correction = !args.is_present("no-correction");
-    let is_spinning = !args.is_present("no-spinning");
-    let startstamp = UNIX_EPOCH
-        + Duration::from_nanos(
-            args.value_of("startat")
-                .unwrap()
-                .parse::<u64>()
-                .expect("[ERR] Couldn't parse startat as a unsigned integer number"),
-        );
-    let est_avg_size = args
-        .value_of("estimation-avg")
-        .unwrap_or("1000")
-        .parse::<usize>()
-        .expect("[ERR] Couldn't parse average as an unsigned integer");
-    let desync_avg_size = args
-        .value_of("desync-avg")
-        .unwrap_or("1000")
-        .parse::<usize>()
-        .expect("[ERR] Couldn't parse average as an unsigned integer");
-    let reader_spec = reader.spec();
+/// Device-local settings, these are never part of a session.
+pub struct Options {
+    /// ALSA device, taken from the geometry when not set
+    pub device: Option<String>,
+    /// Clock the device timestamps its status with
+    pub tstamp_clock: TstampClock,
+    pub player: PlayerConfig,
+    /// Loudspeakers driven by the output channels when there is no geometry
+    pub speakers: Vec<Loudspeaker>,
+    /// Speakers of the geometry driven by this slave, all speakers of this
+    /// host when empty
+    pub speaker_ids: Vec<String>,
+    pub alias_freq: Option<f64>,
+    /// File channels played on the output channels
+    pub channels: Option<ChannelMap>,
+    /// Network time to follow instead of the system clock
+    pub time_source: Option<Arc<dyn TimeSource>>,
+    /// ptp4l disciplining the system clock
+    pub monitor: Option<Arc<Monitor>>,
+    /// Applied while ptp4l isn't locked to a grandmaster
+    pub lock_policy: LockPolicy,
+    /// Largest ptp4l master offset in seconds that counts as locked
+    pub max_offset: f64,
+    /// Output level, changed by the daemon while playing
+    pub level: Arc<Level>,
+}
 
-    let fs = reader_spec.sample_rate;
-    let num_channels = reader_spec.channels as usize;
+impl Options {
+    pub fn from_args(args: &ArgMatches) -> Options {
+        return Options {
+            device: args.value_of("device").map(String::from),
+            tstamp_clock: args
+                .value_of("tstamp-clock")
+                .map_or(TstampClock::default(), |clock| {
+                    clock.parse().unwrap_or_else(|err| {
+                        panic!("[ERR] Couldn't parse timestamp clock: {}", err)
+                    })
+                }),
+            player: PlayerConfig {
+                is_correction: !args.is_present("no-correction"),
+                is_spinning: !args.is_present("no-spinning"),
+                is_estimation: !args.is_present("no-estimation"),
+                is_asrc: args.is_present("asrc"),
+                servo: if args.is_present("servo") {
+                    Some(servo_config(args))
+                } else {
+                    None
+                },
+                kalman: if args.is_present("kalman") {
+                    Some(kalman_config(args))
+                } else {
+                    None
+                },
+                start_fade: number(args, "fade", 10.) / 1e3,
+                cue_fade: number(args, "cue-fade", 10.) / 1e3,
+                join_fade: number(args, "join-fade", 50.) / 1e3,
+                loop_fade: number(args, "loop-fade", 0.) / 1e3,
+                fade_curve: args
+                    .value_of("fade-curve")
+                    .map_or(Curve::default(), |curve| {
+                        curve.parse().unwrap_or_else(|err| {
+                            panic!("[ERR] Couldn't parse fade curve: {}", err)
+                        })
+                    }),
+                ..PlayerConfig::default()
+            },
+            speakers: args.values_of("speaker").map_or(Vec::new(), |speakers| {
+                speakers
+                    .map(|speaker| {
+                        speaker
+                            .parse()
+                            .unwrap_or_else(|err| panic!("[ERR] Couldn't parse speaker: {}", err))
+                    })
+                    .collect()
+            }),
+            speaker_ids: args
+                .values_of("speaker-id")
+                .map_or(Vec::new(), |ids| ids.map(String::from).collect()),
+            alias_freq: args.value_of("alias-freq").map(|freq| {
+                freq.parse::<f64>()
+                    .expect("[ERR] Couldn't parse aliasing frequency as a number")
+            }),
+            channels: args.value_of("channels").map(|channels| {
+                channels
+                    .parse()
+                    .unwrap_or_else(|err| panic!("[ERR] Couldn't parse channels: {}", err))
+            }),
+            time_source: None,
+            monitor: None,
+            lock_policy: args
+                .value_of("lock-policy")
+                .map_or(LockPolicy::default(), |policy| {
+                    policy
+                        .parse()
+                        .unwrap_or_else(|err| panic!("[ERR] Couldn't parse lock policy: {}", err))
+                }),
+            max_offset: number(args, "max-offset", 1000.) * 1e-6,
+            level: Arc::new(Level::new(number(args, "gain", 0.))),
+        };
+    }
 
-    let hwp = HwParams::any(&pcm).unwrap();
-    hwp.set_channels(num_channels as u32).unwrap();
-    hwp.set_rate(fs, ValueOr::Nearest).unwrap();
-    hwp.set_format(Format::s16()).unwrap();
-    hwp.set_access(Access::RWInterleaved).unwrap();
-    pcm.hw_params(&hwp).unwrap();
-    let io = pcm.io_i16().unwrap();
+    /// Device and loudspeakers to use for `session`, the geometry of the
+    /// session takes precedence over the `--speaker` options.
+    pub fn layout(&self, session: &Session) -> Result<Layout, String> {
+        let layout = match &session.geometry {
+            Some(text) => Geometry::parse(text)?.select(&self.speaker_ids, &hostname())?,
+            None => Layout {
+                device: "hw:0".to_string(),
+                speakers: self.speakers.iter().copied().map(Some).collect(),
+                alias_freq: None,
+                channels: None,
+            },
+        };
+        return Ok(Layout {
+            device: self.device.clone().unwrap_or(layout.device),
+            alias_freq: self.alias_freq.or(layout.alias_freq),
+            channels: self.channels.clone().or(layout.channels),
+            ..layout
+        });
+    }
+}
 
-    let hwp = pcm.hw_params_current().unwrap();
-    let swp = pcm.sw_params_current().unwrap();
-    let period_size = hwp.get_period_size().unwrap();
-    let buffer_size = hwp.get_buffer_size().unwrap();
-    let buffer_fill = 2 * period_size as i32 * num_channels as i32;
-    swp.set_start_threshold(buffer_fill.into()).unwrap();
-    swp.set_tstamp_mode(true).unwrap();
-    swp.set_tstamp_type(TstampType::Gettimeofday).unwrap();
-    pcm.sw_params(&swp).unwrap();
-    let sinc_overlap = if is_correction {
-        args.value_of("quality")
-            .unwrap_or("2")
-            .parse::<usize>()
-            .expect("[ERR] Couldn't parse quality as an unsigned integer")
-    } else {
-        0
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    return match nix::unistd::gethostname(&mut buf) {
+        Ok(name) => name.to_string_lossy().into_owned(),
+        Err(_) => "unknown".to_string(),
     };
+}
+
+/// Plays `session` on the sink returned by `open` for the device, channel
+/// count and sample rate the session needs, following the commands of
+/// `timeline` if there is one.
+pub fn play<S, F>(
+    session: &Session,
+    opts: &Options,
+    stop: &AtomicBool,
+    timeline: Option<Arc<Mutex<Timeline>>>,
+    open: F,
+) where
+    S: AudioSink,
+    F: FnOnce(&str, u32, u32) -> Result<S, SinkError>,
+{
+    let layout = opts
+        .layout(session)
+        .unwrap_or_else(|err| panic!("[ERR] Couldn't set up loudspeakers: {}", err));
+    if let Some(map) = &layout.channels {
+        println!("[INF] Playing file channels {} on {}", map, layout.device);
+    }
+    if let Some(source) = opts.time_source.as_ref() {
+        if source.offset().is_none() {
+            println!("[INF] Waiting for the network time");
+        }
+        while source.offset().is_none() {
+            if stop.load(Ordering::Relaxed) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+    if let Ok(late) = SystemTime::now().duration_since(session.startstamp()) {
+        println!("[INF] Joining {:.1} s after the start", late.as_secs_f64());
+    }
+    let mut player = SyncedPlayer::new(opts.player, session, &layout, open)
+        .unwrap_or_else(|err| panic!("[ERR] {}", err));
+    if let Some(source) = opts.time_source.as_ref() {
+        player.set_time_source(Arc::clone(source));
+    }
+    if let Some(timeline) = timeline {
+        player.set_timeline(timeline);
+    }
+    player.set_level(Arc::clone(&opts.level));
     print!(
         "[INF] Fs: {}, Channels: {}, Period: {}, Buffer: {}",
-        fs, num_channels, period_size, buffer_size
+        player.sample_rate(),
+        player.channels(),
+        player.sink().period_size(),
+        player.sink().buffer_size()
     );
     println!("[?25l");
 
-    let sam_num = period_size as usize * num_channels;
-    let sam_num_over = sam_num + (2 * sinc_overlap + 1) * num_channels;
-
-    let mut desync = LinearRegression::new(desync_avg_size).unwrap();
-    let mut act_desync_avg = Average::new(10000).unwrap();
-    let mut correction = 0.;
-
-    let sample_duration = 1. / (fs as f64);
-    let mut real_sample_duration = sample_duration;
-    let mut real_sample_duration_avg = Median::new(est_avg_size).unwrap();
-
-    let mut last_samples_pushed = 0;
-
-    let mut samples_pushed = 0;
-    let mut nsts = VecDeque::new();
-    let mut est_error_var = Variance::new(1000).unwrap();
-
-    let sigint = Arc::new(AtomicBool::new(false));
-    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&sigint))
-        .expect("[ERR] Error setting SIGINT hook");
-
-    while !sigint.load(Ordering::Relaxed) {
-        let loop_start = std::time::Instant::now();
-        let mut elapsed_times = Vec::new();
-        samples_pushed += last_samples_pushed;
-        let mut stamps = Vec::new();
-        let mut delays = Vec::new();
-
-        loop {
-            let status = pcm.status().unwrap();
-            let libc_stamp = status.get_htstamp();
-            let stamp = UNIX_EPOCH
-                + Duration::new(
-                    libc_stamp.tv_sec.try_into().unwrap(),
-                    libc_stamp.tv_nsec.try_into().unwrap(),
-                );
-            if Some(&stamp) == stamps.last() {
-                continue;
-            }
-
-            let delay = status.get_delay();
-
-            if is_spinning {
-                stamps.push(stamp);
-                delays.push(delay);
-            } else {
-                std::thread::sleep(Duration::from_secs_f64(sample_duration / 2.));
-            }
-
-            if status.get_state() != State::Running || delay < buffer_fill.into() {
-                if !is_spinning {
-                    stamps.push(stamp);
-                    delays.push(delay);
-                }
-                break;
-            }
+    let mut problem = None;
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            // Keeps stepping until the fade-out is written
+            player.fade_out();
         }
-        elapsed_times.push(("Spinning", loop_start.elapsed()));
-        let mut est_error = [0., 0.];
-        for (stamp, delay) in stamps.iter().zip(delays.iter()) {
-            loop {
-                if let Some((ns, nst)) = nsts.get(0) {
-                    let cur_ns = samples_pushed - delay;
-                    if cur_ns == *ns {
-                        let err = duration_diff_secs_f64(*nst, *stamp) * 1_000_000.;
-                        //println!("[DBG] Est error: {} (est = {}, act = {})", *nst - *stamp, nst, stamp);
-                        est_error_var.next(err);
-                        if let Some(var) = est_error_var.value() {
-                            est_error = [var, est_error_var.average().unwrap()];
-                        }
-                        nsts.remove(0);
-                    } else if cur_ns > *ns {
-                        nsts.remove(0);
-                        continue;
+        if let Some(monitor) = opts.monitor.as_ref() {
+            let current = monitor.status().problem(opts.max_offset);
+            if current != problem {
+                match &current {
+                    Some(cause) => println!("\n[WRN] {}", cause),
+                    None => println!("\n[INF] ptp4l is locked again"),
+                }
+                match opts.lock_policy {
+                    LockPolicy::Warn => (),
+                    LockPolicy::Mute => player.set_muted(current.is_some()),
+                    LockPolicy::Stop if current.is_some() => {
+                        println!("[ERR] Stopping as the clock can't be trusted");
+                        player.fade_out();
                     }
+                    LockPolicy::Stop => (),
                 }
-                break;
+                problem = current;
             }
         }
-        elapsed_times.push(("Error estimation", loop_start.elapsed()));
-
-        real_sample_duration_avg.next(
-            if !args.is_present("no-estimation")
-                && pcm.state() == State::Running
-                && stamps.len() > 1
-            {
-                stamps
-                    .windows(2)
-                    .zip(delays.windows(2))
-                    .fold(0., |acc, (stampw, delayw)| {
-                        let mtime = stampw[1].duration_since(stampw[0]).unwrap().as_secs_f64()/(delayw[0] - delayw[1]) as f64;
-                        acc + if mtime > 0. {
-                            mtime
-                        } else {
-                            println!("[WRN] Non-continous status times or delays");
-                            real_sample_duration
-                        }
-                    }) / (stamps.len() - 1) as f64
-            } else {
-                real_sample_duration
-            },
-        );
-        real_sample_duration = real_sample_duration_avg.value().unwrap();
-        elapsed_times.push(("Sample duration estimation", loop_start.elapsed()));
-
-        let mut buf: Vec<i16> = Vec::with_capacity(sam_num_over);
-        let mut next_sample_time = UNIX_EPOCH
-            + stamps
-                .iter()
-                .zip(delays.iter())
-                .fold(Duration::new(0, 0), |acc, (stamp, delay)| {
-                    acc + (stamp.duration_since(UNIX_EPOCH).unwrap()
-                        + Duration::from_secs_f64(real_sample_duration * *delay as f64))
-                        / stamps.len().try_into().unwrap()
-                });
-        nsts.push_back((samples_pushed, next_sample_time));
-        elapsed_times.push(("Next sample time estimation", loop_start.elapsed()));
-
-        let mut zeros_pushed = 0.;
-        while startstamp
-            > next_sample_time + Duration::from_secs_f64(real_sample_duration * zeros_pushed)
-        {
-            for _ in 0..num_channels {
-                buf.push(0)
-            }
-            zeros_pushed += 1.;
-            if buf.len() == sam_num {
-                break;
-            } else if buf.len() > sam_num {
-                unreachable!()
+        let step = player
+            .step()
+            .unwrap_or_else(|err| panic!("[ERR] Couldn't play on {}: {}", layout.device, err));
+        let state = player.state();
+        let servo = state.servo.map_or(String::new(), |(servo, ppm, drift)| {
+            format!(
+                ", Servo: {:?} {:+.1} ppm, Drift: {:+.1} ppm",
+                servo, ppm, drift
+            )
+        });
+        let offset = match (opts.time_source.as_ref(), state.time_offset) {
+            (Some(source), Some(offset)) => format!(
+                ", {}: {:+.1} us{}",
+                source.name(),
+                offset * 1e6,
+                state
+                    .time_error
+                    .map_or(String::new(), |error| format!(" ±{:.0} us", error * 1e6))
+            ),
+            _ => String::new(),
+        };
+        let offset = opts.monitor.as_ref().map_or(offset, |monitor| {
+            format!(", ptp4l: {}", ptp4l_status(&monitor.status()))
+        });
+        match step {
+            Step::Playing => print!(
+                "[INF] Desync: {:+.1}, Diff: {:+.3}, Delay: {}, Freq: {:+.3}%, Error: {:+.0}±{:.0} us, Spins: {}{}{}[K\r",
+                state.desync,
+                state.diff,
+                state.delay,
+                100. * (1. / (player.sample_rate() as f64 * state.sample_duration) - 1.),
+                state.est_error.0,
+                state.est_error.1.sqrt(),
+                state.spins,
+                servo,
+                offset
+            ),
+            Step::Underrun => {
+                println!("\n[ERR] Buffer underrun!");
+                println!("----- Execution times breakdown:");
+                let mut previous = Duration::new(0, 0);
+                for (stage, ended) in state.timings.iter() {
+                    println!(
+                        "----> {} ended at {:?} (took {:?})",
+                        stage,
+                        ended,
+                        *ended - previous
+                    );
+                    previous = *ended;
+                }
+                println!("----- Estimated time budget: {:?}", player.time_budget());
             }
+            Step::Finished => break,
         }
-        next_sample_time += Duration::from_secs_f64(real_sample_duration * zeros_pushed);
+    }
+    println!("[?25h");
+    player.finish().unwrap();
+}
 
-        let (cur_desync, avg_act_desync) = if buf.len() < sam_num {
-            let next_sample = next_sample_time
-                .duration_since(startstamp)
-                .unwrap()
-                .as_secs_f64()
-                / sample_duration;
-            let next_read = next_rdr_sample(&mut reader).saturating_sub(sinc_overlap as u32 + 1);
-            let act_desync = next_sample - next_read as f64;
-            act_desync_avg.next(act_desync);
-            let next_sample_time_f64 = next_sample_time
-                .duration_since(startstamp)
-                .unwrap()
-                .as_secs_f64();
-            desync.next((next_sample_time_f64, correction as f64 + act_desync));
-            let (desync_a, desync_b) = desync.value().unwrap_or((0., 0.));
-            let cur_desync = desync_a + desync_b * next_sample_time_f64;
-            let jump = (cur_desync - correction).floor() as i64;
-            let max_jump = 100;
-            let jump = if jump.abs() > max_jump {
-                jump.signum() * max_jump
-            } else {
-                jump
-            };
-            let jumpto = if jump > 0 {
-                next_read.saturating_add(jump as u32)
-            } else {
-                next_read.saturating_sub((-jump) as u32)
-            }
-            .saturating_sub(sinc_overlap as u32);
-            let jumpto = if jumpto > reader.len() / num_channels as u32 {
-                reader.len() / num_channels as u32
-            } else {
-                jumpto
-            };
-            //println!("[DBG] ===============================");
-            //println!("[DBG] j = {}, jt = {}, c = {}, lsp = {}", jump, jumpto, correction, last_samples_pushed);
+/// Port state and master offset of `status` for the status line
+fn ptp4l_status(status: &Ptp4lStatus) -> String {
+    if status.error.is_some() {
+        return "no answer".to_string();
+    }
+    let state = status
+        .state
+        .map_or("?".to_string(), |state| state.to_string());
+    return match status.offset {
+        Some(offset) if status.gm_present => format!("{} {:+.0} ns", state, offset * 1e9),
+        _ => state,
+    };
+}
 
-            if is_correction {
-                correction += jumpto as f64 - next_read.saturating_sub(sinc_overlap as u32) as f64;
-                reader.seek(jumpto).unwrap();
-            }
-            elapsed_times.push(("Seeking", loop_start.elapsed()));
+fn open_alsa(
+    device: &str,
+    channels: u32,
+    rate: u32,
+    clock: TstampClock,
+) -> Result<AlsaSink, SinkError> {
+    let sink = AlsaSink::open(device, channels, rate, clock)?;
+    println!("[INF] Playing on {} as {:?}", device, sink.format());
+    return Ok(sink);
+}
 
-            for sample in reader.samples::<i16>() {
-                buf.push(match sample {
-                    Ok(res) => res,
-                    Err(_) => break,
-                });
-                if buf.len() == sam_num_over {
-                    break;
-                } else if buf.len() > sam_num_over {
-                    unreachable!()
-                }
-            }
+/// Starts the built-in PTP slave, its offset is known once it heard from
+/// a grandmaster.
+fn start_ptp(args: &ArgMatches) -> Arc<PtpSlave> {
+    let config = SlaveConfig {
+        domain: ptp::domain_from_args(args),
+        ..SlaveConfig::default()
+    };
+    let ptp = PtpSlave::start(config).expect("[ERR] Couldn't bind PTP ports");
+    println!("[INF] Following PTP domain {}", config.domain);
+    return Arc::new(ptp);
+}
 
-            let ratio = cur_desync - correction;
-            if is_correction {
-                buf = if buf.len() > (2 * sinc_overlap + 1) * num_channels {
-                    sinc_move_inter(&buf, ratio as f32, sinc_overlap, num_channels)
-                } else {
-                    buf[sinc_overlap..].into()
-                }
-            }
-            elapsed_times.push(("Interpolation", loop_start.elapsed()));
+/// Starts polling ptp4l, the policy applies as soon as something is playing.
+fn start_ptp4l(args: &ArgMatches) -> Arc<Monitor> {
+    let socket = Path::new(
+        args.value_of("ptp4l-socket")
+            .unwrap_or(management::UDS_PATH),
+    );
+    let monitor = Monitor::start(socket, ptp::domain_from_args(args), Duration::from_secs(1))
+        .unwrap_or_else(|err| {
+            panic!(
+                "[ERR] Couldn't reach ptp4l on {}: {}",
+                socket.display(),
+                err
+            )
+        });
+    if let Some(problem) = monitor.status().problem(f64::INFINITY) {
+        println!("[WRN] {}", problem);
+    }
+    return Arc::new(monitor);
+}
 
-            if buf.len() == 0 {
-                break;
-            }
+pub fn main(args: &ArgMatches) {
+    let mut opts = Options::from_args(args);
+    let sigint = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&sigint))
+        .expect("[ERR] Error setting SIGINT hook");
+    if args.is_present("ptp") {
+        opts.time_source = Some(start_ptp(args));
+    }
+    if args.is_present("ntp") {
+        opts.time_source = Some(Arc::new(ntp::Kernel));
+        println!("[INF] Following the system clock disciplined by NTP");
+    }
+    if let Some(unit) = args.value_of("ntp-shm") {
+        let unit = unit
+            .parse()
+            .expect("[ERR] Couldn't parse SHM unit as a number");
+        let shm = ntp::Shm::attach(unit)
+            .unwrap_or_else(|err| panic!("[ERR] Couldn't attach to SHM unit {}: {}", unit, err));
+        opts.time_source = Some(Arc::new(shm));
+        println!("[INF] Following the reference clock of SHM unit {}", unit);
+    }
+    if args.is_present("ptp4l") {
+        opts.monitor = Some(start_ptp4l(args));
+    }
 
-            (cur_desync, act_desync_avg.value().unwrap())
-        } else {
-            (
-                -startstamp
-                    .duration_since(next_sample_time)
-                    .unwrap_or(Duration::new(0, 0))
-                    .as_secs_f64()
-                    / sample_duration,
-                0.,
-            )
+    if let Some(listen) = args.value_of("listen") {
+        let addr = protocol::resolve(listen).expect("[ERR] Couldn't resolve listen address");
+        let device = opts.device.clone().unwrap_or_else(|| "hw:0".to_string());
+        let (channels, rate) = AlsaSink::probe(&device).unwrap_or_else(|err| {
+            println!("[WRN] Couldn't probe {}: {}", device, err);
+            (0, 0)
+        });
+        let announcement = Announcement {
+            hostname: hostname(),
+            device,
+            channels,
+            rate,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            port: 0,
         };
-
-        print!(
-            "[INF] Desync: {:+.1}, Diff: {:+.3}, Delay: {}, Freq: {:+.3}%, Error: {:+.0}±{:.0} us, Spins: {}[K\r",
-            cur_desync,
-            avg_act_desync,
-            delays.last().unwrap(),
-            100. * (sample_duration / real_sample_duration - 1.),
-            est_error[1],
-            est_error[0].sqrt(),
-            delays.len()
+        let level = Arc::clone(&opts.level);
+        let time_source = opts.time_source.clone();
+        let daemon = Daemon::new(
+            Box::new(move |session, stop, timeline| {
+                play(
+                    &session,
+                    &opts,
+                    &stop,
+                    Some(timeline),
+                    |device, channels, rate| open_alsa(device, channels, rate, opts.tstamp_clock),
+                )
+            }),
+            level,
+            time_source,
         );
-        //println!("\n[DBG] ns = {}, nr = {}, nrs = {}, nst = {}", next_sample, next_read, next_read, next_sample_time);
-        elapsed_times.push(("Printing", loop_start.elapsed()));
-
-        match io.writei(&buf) {
-            Ok(num) => {
-                assert_eq!(num, buf.len() / num_channels);
-                last_samples_pushed = num.try_into().unwrap();
-            }
-            Err(err) => {
-                if let Some(errno) = err.errno() {
-                    if errno == nix::errno::Errno::EPIPE {
-                        println!("\n[ERR] ALSA buffer underrun!");
-                        println!("----- Execution times breakdown:");
-                        for ind in 0..elapsed_times.len() {
-                            let took_time = if ind > 0 {
-                                elapsed_times[ind].1 - elapsed_times[ind - 1].1
-                            } else {
-                                elapsed_times[ind].1
-                            };
-                            println!(
-                                "----> {} ended at {:?} (took {:?})",
-                                elapsed_times[ind].0, elapsed_times[ind].1, took_time
-                            );
-                        }
-                        println!(
-                            "----- Estimated time budget: {:?}",
-                            Duration::from_secs_f64(
-                                *delays.first().unwrap() as f64 * real_sample_duration
-                            )
-                        );
-                        last_samples_pushed = 0;
-                        pcm.prepare().unwrap();
-                    } else {
-                        panic_any(err);
-                    }
-                } else {
-                    panic_any(err);
-                }
-            }
+        let addr = Arc::clone(&daemon)
+            .serve(addr)
+            .expect("[ERR] Couldn't bind listen address");
+        println!("[INF] Listening on {}", addr);
+        let stop_announcing = Arc::new(AtomicBool::new(false));
+        if !args.is_present("no-announce") {
+            let group = discovery::group_from_args(args);
+            discovery::spawn_announcer(
+                Announcement {
+                    port: addr.port(),
+                    ..announcement
+                },
+                group,
+                discovery::INTERVAL,
+                Arc::clone(&stop_announcing),
+            )
+            .expect("[ERR] Couldn't start announcing");
+            println!("[INF] Announcing on {}", group);
+        }
+        while !sigint.load(Ordering::Relaxed) {
+            std::thread::sleep(Duration::from_millis(100));
         }
+        stop_announcing.store(true, Ordering::Relaxed);
+        daemon.stop();
+    } else {
+        play(
+            &Session::from_args(args),
+            &opts,
+            &sigint,
+            None,
+            |device, channels, rate| open_alsa(device, channels, rate, opts.tstamp_clock),
+        );
     }
-    println!("[?25h");
-    pcm.drain().unwrap();
 }
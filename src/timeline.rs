@@ -0,0 +1,166 @@
+//! Pausing, resuming and seeking a running session. Every command takes
+//! effect at an instant of the shared clock chosen by the master, so the
+//! file position at any instant follows from the session start and the
+//! commands before it alone and every slave plays the same frame.
+
+#[cfg(test)]
+mod tests;
+
+use crate::protocol::Message;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Pause,
+    Resume,
+    /// Goes on from this many seconds into the file, paused if it was
+    Seek(f64),
+}
+
+/// An action and when it takes effect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cue {
+    /// Nanoseconds since the UNIX epoch
+    pub at: u64,
+    pub action: Action,
+}
+
+impl Cue {
+    pub fn to_message(&self) -> Message {
+        let command = match self.action {
+            Action::Pause => "PAUSE",
+            Action::Resume => "RESUME",
+            Action::Seek(_) => "SEEK",
+        };
+        let msg = Message::new(command).with("at", self.at);
+        return match self.action {
+            Action::Seek(position) => msg.with("position", position),
+            _ => msg,
+        };
+    }
+
+    /// Builds a cue from a `PAUSE`, `RESUME` or `SEEK` message.
+    pub fn from_message(msg: &Message) -> Result<Cue, String> {
+        let at = msg.parse("at")?.ok_or_else(|| "Missing at".to_string())?;
+        let action = match msg.command.as_str() {
+            "PAUSE" => Action::Pause,
+            "RESUME" => Action::Resume,
+            "SEEK" => Action::Seek(
+                msg.parse("position")?
+                    .ok_or_else(|| "Missing position".to_string())?,
+            ),
+            other => return Err(format!("Unknown timeline command {}", other)),
+        };
+        return Ok(Cue { at, action });
+    }
+}
+
+/// Stretch of the session between two commands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Segment {
+    /// Seconds after the session start
+    start: f64,
+    /// File position at `start` in seconds
+    position: f64,
+    playing: bool,
+}
+
+/// File position over the time of a session. Times are seconds after the
+/// session start, positions seconds into the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeline {
+    startat: u64,
+    segments: Vec<Segment>,
+}
+
+impl Timeline {
+    /// Plays from the start of the file at `startat` on
+    pub fn new(startat: u64) -> Timeline {
+        return Timeline {
+            startat,
+            segments: vec![Segment {
+                start: 0.,
+                position: 0.,
+                playing: true,
+            }],
+        };
+    }
+
+    /// Adds a command, which may not take effect before the session start
+    /// or the last command.
+    pub fn add(&mut self, cue: Cue) -> Result<(), String> {
+        if cue.at < self.startat {
+            return Err("Takes effect before the session starts".to_string());
+        }
+        let start = (cue.at - self.startat) as f64 / 1e9;
+        let last = *self.segments.last().unwrap();
+        if start < last.start {
+            return Err("Takes effect before the last command".to_string());
+        }
+        let position = if last.playing {
+            last.position + start - last.start
+        } else {
+            last.position
+        };
+        let segment = match cue.action {
+            Action::Pause => Segment {
+                start,
+                position,
+                playing: false,
+            },
+            Action::Resume => Segment {
+                start,
+                position,
+                playing: true,
+            },
+            Action::Seek(position) if position < 0. || !position.is_finite() => {
+                return Err(format!("Bad position {}", position));
+            }
+            Action::Seek(position) => Segment {
+                start,
+                position,
+                playing: last.playing,
+            },
+        };
+        self.segments.push(segment);
+        return Ok(());
+    }
+
+    /// Index of the segment playing at `time`, it changes with every command
+    pub fn segment(&self, time: f64) -> usize {
+        return self
+            .segments
+            .iter()
+            .rposition(|segment| segment.start <= time)
+            .unwrap_or(0);
+    }
+
+    /// Position at `time` extrapolated along `segment`, `None` while paused
+    pub fn position(&self, segment: usize, time: f64) -> Option<f64> {
+        let segment = &self.segments[segment];
+        if !segment.playing {
+            return None;
+        }
+        return Some(segment.position + time - segment.start);
+    }
+
+    /// Position at `time`, `None` while paused
+    pub fn position_at(&self, time: f64) -> Option<f64> {
+        return self.position(self.segment(time), time);
+    }
+
+    /// Time the last command before `time` took effect
+    pub fn cue_before(&self, time: f64) -> Option<f64> {
+        return match self.segment(time) {
+            0 => None,
+            segment => Some(self.segments[segment].start),
+        };
+    }
+
+    /// Time the first command after `time` takes effect
+    pub fn cue_after(&self, time: f64) -> Option<f64> {
+        return self.segments[1..]
+            .iter()
+            .map(|segment| segment.start)
+            .find(|&start| start > time);
+    }
+}
@@ -0,0 +1,55 @@
+use super::*;
+
+const START: u64 = 1_600_000_000_000_000_000;
+
+fn cue(seconds: f64, action: Action) -> Cue {
+    return Cue {
+        at: START + (seconds * 1e9) as u64,
+        action,
+    };
+}
+
+#[test]
+fn test_timeline() {
+    let mut timeline = Timeline::new(START);
+    assert_eq!(timeline.position_at(2.), Some(2.));
+    assert_eq!(timeline.cue_after(0.), None);
+    timeline.add(cue(10., Action::Pause)).unwrap();
+    timeline.add(cue(15., Action::Resume)).unwrap();
+    timeline.add(cue(20., Action::Seek(2.5))).unwrap();
+    timeline.add(cue(21., Action::Pause)).unwrap();
+    timeline.add(cue(22., Action::Seek(60.))).unwrap();
+    timeline.add(cue(23., Action::Resume)).unwrap();
+
+    assert_eq!(timeline.position_at(9.), Some(9.));
+    assert_eq!(timeline.position_at(12.), None);
+    assert_eq!(timeline.position_at(17.), Some(12.));
+    assert_eq!(timeline.position_at(20.5), Some(3.));
+    assert_eq!(timeline.position_at(22.5), None);
+    assert_eq!(timeline.position_at(24.), Some(61.));
+    // A segment goes on past its end when asked to
+    assert_eq!(timeline.position(0, 12.), Some(12.));
+    assert_eq!(timeline.cue_before(9.), None);
+    assert_eq!(timeline.cue_before(17.), Some(15.));
+    assert_eq!(timeline.cue_after(15.), Some(20.));
+    assert_eq!(timeline.cue_after(23.), None);
+
+    assert!(timeline.add(cue(22.9, Action::Pause)).is_err());
+    assert!(timeline.add(cue(30., Action::Seek(-1.))).is_err());
+    let early = Cue {
+        at: START - 1,
+        action: Action::Pause,
+    };
+    assert!(Timeline::new(START).add(early).is_err());
+}
+
+#[test]
+fn test_cue_messages() {
+    for &action in &[Action::Pause, Action::Resume, Action::Seek(12.25)] {
+        let cue = cue(3., action);
+        assert_eq!(Cue::from_message(&cue.to_message()), Ok(cue));
+    }
+    assert!(Cue::from_message(&Message::new("SEEK").with("at", START)).is_err());
+    assert!(Cue::from_message(&Message::new("PAUSE")).is_err());
+    assert!(Cue::from_message(&Message::new("STOP").with("at", START)).is_err());
+}
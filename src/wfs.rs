@@ -0,0 +1,445 @@
+//! 2.5D Wave Field Synthesis driving functions and a renderer applying them
+//! to a mono signal. Formulas follow Spors, Rabenstein and Ahrens, "The
+//! Theory of Wave Field Synthesis Revisited", 124th AES Convention, 2008.
+
+#[cfg(test)]
+mod tests;
+
+use crate::resampler::{FractionalDelay, Quality, Window};
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::ops::{Add, Mul, Sub};
+use std::str::FromStr;
+
+pub const SPEED_OF_SOUND: f64 = 343.;
+/// Length of the pre-equalization filter
+pub const PREFILTER_TAPS: usize = 255;
+/// Below this frequency the pre-equalization filter is flat
+pub const PREFILTER_LOW: f64 = 100.;
+/// Aliasing frequency used when neither the user nor the geometry set one
+pub const DEFAULT_ALIAS_FREQ: f64 = 1500.;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Vec2 {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Vec2 {
+    pub fn new(x: f64, y: f64) -> Vec2 {
+        return Vec2 { x, y };
+    }
+    /// Unit vector pointing at `degrees` counterclockwise from the x axis.
+    pub fn from_angle(degrees: f64) -> Vec2 {
+        let rad = degrees.to_radians();
+        return Vec2::new(rad.cos(), rad.sin());
+    }
+    pub fn angle(&self) -> f64 {
+        return self.y.atan2(self.x).to_degrees();
+    }
+    pub fn dot(&self, other: Vec2) -> f64 {
+        return self.x * other.x + self.y * other.y;
+    }
+    pub fn norm(&self) -> f64 {
+        return self.dot(*self).sqrt();
+    }
+}
+
+impl Add for Vec2 {
+    type Output = Vec2;
+    fn add(self, other: Vec2) -> Vec2 {
+        return Vec2::new(self.x + other.x, self.y + other.y);
+    }
+}
+
+impl Sub for Vec2 {
+    type Output = Vec2;
+    fn sub(self, other: Vec2) -> Vec2 {
+        return Vec2::new(self.x - other.x, self.y - other.y);
+    }
+}
+
+impl Mul<f64> for Vec2 {
+    type Output = Vec2;
+    fn mul(self, scale: f64) -> Vec2 {
+        return Vec2::new(self.x * scale, self.y * scale);
+    }
+}
+
+fn parse_floats(s: &str, count: usize) -> Result<Vec<f64>, String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("Couldn't parse {} as numbers", s))?;
+    if values.len() != count {
+        return Err(format!(
+            "Expected {} comma separated numbers, got {}",
+            count, s
+        ));
+    }
+    return Ok(values);
+}
+
+impl FromStr for Vec2 {
+    type Err = String;
+    /// Parses `X,Y`
+    fn from_str(s: &str) -> Result<Vec2, String> {
+        let v = parse_floats(s, 2)?;
+        return Ok(Vec2::new(v[0], v[1]));
+    }
+}
+
+impl fmt::Display for Vec2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{},{}", self.x, self.y);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudspeaker {
+    pub position: Vec2,
+    /// Unit vector pointing into the listening area
+    pub normal: Vec2,
+}
+
+impl FromStr for Loudspeaker {
+    type Err = String;
+    /// Parses `X,Y,ANGLE` where the angle (in degrees) is the direction the
+    /// loudspeaker is facing.
+    fn from_str(s: &str) -> Result<Loudspeaker, String> {
+        let v = parse_floats(s, 3)?;
+        return Ok(Loudspeaker {
+            position: Vec2::new(v[0], v[1]),
+            normal: Vec2::from_angle(v[2]),
+        });
+    }
+}
+
+/// Virtual sound source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    /// Omnidirectional point source at a position
+    Point(Vec2),
+    /// Plane wave travelling in a direction (unit vector)
+    Plane(Vec2),
+}
+
+impl FromStr for Source {
+    type Err = String;
+    /// Parses `point:X,Y` or `plane:ANGLE`
+    fn from_str(s: &str) -> Result<Source, String> {
+        return match s.split_once(':') {
+            Some(("point", pos)) => Ok(Source::Point(pos.parse()?)),
+            Some(("plane", angle)) => Ok(Source::Plane(Vec2::from_angle(
+                angle
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|_| format!("Couldn't parse {} as an angle", angle))?,
+            ))),
+            _ => Err(format!("Expected point:X,Y or plane:ANGLE, got {}", s)),
+        };
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            Source::Point(pos) => write!(f, "point:{}", pos),
+            Source::Plane(dir) => write!(f, "plane:{}", dir.angle()),
+        };
+    }
+}
+
+/// Position of a moving source at a point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keyframe {
+    /// Seconds since the start of playback
+    pub time: f64,
+    pub position: Vec2,
+}
+
+/// Path of a moving point source, the position is linearly interpolated
+/// between keyframes and held before the first and after the last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trajectory {
+    keyframes: Vec<Keyframe>,
+}
+
+impl Trajectory {
+    pub fn new(keyframes: Vec<Keyframe>) -> Result<Trajectory, String> {
+        if keyframes.is_empty() {
+            return Err("Trajectory needs at least one keyframe".to_string());
+        }
+        if keyframes.windows(2).any(|pair| pair[1].time < pair[0].time) {
+            return Err("Keyframes have to be ordered by time".to_string());
+        }
+        return Ok(Trajectory { keyframes });
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        return &self.keyframes;
+    }
+
+    pub fn position(&self, time: f64) -> Vec2 {
+        let next = self.keyframes.iter().position(|key| key.time > time);
+        return match next {
+            Some(0) => self.keyframes[0].position,
+            Some(idx) => {
+                let (a, b) = (self.keyframes[idx - 1], self.keyframes[idx]);
+                let alpha = (time - a.time) / (b.time - a.time);
+                a.position + (b.position - a.position) * alpha
+            }
+            None => self.keyframes.last().unwrap().position,
+        };
+    }
+}
+
+impl FromStr for Trajectory {
+    type Err = String;
+    /// Parses `T:X,Y;T:X,Y;...` with T in seconds since the start
+    fn from_str(s: &str) -> Result<Trajectory, String> {
+        let keyframes = s
+            .split(';')
+            .map(|key| match key.split_once(':') {
+                Some((time, pos)) => Ok(Keyframe {
+                    time: time
+                        .trim()
+                        .parse::<f64>()
+                        .map_err(|_| format!("Couldn't parse {} as a time", time))?,
+                    position: pos.parse()?,
+                }),
+                None => Err(format!("Expected T:X,Y, got {}", key)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        return Trajectory::new(keyframes);
+    }
+}
+
+impl fmt::Display for Trajectory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, key) in self.keyframes.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ";")?;
+            }
+            write!(f, "{}:{}", key.time, key.position)?;
+        }
+        return Ok(());
+    }
+}
+
+/// Delay and weight a loudspeaker applies to the pre-equalized source signal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Driving {
+    /// Delay in seconds, may be negative for plane waves
+    pub delay: f64,
+    /// Amplitude weight, zero when the loudspeaker is not active
+    pub gain: f64,
+}
+
+impl Source {
+    /// Driving function of `speaker`, with amplitude correct on the line
+    /// parallel to the array going through `reference`. Both source types
+    /// share the 1/sqrt(2π) of the sqrt(jk/2π) pre-equalization, of which
+    /// `prefilter` only applies the sqrt(jk).
+    pub fn driving(&self, speaker: &Loudspeaker, reference: Vec2) -> Driving {
+        let x0 = speaker.position;
+        let g0 = (2. * PI * (reference - x0).norm()).sqrt() / (2. * PI).sqrt();
+        return match *self {
+            Source::Point(xs) => {
+                let diff = x0 - xs;
+                let r = diff.norm();
+                let proj = diff.dot(speaker.normal);
+                Driving {
+                    delay: r / SPEED_OF_SOUND,
+                    gain: if proj > 0. {
+                        g0 * proj / r.powf(1.5)
+                    } else {
+                        0.
+                    },
+                }
+            }
+            Source::Plane(nk) => {
+                let proj = nk.dot(speaker.normal);
+                Driving {
+                    delay: nk.dot(x0) / SPEED_OF_SOUND,
+                    gain: if proj > 0. { 2. * g0 * proj } else { 0. },
+                }
+            }
+        };
+    }
+}
+
+/// Linear phase FIR approximating the sqrt(jk) pre-equalization, the +3 dB
+/// per octave slope is applied between `PREFILTER_LOW` and `f_alias`, flat
+/// elsewhere, and the response is normalized to unity above `f_alias`. The
+/// filter delays the signal by `(PREFILTER_TAPS - 1) / 2` samples.
+pub fn prefilter(fs: f64, f_alias: f64) -> Vec<f32> {
+    let n = PREFILTER_TAPS;
+    let mid = (n - 1) as f64 / 2.;
+    let magnitude = |f: f64| (f.max(PREFILTER_LOW).min(f_alias) / f_alias).sqrt();
+    return (0..n)
+        .map(|i| {
+            let t = i as f64 - mid;
+            let sum = (1..=n / 2).fold(magnitude(0.), |acc, k| {
+                acc + 2.
+                    * magnitude(k as f64 * fs / n as f64)
+                    * (2. * PI * k as f64 * t / n as f64).cos()
+            });
+            (sum / n as f64 * Window::Blackman.at(t / mid)) as f32
+        })
+        .collect();
+}
+
+struct Channel {
+    /// Delay in samples reached at the end of the last block
+    delay: f64,
+    gain: f32,
+    target_delay: f64,
+    target_gain: f32,
+}
+
+/// Turns a mono signal into one output channel per loudspeaker. Drivings
+/// set while playing are approached sample by sample over the next block,
+/// so moving sources glide instead of jumping. Fractional delays are read
+/// with the same filters as the desync correction.
+pub struct Renderer {
+    fs: f64,
+    predelay: f64,
+    prefilter: Vec<f32>,
+    delay: FractionalDelay,
+    input: Vec<f32>,
+    filtered: Vec<f32>,
+    channels: Vec<Channel>,
+    history: usize,
+    warned: bool,
+}
+
+impl Renderer {
+    /// The signal of every loudspeaker is delayed by `predelay` seconds on
+    /// top of its driving function, it has to be large enough to keep the
+    /// total delay positive. `quality` selects the fractional delay filter.
+    pub fn new(
+        drivings: &[Driving],
+        fs: f64,
+        predelay: f64,
+        f_alias: f64,
+        quality: Quality,
+    ) -> Renderer {
+        let mut renderer = Renderer {
+            fs,
+            predelay,
+            prefilter: prefilter(fs, f_alias),
+            delay: FractionalDelay::new(quality.design()),
+            input: vec![0.; PREFILTER_TAPS - 1],
+            filtered: Vec::new(),
+            channels: Vec::new(),
+            history: 0,
+            warned: false,
+        };
+        renderer.channels = drivings
+            .iter()
+            .map(|driving| {
+                let delay = renderer.delay_samples(driving);
+                Channel {
+                    delay,
+                    gain: driving.gain as f32,
+                    target_delay: delay,
+                    target_gain: driving.gain as f32,
+                }
+            })
+            .collect();
+        renderer.reserve(drivings);
+        return renderer;
+    }
+
+    fn delay_samples(&mut self, driving: &Driving) -> f64 {
+        let filter_delay = (PREFILTER_TAPS - 1) as f64 / 2.;
+        let delay = (self.predelay + driving.delay) * self.fs - filter_delay;
+        let min = self.delay.half_width() as f64;
+        if delay < min {
+            if !self.warned {
+                println!(
+                    "[WRN] Predelay too short by {:.1} ms, the source will be misplaced",
+                    (min - delay) / self.fs * 1000.
+                );
+                self.warned = true;
+            }
+            return min;
+        }
+        return delay;
+    }
+
+    /// Keeps enough of the signal to apply any of `drivings` later on.
+    pub fn reserve(&mut self, drivings: &[Driving]) {
+        let needed = drivings
+            .iter()
+            .map(|driving| {
+                self.delay_samples(driving).ceil() as usize + self.delay.half_width() + 1
+            })
+            .max()
+            .unwrap_or(0);
+        if needed > self.history {
+            self.filtered.splice(0..0, vec![0.; needed - self.history]);
+            self.history = needed;
+        }
+    }
+
+    /// Drivings to reach at the end of the next processed block.
+    pub fn set_drivings(&mut self, drivings: &[Driving]) {
+        self.reserve(drivings);
+        for (idx, driving) in drivings.iter().enumerate() {
+            let delay = self.delay_samples(driving);
+            let channel = &mut self.channels[idx];
+            channel.target_delay = delay;
+            channel.target_gain = driving.gain as f32;
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        return self.channels.len();
+    }
+
+    /// Renders a block of mono samples into interleaved loudspeaker signals.
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        let taps = self.prefilter.len();
+        self.input.extend_from_slice(input);
+        for n in 0..input.len() {
+            let window = &self.input[n..n + taps];
+            self.filtered.push(
+                window
+                    .iter()
+                    .rev()
+                    .zip(self.prefilter.iter())
+                    .map(|(x, h)| x * h)
+                    .sum(),
+            );
+        }
+        self.input.drain(..input.len());
+
+        let start = self.filtered.len() - input.len();
+        let half_width = self.delay.half_width();
+        let mut out = Vec::with_capacity(input.len() * self.channels.len());
+        for (n, pos) in (start..self.filtered.len()).enumerate() {
+            let alpha = (n + 1) as f64 / input.len() as f64;
+            for ch in &self.channels {
+                let delay = ch.delay + (ch.target_delay - ch.delay) * alpha;
+                let gain = ch.gain + (ch.target_gain - ch.gain) * alpha as f32;
+                // Reads `1 - fract` after the sample `whole + 1` before `pos`
+                let whole = delay as usize;
+                let first = pos - whole - half_width;
+                let window = &self.filtered[first..first + 2 * half_width];
+                let value = self.delay.read(window, 1. - delay.fract());
+                out.push(value * gain);
+            }
+        }
+        for ch in &mut self.channels {
+            ch.delay = ch.target_delay;
+            ch.gain = ch.target_gain;
+        }
+        let excess = self.filtered.len().saturating_sub(self.history);
+        self.filtered.drain(..excess);
+        return out;
+    }
+}
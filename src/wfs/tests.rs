@@ -0,0 +1,204 @@
+use super::*;
+
+const FS: f64 = 48000.;
+const EPS: f64 = 1e-9;
+
+fn speaker(x: f64, angle: f64) -> Loudspeaker {
+    return Loudspeaker {
+        position: Vec2::new(x, 0.),
+        normal: Vec2::from_angle(angle),
+    };
+}
+
+fn magnitude(taps: &[f32], f: f64) -> f64 {
+    let (re, im) = taps.iter().enumerate().fold((0., 0.), |(re, im), (n, h)| {
+        let phase = 2. * PI * f / FS * n as f64;
+        (re + *h as f64 * phase.cos(), im - *h as f64 * phase.sin())
+    });
+    return (re * re + im * im).sqrt();
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("1.5, -2".parse::<Vec2>(), Ok(Vec2::new(1.5, -2.)));
+    assert!("1,2,3".parse::<Vec2>().is_err());
+    let sp: Loudspeaker = "1,2,90".parse().unwrap();
+    assert!((sp.normal - Vec2::new(0., 1.)).norm() < EPS);
+    let src: Source = "point:0,-1".parse().unwrap();
+    assert_eq!(src, Source::Point(Vec2::new(0., -1.)));
+    assert_eq!(src.to_string().parse::<Source>(), Ok(src));
+    match "plane:90".parse::<Source>().unwrap() {
+        Source::Plane(dir) => assert!((dir - Vec2::new(0., 1.)).norm() < EPS),
+        _ => unreachable!(),
+    }
+    assert!("line:0".parse::<Source>().is_err());
+}
+
+#[test]
+fn test_point_source() {
+    let source = Source::Point(Vec2::new(0., -1.));
+    let reference = Vec2::new(0., 2.);
+    let center = source.driving(&speaker(0., 90.), reference);
+    assert!((center.delay - 1. / SPEED_OF_SOUND).abs() < EPS);
+    // sqrt(|xref - x0|) * <x0 - xs, n0> / r^(3/2)
+    assert!((center.gain - 2f64.sqrt()).abs() < EPS);
+
+    let left = source.driving(&speaker(-1., 90.), reference);
+    let right = source.driving(&speaker(1., 90.), reference);
+    assert!((left.delay - 2f64.sqrt() / SPEED_OF_SOUND).abs() < EPS);
+    assert!((left.delay - right.delay).abs() < EPS);
+    assert!((left.gain - right.gain).abs() < EPS);
+    assert!(left.gain < center.gain && left.gain > 0.);
+
+    let facing_away = source.driving(&speaker(0., -90.), reference);
+    assert_eq!(facing_away.gain, 0.);
+}
+
+#[test]
+fn test_plane_wave() {
+    let reference = Vec2::new(0., 2.);
+    let source = Source::Plane(Vec2::from_angle(90.));
+    let a = source.driving(&speaker(-1., 90.), reference);
+    let b = source.driving(&speaker(1., 90.), reference);
+    assert!(a.delay.abs() < EPS && b.delay.abs() < EPS);
+    assert!((a.gain - 2. * 5f64.sqrt().sqrt()).abs() < EPS);
+
+    let source = Source::Plane(Vec2::from_angle(45.));
+    let a = source.driving(&speaker(-1., 90.), reference);
+    let b = source.driving(&speaker(1., 90.), reference);
+    assert!((b.delay - a.delay - 2f64.sqrt() / SPEED_OF_SOUND).abs() < EPS);
+    assert!(a.delay < 0.);
+
+    let behind = Source::Plane(Vec2::from_angle(-90.));
+    assert_eq!(behind.driving(&speaker(0., 90.), reference).gain, 0.);
+}
+
+#[test]
+fn test_broadside_levels() {
+    // Normalized alike, a plane wave and a point source straight ahead
+    // differ only by 2 and the 1 / sqrt(r) of the spreading
+    let reference = Vec2::new(0., 2.);
+    let plane = Source::Plane(Vec2::from_angle(90.)).driving(&speaker(0., 90.), reference);
+    for &distance in &[1., 4., 100.] {
+        let point = Source::Point(Vec2::new(0., -distance)).driving(&speaker(0., 90.), reference);
+        assert!((plane.gain / point.gain - 2. * distance.sqrt()).abs() < EPS);
+    }
+    assert!((plane.gain - 2. * 2f64.sqrt()).abs() < EPS);
+}
+
+#[test]
+fn test_prefilter() {
+    let f_alias = 2000.;
+    let taps = prefilter(FS, f_alias);
+    assert_eq!(taps.len(), PREFILTER_TAPS);
+    for (a, b) in taps.iter().zip(taps.iter().rev()) {
+        assert!((a - b).abs() < 1e-6, "Prefilter is not linear phase");
+    }
+    let db = |f: f64| 20. * magnitude(&taps, f).log10();
+    for f in &[4000., 8000., 16000.] {
+        assert!(db(*f).abs() < 0.5, "{} dB at {} Hz", db(*f), f);
+    }
+    // +3 dB per octave below the aliasing frequency
+    for f in &[500., 1000.] {
+        let expected = 10. * (f / f_alias).log10();
+        assert!((db(*f) - expected).abs() < 1., "{} dB at {} Hz", db(*f), f);
+    }
+    assert!(db(250.) < db(500.) && db(500.) < db(1000.));
+}
+
+#[test]
+fn test_renderer() {
+    let reference = Vec2::new(0., 2.);
+    let source = Source::Plane(Vec2::from_angle(60.));
+    let drivings: Vec<_> = (0..4)
+        .map(|idx| source.driving(&speaker(idx as f64 * 0.3 - 0.45, 90.), reference))
+        .collect();
+    let predelay = 0.01;
+    let freq = 5000.;
+    let signal = |t: f64| (2. * PI * freq * t).sin();
+    let input: Vec<f32> = (0..4800).map(|n| signal(n as f64 / FS) as f32).collect();
+    for &quality in &[Quality::Fast, Quality::Medium, Quality::High, Quality::Best] {
+        let mut renderer = Renderer::new(&drivings, FS, predelay, 2000., quality);
+        assert_eq!(renderer.channels(), 4);
+        let mut out = Vec::new();
+        for block in input.chunks(333) {
+            out.extend(renderer.process(block));
+        }
+        assert_eq!(out.len(), input.len() * 4);
+        for n in 2400..4800 {
+            for (ch, driving) in drivings.iter().enumerate() {
+                let expected = driving.gain * signal(n as f64 / FS - predelay - driving.delay);
+                let err = (out[n * 4 + ch] as f64 - expected).abs();
+                assert!(
+                    err < 0.02 * driving.gain,
+                    "Channel {} at {} with {}: {}",
+                    ch,
+                    n,
+                    quality,
+                    err
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_trajectory() {
+    let trajectory: Trajectory = "0:0,-1; 2:2,-1;3:2,-3".parse().unwrap();
+    assert_eq!(trajectory.keyframes().len(), 3);
+    assert_eq!(
+        trajectory.to_string().parse::<Trajectory>(),
+        Ok(trajectory.clone())
+    );
+    assert_eq!(trajectory.position(-1.), Vec2::new(0., -1.));
+    assert!((trajectory.position(0.5) - Vec2::new(0.5, -1.)).norm() < EPS);
+    assert!((trajectory.position(2.5) - Vec2::new(2., -2.)).norm() < EPS);
+    assert_eq!(trajectory.position(10.), Vec2::new(2., -3.));
+
+    assert!("".parse::<Trajectory>().is_err());
+    assert!("1:0,0;0:1,1".parse::<Trajectory>().is_err());
+    assert!("1,0,0".parse::<Trajectory>().is_err());
+}
+
+#[test]
+fn test_moving_renderer() {
+    // Delay growing by 10 ms per second, a source receding at 3.43 m/s
+    let rate = 0.01;
+    let delay = |t: f64| 0.002 + rate * t;
+    let driving = |t: f64| Driving {
+        delay: delay(t),
+        gain: 1.,
+    };
+    let predelay = 0.01;
+    let mut renderer = Renderer::new(&[driving(0.)], FS, predelay, 2000., Quality::Medium);
+    renderer.reserve(&[driving(1.)]);
+
+    let freq = 5000.;
+    let signal = |t: f64| (2. * PI * freq * t).sin();
+    let input: Vec<f32> = (0..48000).map(|n| signal(n as f64 / FS) as f32).collect();
+    let block = 480;
+    let mut out = Vec::new();
+    for (idx, chunk) in input.chunks(block).enumerate() {
+        let last = (idx * block + chunk.len() - 1) as f64 / FS;
+        renderer.set_drivings(&[driving(last)]);
+        out.extend(renderer.process(chunk));
+    }
+    for (n, value) in out.iter().enumerate().skip(4800) {
+        let t = n as f64 / FS;
+        let expected = signal(t - predelay - delay(t));
+        let err = (*value as f64 - expected).abs();
+        assert!(err < 0.02, "Sample {}: {}", n, err);
+    }
+
+    // Doppler shifted by the delay rate
+    let crossings = out[4800..]
+        .windows(2)
+        .filter(|pair| pair[0] < 0. && pair[1] >= 0.)
+        .count();
+    let measured = crossings as f64 / (43200. / FS);
+    assert!(
+        (measured - freq * (1. - rate)).abs() < 5.,
+        "{} Hz",
+        measured
+    );
+}